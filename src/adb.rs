@@ -0,0 +1,948 @@
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use which::which;
+
+use crate::for_stream;
+use crate::interrupt;
+use crate::logging::{log, LoggedCommand};
+
+/// The adb executable together with the server and the device every command should target
+pub struct Adb {
+    pub path: PathBuf,
+    pub serial: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// The algorithm given to `adb pull -z`, with --compress
+    pub pull_compression: Option<&'static str>,
+    /// Whether the files are pulled with `-a`, keeping their modification time and permissions, unless --no-metadata
+    pub keep_metadata: bool,
+}
+
+/// A compressor on the device, which the tar stream is piped through with --compress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamCompressor {
+    Zstd,
+    Gzip,
+}
+
+impl StreamCompressor {
+    /// Name of its command on the device
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamCompressor::Zstd => "zstd",
+            StreamCompressor::Gzip => "gzip",
+        }
+    }
+}
+
+impl Adb {
+    pub fn new(path: PathBuf, serial: Option<String>, host: Option<String>, port: Option<u16>) -> Self {
+        Self {
+            path,
+            serial,
+            host,
+            port,
+            pull_compression: None,
+            keep_metadata: true,
+        }
+    }
+
+    /// Returns a new adb command which talks to the selected server, if any
+    pub fn server_command(&self) -> process::Command {
+        let mut cmd = process::Command::new(&self.path);
+        if let Some(host) = &self.host {
+            cmd.arg("-H").arg(host);
+        }
+        if let Some(port) = &self.port {
+            cmd.arg("-P").arg(port.to_string());
+        }
+        cmd
+    }
+
+    /// Returns a new adb command which talks to the selected server and device, if any
+    pub fn command(&self) -> process::Command {
+        let mut cmd = self.server_command();
+        if let Some(serial) = &self.serial {
+            cmd.arg("-s").arg(serial);
+        }
+        cmd
+    }
+
+    /// Starts the adb server if it isn't running and waits until it answers
+    pub fn start_server(&self, timeout: Duration) -> Result<()> {
+        let status = self.server_command()
+            .arg("start-server")
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .logged()
+            .status()
+            .context("Failed to execute `adb start-server`")?;
+
+        if !status.success() {
+            return Err(anyhow!("`adb start-server` exited with {status}"));
+        }
+
+        let start = Instant::now();
+        loop {
+            let server_up = self.server_command()
+                .arg("devices")
+                .stdout(process::Stdio::null())
+                .stderr(process::Stdio::null())
+                .logged()
+                .status()
+                .is_ok_and(|status| status.success());
+
+            if server_up {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                return Err(anyhow!("The adb server didn't start within {} seconds", timeout.as_secs()));
+            }
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Connects to a device over the network with `adb connect <endpoint>`
+    pub fn connect(&self, endpoint: &str) -> Result<()> {
+        let output = self.server_command()
+            .arg("connect")
+            .arg(endpoint)
+            .logged()
+            .output()
+            .context("Failed to execute `adb connect`")?;
+
+        // `adb connect` exits successfully even when the connection fails, so the output has to be checked
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() && stdout.contains("connected to") {
+            Ok(())
+        } else {
+            Err(anyhow!("Unable to connect to {endpoint}: {}{}", stdout.trim(), stderr.trim()))
+        }
+    }
+
+    /// Pairs with a device using Android 11+ wireless debugging with `adb pair <endpoint> <code>`
+    pub fn pair(&self, endpoint: &str, code: &str) -> Result<()> {
+        let output = self.server_command()
+            .arg("pair")
+            .arg(endpoint)
+            .arg(code)
+            .stdin(process::Stdio::null())
+            .logged()
+            .output()
+            .context("Failed to execute `adb pair`")?;
+
+        // Like `adb connect`, `adb pair` may exit successfully even when the pairing fails
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() && stdout.contains("Successfully paired") {
+            Ok(())
+        } else {
+            Err(anyhow!("Unable to pair with {endpoint}: {}{}", stdout.trim(), stderr.trim()))
+        }
+    }
+
+    /// Disconnects from a device previously connected with [`Adb::connect`]
+    pub fn disconnect(&self, endpoint: &str) -> Result<()> {
+        let status = self.server_command()
+            .arg("disconnect")
+            .arg(endpoint)
+            .stdout(process::Stdio::null())
+            .logged()
+            .status()
+            .context("Failed to execute `adb disconnect`")?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("`adb disconnect {endpoint}` exited with {status}"))
+        }
+    }
+
+    /// Pulls `src` to `dest`, keeping its metadata unless [`Adb::keep_metadata`] is false, calling `on_percent` whenever adb reports how much
+    /// of the file has been copied. adb reports it only on some versions, in which case `on_percent`
+    /// is never called. adb is killed if it's still running after `timeout`, returning an error of
+    /// kind [`io::ErrorKind::TimedOut`], or after Ctrl-C, returning [`io::ErrorKind::Interrupted`]
+    pub fn pull(&self, src: &str, dest: &Path, timeout: Option<Duration>, mut on_percent: impl FnMut(u8)) -> io::Result<process::Output> {
+        let (mut child, stderr_reader) = spawn_draining_stderr(
+            self.command()
+                .arg("pull")
+                .args(self.keep_metadata.then_some("-a"))
+                .args(self.pull_compression.iter().flat_map(|algorithm| ["-z", algorithm]))
+                .arg(src)
+                .arg(dest),
+        )?;
+
+        // The progress lines, such as "[ 42%] /sdcard/DCIM/video.mp4", are ended by \r or \n
+        let mut child_stdout = child.stdout.take().unwrap();
+        let mut stdout = Vec::new();
+        let mut line = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let (read, killed): (io::Result<()>, _) = kill_after(&mut child, timeout, || loop {
+            let read = child_stdout.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            stdout.extend_from_slice(&buffer[..read]);
+            for byte in &buffer[..read] {
+                if *byte == b'\r' || *byte == b'\n' {
+                    if let Some(percent) = parse_pull_percent(&String::from_utf8_lossy(&line)) {
+                        on_percent(percent);
+                    }
+                    line.clear();
+                } else {
+                    line.push(*byte);
+                }
+            }
+        });
+
+        let status = child.wait()?;
+        let stderr = stderr_reader.join().unwrap_or_default();
+        if let Some(killed) = killed {
+            return Err(io::Error::from(killed.error_kind()));
+        }
+        read?;
+        Ok(process::Output { status, stdout, stderr })
+    }
+
+    /// Blocks until the selected device is attached, returning false if `timeout` expires first
+    pub fn wait_for_device(&self, timeout: Duration) -> Result<bool> {
+        let mut child = self
+            .command()
+            .arg("wait-for-device")
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .logged()
+            .spawn()
+            .context("Failed to execute `adb wait-for-device`")?;
+
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status.success());
+            }
+            if start.elapsed() > timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(false);
+            }
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Runs `script` in the shell of the device, returning what it printed trimmed. Old adb versions don't
+    /// forward the exit code of the shell command, so scripts print their result instead
+    pub fn shell_answer(&self, script: &str) -> io::Result<String> {
+        let output = self.command().arg("shell").arg(script).logged().output()?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Whether the device has the command `name`, e.g. `tar` which old devices without toybox lack
+    pub fn has_command(&self, name: &str) -> bool {
+        self.shell_answer(&format!("if command -v {name} >/dev/null 2>&1; then echo yes; fi"))
+            .is_ok_and(|answer| answer == "yes")
+    }
+
+    /// The system property `name` of the device, such as `ro.product.model`, None if it isn't set
+    pub fn getprop(&self, name: &str) -> Option<String> {
+        let output = self.command().arg("shell").arg(format!("getprop {name}")).logged().output().ok()?;
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (!value.is_empty()).then_some(value)
+    }
+
+    /// The bytes taken on the device by `path` and all the files inside it, as told by `du -s -k`, so rounded up to
+    /// whole blocks
+    pub fn disk_usage(&self, path: &str) -> Result<u64> {
+        let output = self
+            .command()
+            .arg("shell")
+            .arg(format!("du -s -k {}", shell_quote(path)))
+            .logged()
+            .output()
+            .context("Failed to execute `adb shell du`")?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let kibibytes: u64 = stdout.split_whitespace().next().and_then(|size| size.parse().ok()).ok_or_else(|| {
+            anyhow!("Unable to get the size of {:?} with du: {}", path, String::from_utf8_lossy(&output.stderr).trim())
+        })?;
+        Ok(kibibytes * 1024)
+    }
+
+    /// The compression `adb pull -z` can use with this adb and device, preferring zstd. It needs
+    /// platform-tools 34 or later, and a device advertising the algorithm among its features
+    pub fn supported_pull_compression(&self) -> Option<&'static str> {
+        let version = self.server_command().arg("version").logged().output().ok()?;
+        let major: u32 = String::from_utf8_lossy(&version.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix("Version "))?
+            .split('.')
+            .next()?
+            .parse()
+            .ok()?;
+        if major < 34 {
+            return None;
+        }
+
+        let features = self.command().arg("features").logged().output().ok()?;
+        let features = String::from_utf8_lossy(&features.stdout);
+        let features: Vec<&str> = features.split([',', '\n']).map(str::trim).collect();
+        ["zstd", "lz4", "brotli"]
+            .into_iter()
+            .find(|algorithm| features.contains(&format!("sendrecv_v2_{algorithm}").as_str()))
+    }
+
+    /// The compressor on the device to pipe the tar stream through, preferring zstd
+    pub fn stream_compressor(&self) -> Option<StreamCompressor> {
+        let answer = self
+            .shell_answer("for c in zstd gzip; do if command -v $c >/dev/null 2>&1; then echo $c; break; fi; done")
+            .ok()?;
+        match answer.as_str() {
+            "zstd" => Some(StreamCompressor::Zstd),
+            "gzip" => Some(StreamCompressor::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Starts `tar` on the device, writing on its stdout an archive of `paths`, which are relative to
+    /// `dir`, compressed by `compressor` if given. Its stderr is read by the thread returned, see [`spawn_draining_stderr`]
+    pub fn tar(&self, dir: &str, paths: &[&str], compressor: Option<StreamCompressor>) -> io::Result<(process::Child, StderrReader)> {
+        let paths: Vec<String> = paths.iter().map(|path| shell_quote(path)).collect();
+        let mut command = format!("tar -cf - -C {} {}", shell_quote(dir), paths.join(" "));
+        if let Some(compressor) = compressor {
+            command = format!("{command} | {} -c", compressor.name());
+        }
+        spawn_draining_stderr(self.command().arg("exec-out").arg(command))
+    }
+
+    /// Deletes the files at `paths` from the device, returning those deleted
+    pub fn remove_files(&self, paths: &[&str]) -> Result<Vec<String>> {
+        self.for_each_path(paths, "rm -f --")
+    }
+
+    /// Removes the folders at `dirs` which are empty, returning those removed. A folder emptied by the
+    /// removal of its subfolders is removed only if it comes after them
+    pub fn remove_empty_dirs(&self, dirs: &[&str]) -> Result<Vec<String>> {
+        self.for_each_path(dirs, "rmdir -- 2>/dev/null")
+    }
+
+    /// Runs `command` on the device on each of `paths`, in order, returning those it succeeded on. The paths
+    /// are sent through stdin to `xargs` separated by NUL, so that they need no quoting and a batch takes a
+    /// single adb call
+    fn for_each_path(&self, paths: &[&str], command: &str) -> Result<Vec<String>> {
+        if paths.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let script = format!("for path; do {command} \"$path\" && printf '%s\\0' \"$path\"; done");
+        let mut child = self
+            .command()
+            .arg("shell")
+            .arg(format!("xargs -0 sh -c {} sh", shell_quote(&script)))
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .logged()
+            .spawn()
+            .with_context(|| format!("Failed to run `{command}` on the device"))?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        let input: Vec<u8> = paths.iter().flat_map(|path| path.bytes().chain([0])).collect();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Failed to run `{command}` on the device"))?;
+        let _ = writer.join();
+
+        // The paths which failed are left out, their errors go to the log
+        for line in String::from_utf8_lossy(&output.stderr).trim().lines() {
+            log(format!("    {line}"));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(String::from)
+            .collect())
+    }
+}
+
+/// Why [`kill_after`] killed the child
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Killed {
+    Timeout,
+    /// Ctrl-C was pressed and the grace period is over
+    Interrupted,
+}
+
+impl Killed {
+    pub fn error_kind(self) -> io::ErrorKind {
+        match self {
+            Killed::Timeout => io::ErrorKind::TimedOut,
+            Killed::Interrupted => io::ErrorKind::Interrupted,
+        }
+    }
+}
+
+/// Runs `work`, such as reading the output of `child`, killing `child` if it's still running after
+/// `timeout` or after the grace period given by Ctrl-C. Returns what `work` returned and why `child`
+/// was killed, if it was
+pub fn kill_after<T>(child: &mut process::Child, timeout: Option<Duration>, work: impl FnOnce() -> T) -> (T, Option<Killed>) {
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let watchdog = scope.spawn(|| {
+            let start = Instant::now();
+            while !done.load(Ordering::Relaxed) {
+                let killed = match timeout {
+                    Some(timeout) if start.elapsed() >= timeout => Some(Killed::Timeout),
+                    _ if interrupt::grace_over() => Some(Killed::Interrupted),
+                    _ => None,
+                };
+                if killed.is_some() {
+                    return killed.filter(|_| child.kill().is_ok());
+                }
+                sleep(Duration::from_millis(50));
+            }
+            None
+        });
+        let result = work();
+        done.store(true, Ordering::Relaxed);
+        (result, watchdog.join().unwrap_or(None))
+    })
+}
+
+/// Parses the percentage from the progress lines of `adb pull`, such as "[ 42%] /sdcard/DCIM/video.mp4"
+fn parse_pull_percent(line: &str) -> Option<u8> {
+    let percent = line.trim_start().strip_prefix('[')?.split_once("%]")?.0;
+    percent.trim().parse().ok().filter(|percent| *percent <= 100)
+}
+
+/// Quotes `arg` so that the device shell treats it as a single literal word, whatever it contains
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// The state of a device as reported by `adb devices`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceState {
+    Device,
+    Unauthorized,
+    Offline,
+    Other(String),
+}
+
+impl DeviceState {
+    fn parse(state: &str) -> Self {
+        match state {
+            "device" => DeviceState::Device,
+            "unauthorized" => DeviceState::Unauthorized,
+            "offline" => DeviceState::Offline,
+            other => DeviceState::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceState::Device => write!(f, "device"),
+            DeviceState::Unauthorized => write!(f, "unauthorized"),
+            DeviceState::Offline => write!(f, "offline"),
+            DeviceState::Other(state) => write!(f, "{state}"),
+        }
+    }
+}
+
+/// The thread reading the stderr of a command, returning all of it once the command closes it
+pub type StderrReader = thread::JoinHandle<Vec<u8>>;
+
+/// Spawns `cmd` with its stdout and stderr piped. stderr is read on another thread so that the command
+/// can't block on a full stderr pipe while its stdout is being read
+pub fn spawn_draining_stderr(cmd: &mut process::Command) -> io::Result<(process::Child, StderrReader)> {
+    let mut child = cmd.stdout(process::Stdio::piped()).stderr(process::Stdio::piped()).logged().spawn()?;
+
+    let mut child_stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut stderr = Vec::new();
+        let _ = child_stderr.read_to_end(&mut stderr);
+        stderr
+    });
+    Ok((child, stderr_reader))
+}
+
+/// Why a file couldn't be pulled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PullFailure {
+    /// The file doesn't exist on the device anymore
+    NotFound,
+    PermissionDenied,
+    /// The device went away during the transfer
+    DeviceOffline,
+    /// The destination disk is full
+    NoSpace,
+    /// The file couldn't be written on this computer
+    LocalIo(String),
+    /// The pull took longer than --file-timeout and was killed
+    Timeout,
+    /// The pulled file has another size than on the device
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The pulled file has another hash than on the device, computed with the algorithm given
+    ChecksumMismatch(&'static str),
+    /// The device couldn't hash the file with the algorithm given
+    NoDeviceChecksum(&'static str),
+    /// The pull of its folder succeeded, but the file isn't in the folder pulled, given
+    MissingAfterPull(PathBuf),
+    /// The pull was stopped with Ctrl-C
+    Interrupted,
+    /// An error of adb not classified, such as a protocol fault, which may go away
+    Other(String),
+}
+
+impl PullFailure {
+    /// Classifies the failure from the exit code and stderr of `adb pull`
+    pub fn from_pull(code: Option<i32>, stderr: &str) -> Self {
+        let lowercase = stderr.to_lowercase();
+        if lowercase.contains("device offline")
+            || lowercase.contains("no devices/emulators found")
+            || (lowercase.contains("device '") && lowercase.contains("' not found"))
+        {
+            PullFailure::DeviceOffline
+        } else if lowercase.contains("no space left on device") {
+            PullFailure::NoSpace
+        } else if lowercase.contains("no such file or directory") && lowercase.contains("remote") {
+            PullFailure::NotFound
+        } else if lowercase.contains("permission denied") && lowercase.contains("remote") {
+            PullFailure::PermissionDenied
+        } else if lowercase.contains("cannot create") || lowercase.contains("failed to create") || lowercase.contains("cannot open") {
+            PullFailure::LocalIo(stderr.trim().lines().last().unwrap_or_default().to_string())
+        } else {
+            match stderr.trim().lines().last() {
+                Some(line) => PullFailure::Other(line.to_string()),
+                None => PullFailure::Other(format!("exit code {}", code.map_or("unknown".to_string(), |code| code.to_string()))),
+            }
+        }
+    }
+
+    /// Whether pulling again may succeed, as the failure can be caused by a hiccup of the connection
+    /// or of this computer, while the file missing or unreadable on the device stays so
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PullFailure::DeviceOffline
+            | PullFailure::LocalIo(_)
+            | PullFailure::Timeout
+            | PullFailure::SizeMismatch { .. }
+            | PullFailure::ChecksumMismatch(_)
+            | PullFailure::Other(_) => true,
+            PullFailure::NotFound
+            | PullFailure::PermissionDenied
+            | PullFailure::NoSpace
+            | PullFailure::NoDeviceChecksum(_)
+            | PullFailure::MissingAfterPull(_)
+            | PullFailure::Interrupted => false,
+        }
+    }
+}
+
+impl fmt::Display for PullFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PullFailure::NotFound => write!(f, "does not exist on the device"),
+            PullFailure::PermissionDenied => write!(f, "permission denied"),
+            PullFailure::DeviceOffline => write!(f, "device offline"),
+            PullFailure::NoSpace => write!(f, "no space left on the destination"),
+            PullFailure::LocalIo(err) => write!(f, "local I/O error: {err}"),
+            PullFailure::Timeout => write!(f, "timeout"),
+            PullFailure::SizeMismatch { expected, actual } => write!(f, "size mismatch, {actual} bytes instead of {expected}"),
+            PullFailure::ChecksumMismatch(algorithm) => write!(f, "checksum mismatch, its {algorithm} differs from the device"),
+            PullFailure::NoDeviceChecksum(algorithm) => write!(f, "unable to compute its {algorithm} on the device"),
+            PullFailure::MissingAfterPull(dir) => write!(f, "not found in {:?} after pulling it", dir),
+            PullFailure::Interrupted => write!(f, "interrupted"),
+            PullFailure::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// A device attached to the adb server, as listed by `adb devices -l`
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub serial: String,
+    pub state: DeviceState,
+    pub product: Option<String>,
+    pub model: Option<String>,
+    pub transport_id: Option<String>,
+}
+
+impl Device {
+    /// Parses a line of `adb devices -l` such as:
+    /// `12ec6c18    device usb:1-1 product:panther model:Pixel_7 device:panther transport_id:1`
+    fn parse(line: &str) -> Option<Self> {
+        let mut tokens = line.split_whitespace();
+        let serial = tokens.next()?.to_string();
+        let mut state = tokens.next()?.to_string();
+
+        // The state of devices which can't be accessed by the current user is `no permissions (...)`
+        if state == "no" {
+            state.push_str(" permissions");
+        }
+
+        let mut device = Device {
+            serial,
+            state: DeviceState::parse(&state),
+            product: None,
+            model: None,
+            transport_id: None,
+        };
+
+        for token in tokens {
+            match token.split_once(':') {
+                Some(("product", value)) => device.product = Some(value.to_string()),
+                Some(("model", value)) => device.model = Some(value.to_string()),
+                Some(("transport_id", value)) => device.transport_id = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Some(device)
+    }
+}
+
+/// Returns the devices attached to the adb server
+pub fn get_devices(adb: &Adb) -> Result<Vec<Device>> {
+    let output = adb
+        .server_command()
+        .arg("devices")
+        .arg("-l")
+        .stdout(process::Stdio::piped())
+        .logged()
+        .output()
+        .with_context(|| format!("Unable to check if adb is connected. \nADB path: {:?}", adb.path.as_path()))?;
+
+    let out_string = String::from_utf8_lossy(&output.stdout);
+
+    // `adb devices` outputs the devices attached to the adb server after `List of devices attached`
+    Ok(out_string
+        .lines()
+        .skip_while(|line| !line.starts_with("List of devices attached"))
+        .skip(1)
+        .filter_map(Device::parse)
+        .collect())
+}
+
+/// Prints the devices as a table, highlighting the ones which can't be used
+pub fn print_devices(devices: &[Device]) {
+    if devices.is_empty() {
+        println!("No device attached");
+        return;
+    }
+
+    let headers = ["SERIAL", "STATE", "MODEL", "PRODUCT", "TRANSPORT ID"];
+    let rows: Vec<[String; 5]> = devices
+        .iter()
+        .map(|device| {
+            [
+                device.serial.clone(),
+                device.state.to_string(),
+                device.model.clone().unwrap_or_else(|| "-".to_string()),
+                device.product.clone().unwrap_or_else(|| "-".to_string()),
+                device.transport_id.clone().unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+
+    let mut widths = headers.map(|header| header.len());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let header_line: Vec<String> = headers.iter().zip(widths.iter()).map(|(h, w)| format!("{h:w$}")).collect();
+    println!("{}", for_stream(header_line.join("  ").trim_end().bold().to_string(), false));
+
+    for (device, row) in devices.iter().zip(rows.iter()) {
+        let line: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, w)| format!("{cell:w$}")).collect();
+        let line = line.join("  ").trim_end().to_string();
+        if device.state == DeviceState::Device {
+            println!("{line}");
+        } else {
+            println!("{}", for_stream(line.red().to_string(), false));
+        }
+    }
+}
+
+/// Whether a usable device is attached to the adb server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    Unauthorized,
+    Offline,
+    NoDevice,
+}
+
+/// Checks the state of the selected device, retrying every `retry_delay` as the server
+/// may need a moment to enumerate the USB devices after starting
+pub fn connected_to_adb_server(adb: &Adb, retries: Option<usize>, retry_delay: Duration) -> Result<ConnectionStatus> {
+    let retries = retries.unwrap_or(1);
+
+    let devices: Vec<Device> = get_devices(adb)?
+        .into_iter()
+        .filter(|device| adb.serial.as_ref().is_none_or(|serial| &device.serial == serial))
+        .collect();
+
+    let status = if devices.iter().any(|device| device.state == DeviceState::Device) {
+        ConnectionStatus::Connected
+    } else if devices.iter().any(|device| device.state == DeviceState::Unauthorized) {
+        ConnectionStatus::Unauthorized
+    } else if devices.iter().any(|device| device.state == DeviceState::Offline) {
+        ConnectionStatus::Offline
+    } else {
+        ConnectionStatus::NoDevice
+    };
+
+    if status == ConnectionStatus::Connected || retries == 0 {
+        Ok(status)
+    } else {
+        sleep(retry_delay);
+        connected_to_adb_server(adb, Some(retries - 1), retry_delay)
+    }
+}
+
+/// Checks that `path` points to an adb executable which actually runs
+fn validate_adb_path(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Err(anyhow!("does not exist"));
+    }
+    if !path.is_file() {
+        return Err(anyhow!("is not a file"));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = path.metadata().context("unable to read its metadata")?.permissions().mode();
+        if mode & 0o111 == 0 {
+            return Err(anyhow!("is not executable"));
+        }
+    }
+
+    let status = process::Command::new(path)
+        .arg("version")
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .logged()
+        .status()
+        .context("`adb version` could not be executed")?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("`adb version` exited with {status}"))
+    }
+}
+
+/// Finds the adb executable to use. In order of precedence:
+/// - the path given by the user with `--adb-path`
+/// - the `ADB` environment variable
+/// - the folder of the adbpuller executable, or its `platform-tools/` subfolder
+/// - the `$PATH`
+pub fn get_adb_path(user_path: Option<&Path>) -> Result<PathBuf> {
+    let adb_name = if cfg!(windows) {
+        "adb.exe"
+    } else if cfg!(unix) {
+        "adb"
+    } else {
+        return Err(anyhow!("OS is not supported"));
+    };
+
+    // A folder may be given instead of the executable itself
+    let resolve = |path: PathBuf| if path.is_dir() { path.join(adb_name) } else { path };
+
+    if let Some(path) = user_path {
+        let path = resolve(path.to_path_buf());
+        validate_adb_path(&path).with_context(|| format!("The adb path given with --adb-path {path:?} is not valid"))?;
+        return Ok(path);
+    }
+
+    if let Some(path) = env::var_os("ADB").filter(|path| !path.is_empty()) {
+        let path = resolve(PathBuf::from(path));
+        validate_adb_path(&path).with_context(|| format!("The adb path given with the ADB environment variable {path:?} is not valid"))?;
+        return Ok(path);
+    }
+
+    let exe_folder = env::current_exe()
+        .context("Failed to get path of the adbpuller executable")?
+        .parent()
+        .context("Unable to get the parent folder of the adbpuller executable")?
+        .to_path_buf();
+
+    let mut candidates = vec![exe_folder.join(adb_name), exe_folder.join("platform-tools").join(adb_name)];
+    if let Ok(path) = which("adb") {
+        candidates.push(path);
+    }
+
+    let mut tried: Vec<String> = Vec::new();
+    for candidate in candidates {
+        match validate_adb_path(&candidate) {
+            Ok(()) => return Ok(candidate),
+            Err(err) => tried.push(format!("    {candidate:?}: {err}")),
+        }
+    }
+
+    if tried.len() < 3 {
+        tried.push("    adb not found in $PATH".to_string());
+    }
+
+    Err(anyhow!(
+        "Unable to find adb drivers. Download and add them to $PATH, or pass their location with --adb-path. Tried:\n{}",
+        tried.join("\n")
+    ))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A folder of its own in the temp folder for the test `name`, emptied if a previous run left it
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("adbpuller-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An adb which is the shell script `script` written in `dir`, run with the arguments given to adb
+    #[cfg(unix)]
+    pub(crate) fn fake_adb(dir: &Path, script: &str) -> Adb {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("adb");
+        fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        Adb::new(path, None, None, None)
+    }
+
+    /// A fake adb running the commands given to `adb shell` in the local shell, joining its arguments as adb does
+    #[cfg(unix)]
+    pub(crate) fn local_shell_adb(dir: &Path) -> Adb {
+        fake_adb(dir, r#"[ "$1" = shell ] && shift; exec sh -c "$*""#)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pull_keeps_the_metadata_with_a_unless_told_not_to() {
+        let dir = test_dir("pull-metadata");
+        let mut adb = fake_adb(&dir, r#"printf '%s\n' "$@" > "$(dirname "$0")/args""#);
+        let dest = dir.join("a.jpg");
+        let pulled_with = |adb: &Adb| -> Vec<String> {
+            assert!(adb.pull("/sdcard/DCIM/a.jpg", &dest, None, |_| {}).unwrap().status.success());
+            fs::read_to_string(dir.join("args")).unwrap().lines().map(String::from).collect()
+        };
+
+        let dest_arg = dest.to_str().unwrap();
+        assert_eq!(pulled_with(&adb), ["pull", "-a", "/sdcard/DCIM/a.jpg", dest_arg]);
+        adb.keep_metadata = false;
+        assert_eq!(pulled_with(&adb), ["pull", "/sdcard/DCIM/a.jpg", dest_arg]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn shell_quote_wraps_in_single_quotes() {
+        assert_eq!(shell_quote("DCIM/a b.jpg"), "'DCIM/a b.jpg'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_quote_survives_the_shell() {
+        let args = [
+            "it's",
+            "say \"cheese\"",
+            "$(echo injected)",
+            "`echo injected`; echo injected",
+            "a b  c",
+            "Fotografie di Natale 🎄 ü",
+            "back\\slash",
+            "",
+        ];
+        for arg in args {
+            let output = process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_quote(arg)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), arg);
+        }
+    }
+
+    #[test]
+    fn classifies_pull_failures() {
+        let classify = |stderr: &str| PullFailure::from_pull(Some(1), stderr);
+        assert_eq!(classify("adb: error: device offline"), PullFailure::DeviceOffline);
+        assert_eq!(classify("adb: error: device 'ABC123' not found"), PullFailure::DeviceOffline);
+        assert_eq!(
+            classify("adb: error: cannot create '/mnt/a.jpg': No space left on device"),
+            PullFailure::NoSpace
+        );
+        assert_eq!(
+            classify("adb: error: failed to stat remote object '/sdcard/a.jpg': No such file or directory"),
+            PullFailure::NotFound
+        );
+        assert_eq!(
+            classify("adb: error: failed to stat remote object '/data/a': Permission denied"),
+            PullFailure::PermissionDenied
+        );
+        assert_eq!(
+            classify("adb: error: cannot create '/mnt/a.jpg': Read-only file system"),
+            PullFailure::LocalIo("adb: error: cannot create '/mnt/a.jpg': Read-only file system".to_string())
+        );
+        assert_eq!(
+            classify("adb: error: protocol fault (couldn't read status): Success"),
+            PullFailure::Other("adb: error: protocol fault (couldn't read status): Success".to_string())
+        );
+        assert_eq!(classify(""), PullFailure::Other("exit code 1".to_string()));
+    }
+
+    #[test]
+    fn only_the_failures_which_may_go_away_are_retryable() {
+        let retryable = [
+            PullFailure::DeviceOffline,
+            PullFailure::LocalIo("Read-only file system".to_string()),
+            PullFailure::Timeout,
+            PullFailure::SizeMismatch { expected: 2, actual: 1 },
+            PullFailure::ChecksumMismatch("sha256"),
+            PullFailure::Other("protocol fault".to_string()),
+        ];
+        for failure in retryable {
+            assert!(failure.is_retryable(), "{failure:?}");
+        }
+
+        let final_failures = [
+            PullFailure::NotFound,
+            PullFailure::PermissionDenied,
+            PullFailure::NoSpace,
+            PullFailure::NoDeviceChecksum("sha256"),
+            PullFailure::MissingAfterPull(PathBuf::from("DCIM.partial")),
+            PullFailure::Interrupted,
+        ];
+        for failure in final_failures {
+            assert!(!failure.is_retryable(), "{failure:?}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn has_command_reads_the_printed_answer() {
+        let dir = test_dir("has-command");
+        let adb = local_shell_adb(&dir);
+        assert!(adb.has_command("sh"));
+        assert!(!adb.has_command("adbpuller-no-such-command"));
+    }
+}
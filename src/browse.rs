@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Context, Result};
+use std::process;
+
+use colored::Colorize;
+use console::{Key, Term};
+use unix_path::PathBuf as UnixPathBuf;
+
+use crate::adb::{shell_quote, Adb};
+use crate::logging::LoggedCommand;
+
+/// An item inside the folder being browsed
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    /// How many items the folder contains, None if it can't be read
+    count: Option<usize>,
+}
+
+/// Lists the items inside `dir`, together with how many items each subfolder contains
+fn list_dir(adb: &Adb, dir: &UnixPathBuf) -> Result<Vec<Entry>> {
+    // A single command per folder, as each adb call takes a noticeable time
+    let script = format!(
+        "cd {} || exit 1; for f in .* *; do \
+             if [ \"$f\" = . ] || [ \"$f\" = .. ] || ! [ -e \"$f\" ]; then continue; fi; \
+             if ! [ -d \"$f\" ]; then echo \"f 0 $f\"; \
+             elif [ -r \"$f\" ]; then echo \"d $(ls -A \"$f\" 2>/dev/null | wc -l | tr -d ' ') $f\"; \
+             else echo \"d - $f\"; fi; \
+         done",
+        shell_quote(dir.to_str().unwrap())
+    );
+
+    let output = adb
+        .command()
+        .arg("shell")
+        .arg(script)
+        .stdin(process::Stdio::null())
+        .logged()
+        .output()
+        .context("Failed to list the folder")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stdout.trim().is_empty() && !stderr.trim().is_empty() {
+        return Err(anyhow!("{}", stderr.trim()));
+    }
+
+    let mut entries: Vec<Entry> = stdout
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let kind = parts.next()?;
+            let count = parts.next()?;
+            let name = parts.next()?;
+            Some(Entry {
+                name: name.to_string(),
+                is_dir: kind == "d",
+                count: count.parse().ok(),
+            })
+        })
+        .collect();
+
+    // Folders first, then files, both sorted by name
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase())));
+    Ok(entries)
+}
+
+/// Lets the user navigate the device folders and select the ones to copy.
+/// Returns the selected paths, or None if the user quit without confirming
+pub fn browse(adb: &Adb, start: UnixPathBuf) -> Result<Option<Vec<UnixPathBuf>>> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        return Err(anyhow!("browse needs an interactive terminal"));
+    }
+
+    let mut dir = start;
+    let mut entries = list_dir(adb, &dir)?;
+    let mut cursor: usize = 0;
+    let mut selected: Vec<UnixPathBuf> = Vec::new();
+    let mut message = String::new();
+
+    loop {
+        let (rows, _) = term.size();
+        let page = (rows as usize).saturating_sub(5).max(1);
+        let first = cursor.saturating_sub(page - 1);
+
+        term.clear_screen()?;
+        term.write_line(&format!("{} ({} items, {} selected)", dir.to_str().unwrap().bold(), entries.len(), selected.len()))?;
+        term.write_line("Up/Down move, Right/Enter open, Left/Backspace go up, Space select, c confirm, q quit")?;
+        term.write_line("")?;
+        for (index, entry) in entries.iter().enumerate().skip(first).take(page) {
+            let path = dir.join(&entry.name);
+            let mark = if selected.contains(&path) { "[x]" } else { "[ ]" };
+            let name = if entry.is_dir {
+                let count = entry.count.map_or("unreadable".to_string(), |count| format!("{count} items"));
+                format!("{}/ ({count})", entry.name).blue().to_string()
+            } else {
+                entry.name.clone()
+            };
+            let pointer = if index == cursor { ">" } else { " " };
+            term.write_line(&format!("{pointer} {mark} {name}"))?;
+        }
+        if !message.is_empty() {
+            term.write_line(&message.yellow().to_string())?;
+            message.clear();
+        }
+
+        match term.read_key()? {
+            Key::ArrowUp => cursor = cursor.saturating_sub(1),
+            Key::ArrowDown => cursor = (cursor + 1).min(entries.len().saturating_sub(1)),
+            Key::ArrowRight | Key::Enter => {
+                let Some(entry) = entries.get(cursor).filter(|entry| entry.is_dir) else {
+                    continue;
+                };
+                let child = dir.join(&entry.name);
+                match list_dir(adb, &child) {
+                    Ok(child_entries) => {
+                        dir = child;
+                        entries = child_entries;
+                        cursor = 0;
+                    }
+                    Err(err) => message = format!("Unable to open {:?}: {:#}", child, err),
+                }
+            }
+            Key::ArrowLeft | Key::Backspace => {
+                let Some(parent) = dir.parent().map(|parent| parent.to_path_buf()) else {
+                    continue;
+                };
+                match list_dir(adb, &parent) {
+                    Ok(parent_entries) => {
+                        // Keep the cursor on the folder just left
+                        let name = dir.file_name().map(|name| name.to_str().unwrap().to_string());
+                        cursor = parent_entries.iter().position(|entry| Some(&entry.name) == name.as_ref()).unwrap_or(0);
+                        dir = parent;
+                        entries = parent_entries;
+                    }
+                    Err(err) => message = format!("Unable to open {:?}: {:#}", parent, err),
+                }
+            }
+            Key::Char(' ') => {
+                if let Some(entry) = entries.get(cursor) {
+                    let path = dir.join(&entry.name);
+                    match selected.iter().position(|selected| *selected == path) {
+                        Some(index) => {
+                            selected.remove(index);
+                        }
+                        None => selected.push(path),
+                    }
+                }
+            }
+            Key::Char('c') => {
+                term.clear_screen()?;
+                return Ok(Some(selected));
+            }
+            Key::Char('q') | Key::Escape => {
+                term.clear_screen()?;
+                return Ok(None);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::template::civil_date;
+
+/// The folder of the files whose date isn't known, with --organize-by-date
+pub const UNKNOWN_DATE_DIR: &str = "unknown-date";
+
+/// How much of the start of a photo is searched for its EXIF data, which JPEG and HEIC files keep before the image
+const EXIF_SEARCH_BYTES: u64 = 256 * 1024;
+
+/// Seconds from 1904, when the times of MP4 and QuickTime files start, to the Unix epoch
+const SECONDS_FROM_1904: i64 = 2082844800;
+
+/// The folders of a file with --organize-by-date: the year and month of `date`, or [`UNKNOWN_DATE_DIR`]
+pub fn date_folder(date: Option<(i64, i64)>) -> Vec<String> {
+    match date {
+        Some((year, month)) => vec![format!("{year:04}"), format!("{month:02}")],
+        None => vec![UNKNOWN_DATE_DIR.to_string()],
+    }
+}
+
+/// The year and month `path` was taken, from the EXIF DateTimeOriginal of photos or the creation time of
+/// MP4 and QuickTime videos. None if it has none or it can't be read
+pub fn capture_date(path: &Path) -> Option<(i64, i64)> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" | "mov" | "3gp" => video_date(path),
+        _ => exif_date(path),
+    }
+}
+
+/// The EXIF DateTimeOriginal of the photo, or its DateTime if it hasn't one
+fn exif_date(path: &Path) -> Option<(i64, i64)> {
+    let mut data = Vec::new();
+    File::open(path).ok()?.take(EXIF_SEARCH_BYTES).read_to_end(&mut data).ok()?;
+    let start = data.windows(6).position(|window| window == b"Exif\0\0")? + 6;
+    let tiff = &data[start..];
+
+    let big_endian = match tiff.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| {
+        let bytes: [u8; 2] = tiff.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| {
+        let bytes: [u8; 4] = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        } as usize)
+    };
+    // The offset of the entry with `tag` in the IFD at `ifd`, each entry taking 12 bytes after their count
+    let find_entry = |ifd: usize, tag: u16| {
+        let count = read_u16(ifd)? as usize;
+        (0..count).map(|entry| ifd + 2 + entry * 12).find(|entry| read_u16(*entry) == Some(tag))
+    };
+
+    let ifd0 = read_u32(4)?;
+    let exif_ifd = find_entry(ifd0, 0x8769).and_then(|entry| read_u32(entry + 8));
+    let entry = exif_ifd.and_then(|ifd| find_entry(ifd, 0x9003)).or_else(|| find_entry(ifd0, 0x0132))?;
+    // The value is `YYYY:MM:DD HH:MM:SS`, too long to fit in the entry, so it holds its offset
+    let offset = read_u32(entry + 8)?;
+    let text = std::str::from_utf8(tiff.get(offset..offset + 19)?).ok()?;
+    let year: i64 = text.get(0..4)?.parse().ok()?;
+    let month: i64 = text.get(5..7)?.parse().ok()?;
+    // Some cameras write zeros when they don't know the date
+    (year > 0 && (1..=12).contains(&month)).then_some((year, month))
+}
+
+/// The creation time in the `mvhd` box of the video
+fn video_date(path: &Path) -> Option<(i64, i64)> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let (moov, moov_end) = find_box(&mut file, 0, len, b"moov")?;
+    let (mvhd, _) = find_box(&mut file, moov, moov_end, b"mvhd")?;
+
+    // The version and flags are followed by the creation time, 8 bytes long in version 1 and 4 in version 0
+    let mut header = [0u8; 12];
+    file.seek(SeekFrom::Start(mvhd)).ok()?;
+    file.read_exact(&mut header).ok()?;
+    let created = match header[0] {
+        0 => u32::from_be_bytes(header[4..8].try_into().unwrap()) as i64,
+        1 => i64::try_from(u64::from_be_bytes(header[4..12].try_into().unwrap())).ok()?,
+        _ => return None,
+    };
+    // Left at 0 by the cameras which don't know the date
+    if created == 0 {
+        return None;
+    }
+    let (year, month, _) = civil_date(created - SECONDS_FROM_1904);
+    Some((year, month))
+}
+
+/// The start and end of the content of the first box of type `kind` between `start` and `end`
+fn find_box(file: &mut File, mut start: u64, end: u64, kind: &[u8; 4]) -> Option<(u64, u64)> {
+    while start + 8 <= end {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(start)).ok()?;
+        file.read_exact(&mut header).ok()?;
+        let (content, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The size follows in 8 bytes
+            1 => {
+                let mut size = [0u8; 8];
+                file.read_exact(&mut size).ok()?;
+                (start + 16, u64::from_be_bytes(size))
+            }
+            // The box goes on until the end
+            0 => (start + 8, end - start),
+            size => (start + 8, size as u64),
+        };
+        if size < content - start {
+            return None;
+        }
+        if &header[4..] == kind {
+            return Some((content, start + size));
+        }
+        start += size;
+    }
+    None
+}
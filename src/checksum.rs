@@ -0,0 +1,322 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, read_to_string, File, OpenOptions};
+use std::io::{self, LineWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use unix_path::PathBuf as UnixPathBuf;
+
+use crate::adb::Adb;
+use crate::logging::LoggedCommand;
+
+/// The hash compared between the device and this computer with --verify checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// The command computing the hash on the device, which older toybox builds may lack
+    pub fn command(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5sum",
+            HashAlgorithm::Sha1 => "sha1sum",
+            HashAlgorithm::Sha256 => "sha256sum",
+        }
+    }
+}
+
+/// Hashes `files` on the device, returning their hashes in lowercase hex. The paths are sent through stdin
+/// to `xargs`, so that the hash command is run once for many files instead of once per file
+pub fn device_hashes(adb: &Adb, files: &[UnixPathBuf], algorithm: HashAlgorithm) -> Result<HashMap<UnixPathBuf, String>> {
+    if files.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut child = adb
+        .command()
+        .arg("shell")
+        .arg(format!("xargs -0 {}", algorithm.command()))
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .logged()
+        .spawn()
+        .context("Failed to hash the files on the device")?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut input: Vec<u8> = Vec::new();
+    for file in files {
+        input.extend_from_slice(file.as_unix_str().as_bytes());
+        input.push(0);
+    }
+    let writer = thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output().context("Failed to hash the files on the device")?;
+    let _ = writer.join();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.trim().is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("`xargs -0 {}` failed on the device: {}", algorithm.command(), stderr.trim()));
+    }
+
+    // Each line is the hash, two spaces and the path, escaped if it has a backslash or a newline. The files which
+    // couldn't be read are left out
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let (path, hash) = parse_checksum_line(line)?;
+            Some((UnixPathBuf::from(path.as_str()), hash))
+        })
+        .collect())
+}
+
+/// Hashes the file at `path` on this computer, reading it in chunks
+pub fn local_hash(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Md5 => hash_file::<Md5>(path),
+        HashAlgorithm::Sha1 => hash_file::<Sha1>(path),
+        HashAlgorithm::Sha256 => hash_file::<Sha256>(path),
+    }
+}
+
+fn hash_file<D: Digest>(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// The SHA-256 of the pulled files written with --checksum-file, in the format of `sha256sum`, with the
+/// paths relative to the destination so that `sha256sum -c` run there checks them. The files are hashed
+/// by background threads while the next ones are pulled
+pub struct ChecksumFile {
+    pub path: PathBuf,
+    root: PathBuf,
+    sender: Option<mpsc::Sender<(PathBuf, String)>>,
+    workers: Vec<thread::JoinHandle<()>>,
+    list: Arc<Mutex<ChecksumList>>,
+    bar: ProgressBar,
+}
+
+/// The lines of the checksum file, appended to as the files are hashed
+struct ChecksumList {
+    file: LineWriter<File>,
+    /// The hash of each path in the file, including those written by previous runs
+    hashes: HashMap<String, String>,
+    /// Whether a path was written again with another hash, leaving behind a line to drop
+    superseded: bool,
+    /// The files which couldn't be hashed, with the error
+    errors: Vec<String>,
+}
+
+impl ChecksumList {
+    fn add(&mut self, name: String, hash: io::Result<String>) {
+        let hash = match hash {
+            Ok(hash) => hash,
+            Err(err) => return self.errors.push(format!("{name}: {err}")),
+        };
+        match self.hashes.get(&name) {
+            Some(written) if *written == hash => return,
+            Some(_) => self.superseded = true,
+            None => {}
+        }
+        let _ = writeln!(self.file, "{}", checksum_line(&hash, &name));
+        self.hashes.insert(name, hash);
+    }
+}
+
+impl ChecksumFile {
+    /// Opens the checksum file at `path` to append to it, starting `jobs` threads hashing the files
+    /// given to `add`. Their progress is shown on a bar of `progress`
+    pub fn open(path: PathBuf, root: PathBuf, jobs: usize, progress: &MultiProgress) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|err| anyhow!("Couldn't create {:?}: {err}", parent))?;
+        }
+
+        let hashes = read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(parse_checksum_line)
+            .collect();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|err| anyhow!("Couldn't open {:?}: {err}", path))?;
+        let list = Arc::new(Mutex::new(ChecksumList {
+            file: LineWriter::new(file),
+            hashes,
+            superseded: false,
+            errors: Vec::new(),
+        }));
+
+        let bar = progress
+            .add(ProgressBar::new(0))
+            .with_style(ProgressStyle::with_template("  Hashed {human_pos}/{human_len} files").unwrap());
+        let (sender, receiver) = mpsc::channel::<(PathBuf, String)>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..jobs)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let list = Arc::clone(&list);
+                let bar = bar.clone();
+                thread::spawn(move || loop {
+                    let Ok((file, name)) = receiver.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let hash = local_hash(&file, HashAlgorithm::Sha256);
+                    list.lock().unwrap().add(name, hash);
+                    bar.inc(1);
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            path,
+            root,
+            sender: Some(sender),
+            workers,
+            list,
+            bar,
+        })
+    }
+
+    /// Queues the pulled file at `file` to be hashed
+    pub fn add(&self, file: &Path) {
+        let name = file
+            .strip_prefix(&self.root)
+            .unwrap_or(file)
+            .to_string_lossy()
+            .replace(std::path::MAIN_SEPARATOR, "/");
+        self.bar.inc_length(1);
+        if let Some(sender) = &self.sender {
+            let _ = sender.send((file.to_path_buf(), name));
+        }
+    }
+
+    /// Waits for the files still being hashed, dropping the lines of the files whose hash changed since
+    /// a previous run. Returns the files which couldn't be hashed
+    pub fn finish(mut self) -> Result<Vec<String>> {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        self.bar.finish_and_clear();
+
+        let mut list = self.list.lock().unwrap();
+        list.file.flush().map_err(|err| anyhow!("Couldn't write to {:?}: {err}", self.path))?;
+        if list.superseded {
+            // Only the last line of each path is kept, which has the hash written last
+            let content = read_to_string(&self.path).map_err(|err| anyhow!("Couldn't read {:?}: {err}", self.path))?;
+            let mut seen: HashSet<String> = HashSet::new();
+            let mut lines: Vec<&str> = content
+                .lines()
+                .rev()
+                .filter(|line| parse_checksum_line(line).is_none_or(|(name, _)| seen.insert(name)))
+                .collect();
+            lines.reverse();
+            let partial = self.path.with_extension("tmp");
+            fs::write(&partial, lines.iter().map(|line| format!("{line}\n")).collect::<String>())
+                .and_then(|_| fs::rename(&partial, &self.path))
+                .map_err(|err| anyhow!("Couldn't write to {:?}: {err}", self.path))?;
+        }
+        Ok(std::mem::take(&mut list.errors))
+    }
+}
+
+/// A line of the checksum file. Like `sha256sum`, names containing a backslash or a newline are escaped
+/// and the line starts with a backslash
+fn checksum_line(hash: &str, name: &str) -> String {
+    if !name.contains(['\\', '\n', '\r']) {
+        return format!("{hash}  {name}");
+    }
+    let escaped = name.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r");
+    format!("\\{hash}  {escaped}")
+}
+
+/// The name and hash of a line of the checksum file, None if it isn't one
+fn parse_checksum_line(line: &str) -> Option<(String, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, name) = line.split_once("  ").or_else(|| line.split_once(" *"))?;
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let name = match escaped {
+        true => name
+            .replace("\\\\", "\u{0}")
+            .replace("\\n", "\n")
+            .replace("\\r", "\r")
+            .replace('\u{0}', "\\"),
+        false => name.to_string(),
+    };
+    Some((name, hash.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_escaped_checksum_lines() {
+        let hash = "d41d8cd98f00b204e9800998ecf8427e";
+        let parsed = |name: &str| Some((name.to_string(), hash.to_string()));
+        assert_eq!(parse_checksum_line(&format!("{hash}  DCIM/a.jpg")), parsed("DCIM/a.jpg"));
+        assert_eq!(parse_checksum_line(&format!("{hash} *DCIM/a.jpg")), parsed("DCIM/a.jpg"));
+        assert_eq!(parse_checksum_line(&format!("\\{hash}  a\\\\b")), parsed("a\\b"));
+        assert_eq!(parse_checksum_line(&format!("\\{hash}  line\\nbreak")), parsed("line\nbreak"));
+        assert_eq!(parse_checksum_line(&format!("\\{hash}  a\\\\nb")), parsed("a\\nb"));
+        assert_eq!(parse_checksum_line("not a hash  a.jpg"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn device_hashes_unescapes_the_paths() {
+        use crate::adb::tests::{local_shell_adb, test_dir};
+
+        let dir = test_dir("device-hashes");
+        let adb = local_shell_adb(&dir);
+        let names = ["plain.jpg", "back\\slash.jpg", "line\nbreak.jpg"];
+        let mut files = Vec::new();
+        for name in names {
+            fs::write(dir.join(name), name).unwrap();
+            files.push(UnixPathBuf::from(dir.join(name).to_str().unwrap()));
+        }
+
+        let hashes = device_hashes(&adb, &files, HashAlgorithm::Md5).unwrap();
+        for (name, file) in names.iter().zip(&files) {
+            let local = local_hash(&dir.join(name), HashAlgorithm::Md5).unwrap();
+            assert_eq!(hashes.get(file), Some(&local), "{name:?}");
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, read_to_string, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::checksum::{local_hash, HashAlgorithm};
+use crate::pull::PARTIAL_EXTENSION;
+
+/// The file in the folder of --dedupe-against keeping the hashes of its files for the next runs
+pub const INDEX_FILE: &str = ".adbpuller-index.tsv";
+
+/// The hash the files are compared by, the one `md5sum` gives on the device
+pub const DEDUPE_HASH: HashAlgorithm = HashAlgorithm::Md5;
+
+/// A file under the folder of --dedupe-against
+struct LocalFile {
+    /// Relative to the folder
+    path: PathBuf,
+    mtime: i64,
+    hash: Option<String>,
+}
+
+/// The files under the folder of --dedupe-against by size. They are hashed only when a file of the device has
+/// the same size, and the hashes are kept in [`INDEX_FILE`] as long as the size and modification time of the
+/// file don't change
+pub struct DedupeIndex {
+    dir: PathBuf,
+    by_size: HashMap<u64, Vec<LocalFile>>,
+    /// Whether a file was hashed since the index was read
+    changed: bool,
+}
+
+impl DedupeIndex {
+    /// Lists the files under `dir`, taking the hashes which are still valid from its index
+    pub fn open(dir: &Path) -> Result<Self> {
+        if !dir.is_dir() {
+            return Err(anyhow!("{:?} isn't a folder", dir));
+        }
+        let mut cached = read_index(&dir.join(INDEX_FILE));
+
+        let mut by_size: HashMap<u64, Vec<LocalFile>> = HashMap::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(folder) = pending.pop() {
+            // The folders which can't be read are left out, as their files can't be copied from anyway
+            let Ok(entries) = fs::read_dir(dir.join(&folder)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = folder.join(entry.file_name());
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !metadata.is_file() || name == INDEX_FILE || name.ends_with(PARTIAL_EXTENSION) {
+                    continue;
+                }
+
+                let mtime = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs() as i64);
+                let hash = cached
+                    .remove(&path)
+                    .filter(|(size, cached_mtime, _)| *size == metadata.len() && *cached_mtime == mtime)
+                    .map(|(_, _, hash)| hash);
+                by_size.entry(metadata.len()).or_default().push(LocalFile { path, mtime, hash });
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            by_size,
+            changed: false,
+        })
+    }
+
+    /// How many files are in the folder
+    pub fn len(&self) -> usize {
+        self.by_size.values().map(Vec::len).sum()
+    }
+
+    /// Whether a file of the folder has `size`, so that a file of the device is worth hashing
+    pub fn has_size(&self, size: u64) -> bool {
+        self.by_size.contains_key(&size)
+    }
+
+    /// The file of the folder with `size` and `hash`, hashing those of the same size until it's found
+    pub fn find(&mut self, size: u64, hash: &str) -> Option<PathBuf> {
+        let files = self.by_size.get_mut(&size)?;
+        for file in files.iter_mut() {
+            if file.hash.is_none() {
+                file.hash = local_hash(&self.dir.join(&file.path), DEDUPE_HASH).ok();
+                self.changed |= file.hash.is_some();
+            }
+            if file.hash.as_deref() == Some(hash) {
+                return Some(self.dir.join(&file.path));
+            }
+        }
+        None
+    }
+
+    /// The file of the folder with `size` and named `name`, for when the device can't hash its files
+    pub fn find_by_name(&self, size: u64, name: &str) -> Option<PathBuf> {
+        self.by_size
+            .get(&size)?
+            .iter()
+            .find(|file| file.path.file_name().is_some_and(|file_name| file_name == name))
+            .map(|file| self.dir.join(&file.path))
+    }
+
+    /// Writes the hashes to the index, if any file was hashed
+    pub fn save(&self) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let path = self.dir.join(INDEX_FILE);
+        let mut writer = BufWriter::new(File::create(&path).with_context(|| format!("Unable to create {:?}", path))?);
+        for (size, files) in self.by_size.iter() {
+            for file in files {
+                let (Some(hash), Some(relative)) = (&file.hash, file.path.to_str()) else {
+                    continue;
+                };
+                // A line for each file, so the names with a newline are hashed again every time
+                if !relative.contains('\n') {
+                    writeln!(writer, "{size}\t{}\t{hash}\t{relative}", file.mtime).with_context(|| format!("Unable to write {:?}", path))?;
+                }
+            }
+        }
+        writer.flush().with_context(|| format!("Unable to write {:?}", path))
+    }
+}
+
+/// The size, modification time and hash of each file in the index at `path`, by its path relative to the folder
+fn read_index(path: &Path) -> HashMap<PathBuf, (u64, i64, String)> {
+    read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let size = fields.next()?.parse().ok()?;
+            let mtime = fields.next()?.parse().ok()?;
+            let hash = fields.next()?.to_string();
+            Some((PathBuf::from(fields.next()?), (size, mtime, hash)))
+        })
+        .collect()
+}
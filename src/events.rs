@@ -0,0 +1,69 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+/// Set by --progress-json
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The outcome of a file in a [`Event::FileDone`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Ok,
+    Failed,
+}
+
+/// The events printed on stdout with --progress-json, one JSON object per line. Their fields are
+/// documented in the long help of the command and only ever added to, never renamed or removed
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A source was listed
+    Listing { source: &'a str, count: usize, to_copy: usize },
+    /// A file is about to be pulled. `index` counts from 0 up to `total` - 1
+    FileStart {
+        source_path: &'a str,
+        dest_path: &'a str,
+        size_bytes: Option<u64>,
+        index: usize,
+        total: usize,
+    },
+    /// A file was pulled, or failed to be
+    FileDone {
+        source_path: &'a str,
+        dest_path: &'a str,
+        bytes: u64,
+        status: FileStatus,
+        error: Option<String>,
+    },
+    /// The run is over, also when interrupted
+    Summary {
+        copied: usize,
+        failed: usize,
+        skipped: usize,
+        bytes: u64,
+        elapsed_secs: f64,
+    },
+}
+
+/// Prints `event` on its own line if --progress-json was given, flushing it right away so that it
+/// can be read while the copy goes on
+pub fn emit(event: &Event) {
+    if !enabled() {
+        return;
+    }
+    let Ok(line) = serde_json::to_string(event) else {
+        return;
+    };
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{line}").and_then(|_| stdout.flush());
+}
@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+
+use crate::{exit_with, for_stream, ExitStatus};
+
+/// How long the pulls running when Ctrl-C is pressed are given to finish before being killed
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Set by the first Ctrl-C
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED_AT: OnceLock<Instant> = OnceLock::new();
+
+/// Handles Ctrl-C from now on: the first one asks the copy to stop after the files being pulled,
+/// the second one exits right away
+pub fn install() {
+    let _ = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            exit_with(ExitStatus::Interrupted);
+        }
+        let _ = INTERRUPTED_AT.set(Instant::now());
+        let message = format!(
+            "Interrupted, finishing the files being pulled for up to {} seconds. Press Ctrl-C again to exit right away",
+            GRACE_PERIOD.as_secs()
+        );
+        eprintln!("{}", for_stream(message.yellow().to_string(), true));
+    });
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Whether the pulls still running after Ctrl-C should be killed
+pub fn grace_over() -> bool {
+    INTERRUPTED_AT.get().is_some_and(|at| at.elapsed() >= GRACE_PERIOD)
+}
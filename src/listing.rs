@@ -0,0 +1,619 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::{self, Output};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use indicatif::ProgressBar;
+use unix_path::PathBuf as UnixPathBuf;
+use unix_str::UnixStr;
+
+use crate::adb::{shell_quote, spawn_draining_stderr, Adb};
+use crate::logging::{debug, verbosity, LoggedCommand, LISTING_PREVIEW_LINES};
+use crate::{escape_path, unescape_path};
+
+/// What a source path points to on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    File,
+    Directory,
+    Missing,
+}
+
+/// Checks whether `path` is a file or a directory on the device
+pub fn get_source_kind(adb: &Adb, path: &UnixPathBuf) -> Result<SourceKind> {
+    let quoted = shell_quote(path.as_unix_str().to_str().unwrap());
+
+    let answer = adb
+        .shell_answer(&format!("if [ -f {quoted} ]; then echo file; elif [ -d {quoted} ]; then echo dir; fi"))
+        .context("Failed to check whether the source is a file or a folder")?;
+
+    Ok(match answer.as_str() {
+        "file" => SourceKind::File,
+        "dir" => SourceKind::Directory,
+        _ => SourceKind::Missing,
+    })
+}
+
+/// Whether `path` contains wildcards to be expanded on the device
+pub fn is_glob(path: &UnixPathBuf) -> bool {
+    path.to_str().unwrap().contains(['*', '?', '['])
+}
+
+/// Quotes `pattern` for the device shell leaving the wildcards unquoted, so that they are still expanded
+fn glob_quote(pattern: &str) -> String {
+    let mut quoted = String::new();
+    let mut literal = String::new();
+    for c in pattern.chars() {
+        if matches!(c, '*' | '?' | '[' | ']') {
+            if !literal.is_empty() {
+                quoted.push_str(&shell_quote(&literal));
+                literal.clear();
+            }
+            quoted.push(c);
+        } else {
+            literal.push(c);
+        }
+    }
+    if !literal.is_empty() {
+        quoted.push_str(&shell_quote(&literal));
+    }
+    quoted
+}
+
+/// Expands the wildcards in `pattern` on the device, returning the matching paths sorted by name
+pub fn expand_glob(adb: &Adb, pattern: &UnixPathBuf) -> Result<Vec<UnixPathBuf>> {
+    // When nothing matches the shell leaves the pattern as is, which is then filtered out by `-e`
+    let output = adb
+        .command()
+        .arg("shell")
+        .arg(format!(
+            "for f in {}; do [ -e \"$f\" ] && echo \"$f\"; done",
+            glob_quote(pattern.to_str().unwrap())
+        ))
+        .stdin(process::Stdio::null())
+        .logged()
+        .output()
+        .context("Failed to expand the wildcards on the device")?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(UnixPathBuf::from)
+        .collect())
+}
+
+/// Options controlling how the files on the device are listed
+#[derive(Debug, Clone, Default)]
+pub struct ListingOptions {
+    /// List the files inside symlinked folders too
+    pub follow_symlinks: bool,
+    /// How many levels below the source to descend, where 1 means only its direct children
+    pub max_depth: Option<usize>,
+    /// Get the size and the last modification time of the files too
+    pub with_stats: bool,
+}
+
+/// Resolves symlinks in `path` on the device (e.g. `/sdcard` into `/storage/emulated/0`),
+/// returning `path` itself if it can't be resolved
+pub fn canonicalize(adb: &Adb, path: &UnixPathBuf) -> UnixPathBuf {
+    let Ok(output) = adb
+        .command()
+        .arg("shell")
+        .arg("readlink")
+        .arg("-f")
+        .arg(shell_quote(path.as_unix_str().to_str().unwrap()))
+        .logged()
+        .output()
+    else {
+        return path.clone();
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let canonical = stdout.trim_end_matches(['\r', '\n']);
+    if output.status.success() && canonical.starts_with('/') {
+        UnixPathBuf::from(canonical)
+    } else {
+        path.clone()
+    }
+}
+
+/// The files found inside a source, together with the folders which couldn't be read
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub files: Vec<UnixPathBuf>,
+    pub unreadable_dirs: Vec<UnixPathBuf>,
+    /// The size and modification time of the files, None if they weren't requested or the device can't report them
+    pub stats: Option<HashMap<UnixPathBuf, FileStat>>,
+}
+
+/// The size and modification time of a file on the device
+#[derive(Debug, Clone, Copy)]
+pub struct FileStat {
+    /// Size in bytes
+    pub size: u64,
+    /// Last modification time, in seconds since the Unix epoch
+    pub mtime: i64,
+    /// The fraction of the second of the modification time, in nanoseconds, 0 if the device doesn't tell it
+    pub mtime_nanos: u32,
+}
+
+/// Writes the listings to `path`, to be reused later with [`load_listings`]. Each source is written
+/// as a `source <path>` line followed by the paths of its files, one per line, and its unreadable
+/// folders as `unreadable <path>` lines
+pub fn save_listings(path: &Path, listings: &[(UnixPathBuf, &Listing)]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Unable to create the listing file {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "# adbpuller listing")?;
+    for (root_src, listing) in listings {
+        writeln!(writer, "source {}", escape_path(root_src.to_str().unwrap()))?;
+        for file in listing.files.iter() {
+            writeln!(writer, "{}", escape_path(file.to_str().unwrap()))?;
+        }
+        for dir in listing.unreadable_dirs.iter() {
+            writeln!(writer, "unreadable {}", escape_path(dir.to_str().unwrap()))?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads the listings written by [`save_listings`]
+pub fn load_listings(path: &Path) -> Result<Vec<(UnixPathBuf, Listing)>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Unable to read the listing file {:?}", path))?;
+
+    let mut listings: Vec<(UnixPathBuf, Listing)> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(root_src) = line.strip_prefix("source ") {
+            listings.push((UnixPathBuf::from(unescape_path(root_src)), Listing::default()));
+            continue;
+        }
+
+        let Some((_, listing)) = listings.last_mut() else {
+            return Err(anyhow!("Line {} of {:?} comes before any source", number + 1, path));
+        };
+        if let Some(dir) = line.strip_prefix("unreadable ") {
+            listing.unreadable_dirs.push(UnixPathBuf::from(unescape_path(dir)));
+        } else {
+            listing.files.push(UnixPathBuf::from(unescape_path(line)));
+        }
+    }
+
+    Ok(listings)
+}
+
+/// Returns which of `paths` don't exist on the device. The paths are sent through stdin so that
+/// their number isn't limited by the maximum length of the command line
+pub fn find_missing(adb: &Adb, paths: &[UnixPathBuf]) -> Result<HashSet<UnixPathBuf>> {
+    let mut child = adb
+        .command()
+        .arg("shell")
+        .arg("while IFS= read -r f; do [ -e \"$f\" ] || echo \"$f\"; done")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        .logged()
+        .spawn()
+        .context("Failed to check whether the files exist on the device")?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let input: String = paths.iter().map(|path| format!("{}\n", path.to_str().unwrap())).collect();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = child.wait_with_output().context("Failed to check whether the files exist on the device")?;
+    let _ = writer.join();
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(UnixPathBuf::from)
+        .collect())
+}
+
+/// Gets the size and modification time of `files`. The paths are sent through stdin to `xargs`, so that
+/// `stat` is run once for many files instead of once per file
+pub fn get_file_stats(adb: &Adb, files: &[UnixPathBuf]) -> Result<HashMap<UnixPathBuf, FileStat>> {
+    if files.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut child = adb
+        .command()
+        .arg("shell")
+        .arg("xargs -0 stat -c '%s %Y %y %n'")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .logged()
+        .spawn()
+        .context("Failed to get the size and modification time of the files")?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut input: Vec<u8> = Vec::new();
+    for file in files {
+        input.extend_from_slice(file.as_unix_str().as_bytes());
+        input.push(0);
+    }
+    let writer = thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output().context("Failed to get the size and modification time of the files")?;
+    let _ = writer.join();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stdout.trim().is_empty() {
+        return Err(anyhow!("`xargs -0 stat` failed on the device: {}", stderr.trim()));
+    }
+
+    // Names containing newlines are split across lines and can't be parsed, their stats stay unknown
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(5, ' ');
+            let size = parts.next()?.parse().ok()?;
+            let mtime = parts.next()?.parse().ok()?;
+            // %y is like `2024-07-01 12:34:56.123456789 +0200`, only the fraction of the second is taken from it.
+            // The time zone is left out by some builds, so the path is told apart by its leading slash
+            let _date = parts.next()?;
+            let mtime_nanos = parts.next()?.split_once('.').map_or(0, |(_, fraction)| parse_nanos(fraction));
+            let rest = parts.next()?;
+            let path = match rest.starts_with('/') {
+                true => rest,
+                false => rest.split_once(' ')?.1,
+            };
+            Some((UnixPathBuf::from(path), FileStat { size, mtime, mtime_nanos }))
+        })
+        .collect())
+}
+
+/// The nanoseconds of the fraction of a second written as `fraction`, e.g. 5 for 0.5 seconds
+fn parse_nanos(fraction: &str) -> u32 {
+    let digits: String = fraction.chars().chain(std::iter::repeat('0')).take(9).collect();
+    digits.parse().unwrap_or(0)
+}
+
+/// Whether the file at `path` was last modified more than `age` ago
+pub fn is_older_than(path: &Path, age: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|elapsed| elapsed > age)
+}
+
+/// Lists the files inside `root_path` on the device, recursively. If `root_path` is a file
+/// the list contains only the file itself
+pub fn get_files_from_adb(
+    adb: &Adb,
+    root_path: &UnixPathBuf,
+    kind: SourceKind,
+    options: &ListingOptions,
+    progress: &ProgressBar,
+) -> Result<Listing> {
+    match kind {
+        SourceKind::File => {
+            return Ok(Listing {
+                files: vec![root_path.clone()],
+                ..Default::default()
+            })
+        }
+        SourceKind::Missing => return Err(anyhow!("It does not exist on the device")),
+        SourceKind::Directory => {}
+    }
+
+    let quoted = shell_quote(root_path.as_unix_str().to_str().unwrap());
+
+    // Paths are separated by NUL bytes, so file names containing newlines are listed correctly.
+    // Old toybox builds may not support all the flags, in which case they are dropped one at a time
+    let mut print0 = true;
+    let mut maxdepth = options.max_depth.is_some();
+    let output = loop {
+        let mut cmd = adb.command();
+        cmd.arg("shell")
+            .arg("find")
+            .args(options.follow_symlinks.then_some("-L"))
+            .arg(&quoted)
+            .args(options.max_depth.filter(|_| maxdepth).map(|depth| format!("-maxdepth {depth}")))
+            .arg("-type")
+            .arg("f")
+            .args(print0.then_some("-print0"));
+
+        let separator = if print0 { 0 } else { b'\n' };
+        let output = output_with_progress(cmd, separator, progress).context("Failed to execute `adb shell find`")?;
+
+        if find_is_missing(&output) {
+            return get_files_from_adb_ls(adb, root_path, options, progress);
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if print0 && !output.stdout.contains(&0) && stderr.contains("print0") {
+            print0 = false;
+        } else if maxdepth && stderr.contains("maxdepth") {
+            maxdepth = false;
+        } else {
+            break output;
+        }
+    };
+
+    let files = if print0 {
+        parse_find_print0_output(&output.stdout)
+    } else {
+        let stdout = String::from_utf8(output.stdout).context("Unable to read the output of `adb shell find <path> -type f`")?;
+        parse_find_output(&stdout)
+    };
+
+    Ok(Listing {
+        files: limit_depth(files, root_path, options.max_depth),
+        unreadable_dirs: parse_unreadable_dirs(&String::from_utf8_lossy(&output.stderr)),
+        stats: None,
+    })
+}
+
+/// Lists the folders inside `root_path` on the device, itself included, for --keep-empty-dirs
+pub fn get_dirs_from_adb(adb: &Adb, root_path: &UnixPathBuf, options: &ListingOptions) -> Result<Vec<UnixPathBuf>> {
+    let quoted = shell_quote(root_path.as_unix_str().to_str().unwrap());
+
+    // As for the files, -print0 is dropped by the old toybox builds which don't support it
+    let mut print0 = true;
+    let output = loop {
+        let output = adb
+            .command()
+            .arg("shell")
+            .arg("find")
+            .args(options.follow_symlinks.then_some("-L"))
+            .arg(&quoted)
+            .arg("-type")
+            .arg("d")
+            .args(print0.then_some("-print0"))
+            .stdin(process::Stdio::null())
+            .logged()
+            .output()
+            .context("Failed to execute `adb shell find <path> -type d`")?;
+        if print0 && !output.stdout.contains(&0) && String::from_utf8_lossy(&output.stderr).contains("print0") {
+            print0 = false;
+        } else {
+            break output;
+        }
+    };
+
+    let dirs = match print0 {
+        true => parse_find_print0_output(&output.stdout),
+        false => parse_find_output(&String::from_utf8_lossy(&output.stdout)),
+    };
+    Ok(limit_depth(dirs, root_path, options.max_depth))
+}
+
+/// Keeps only the files at most `max_depth` levels below `root_path`, where 1 means its direct children
+fn limit_depth(mut files: Vec<UnixPathBuf>, root_path: &UnixPathBuf, max_depth: Option<usize>) -> Vec<UnixPathBuf> {
+    if let Some(max_depth) = max_depth {
+        files.retain(|file| match file.strip_prefix(root_path) {
+            Ok(rel) => rel.components().count() <= max_depth,
+            Err(_) => true,
+        });
+    }
+    files
+}
+
+/// Both `find` and `ls` report the folders they can't read on stderr, in the form
+/// `find: /sdcard/Android/data: Permission denied` (or `find: '/sdcard/...': Permission denied`)
+fn parse_unreadable_dirs(stderr: &str) -> Vec<UnixPathBuf> {
+    stderr
+        .lines()
+        .filter_map(|line| line.trim_end().strip_suffix(": Permission denied"))
+        .map(|line| line.strip_prefix("find: ").or_else(|| line.strip_prefix("ls: ")).unwrap_or(line))
+        .map(|path| path.trim_matches(|c| c == '\'' || c == '"' || c == '‘' || c == '’'))
+        .map(UnixPathBuf::from)
+        .collect()
+}
+
+/// Like [`process::Command::output`], but reads stdout while the command is running to show how many
+/// entries, terminated by `separator`, have been found so far
+fn output_with_progress(mut cmd: process::Command, separator: u8, progress: &ProgressBar) -> io::Result<Output> {
+    let (mut child, stderr_reader) = spawn_draining_stderr(&mut cmd)?;
+
+    let mut child_stdout = child.stdout.take().unwrap();
+    let mut stdout = Vec::new();
+    let mut buffer = [0u8; 64 * 1024];
+    let mut entries = 0;
+    loop {
+        let read = child_stdout.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        entries += buffer[..read].iter().filter(|byte| **byte == separator).count();
+        stdout.extend_from_slice(&buffer[..read]);
+        progress.set_position(entries as u64);
+    }
+
+    let status = child.wait()?;
+    let stderr = stderr_reader.join().unwrap_or_default();
+
+    if verbosity() >= 2 {
+        for entry in stdout.split(|byte| *byte == separator).filter(|entry| !entry.is_empty()).take(LISTING_PREVIEW_LINES) {
+            debug(2, format!("    {}", String::from_utf8_lossy(entry)));
+        }
+        if entries > LISTING_PREVIEW_LINES {
+            debug(2, format!("    .. and {} more", entries - LISTING_PREVIEW_LINES));
+        }
+    }
+
+    Ok(Output { status, stdout, stderr })
+}
+
+/// Old devices may ship without toybox `find`, in which case the shell reports it as not found
+fn find_is_missing(output: &Output) -> bool {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    output.status.code() == Some(127) || stdout.contains("find: not found") || stderr.contains("find: not found")
+}
+
+/// `find <path> -type f -print0` outputs the absolute path of each file, each followed by a NUL byte
+fn parse_find_print0_output(output: &[u8]) -> Vec<UnixPathBuf> {
+    output
+        .split(|byte| *byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| UnixPathBuf::from(UnixStr::from_bytes(path)))
+        .collect()
+}
+
+/// `find <path> -type f` outputs the absolute path of each file, one per line
+fn parse_find_output(output: &str) -> Vec<UnixPathBuf> {
+    output.lines().filter(|line| !line.trim().is_empty()).map(UnixPathBuf::from).collect()
+}
+
+/// Lists the files with `ls -R`, for devices where `find` is not available
+fn get_files_from_adb_ls(adb: &Adb, root_path: &UnixPathBuf, options: &ListingOptions, progress: &ProgressBar) -> Result<Listing> {
+    let mut cmd = adb.command();
+    cmd.arg("shell");
+    cmd.arg("ls");
+    cmd.arg(if options.follow_symlinks { "-RL" } else { "-R" });
+    cmd.arg(shell_quote(root_path.as_unix_str().to_str().unwrap()));
+
+    // Folder headers and subfolders are counted too, so the progress is only an estimate
+    let output = output_with_progress(cmd, b'\n', progress).context("Failed to execute `adb shell ls -R`")?;
+
+    let stdout = String::from_utf8(output.stdout).context("Unable to read the output of `adb shell ls -R <path>`")?;
+    let files = parse_ls_output(&stdout, root_path);
+
+    Ok(Listing {
+        files: limit_depth(files, root_path, options.max_depth),
+        unreadable_dirs: parse_unreadable_dirs(&String::from_utf8_lossy(&output.stderr)),
+        stats: None,
+    })
+}
+
+/// `ls -R <folder>` outputs the content of each folder in a block starting with a `<folder>:` header,
+/// with blocks separated by an empty line. Subfolders are listed as entries of their parent too, so
+/// they are removed once all the headers are known.
+///
+/// Only the first line of a block can be a header, so entries ending with `:` and folders whose
+/// names contain `:` are not mistaken for one another
+fn parse_ls_output(output: &str, root_path: &UnixPathBuf) -> Vec<UnixPathBuf> {
+    let mut file_list: Vec<UnixPathBuf> = Vec::new();
+    let mut folders: HashSet<UnixPathBuf> = HashSet::new();
+    let root = root_path.as_unix_str().to_str().unwrap();
+
+    let mut current_folder_root: UnixPathBuf = UnixPathBuf::from(&root_path); // default, but should be changed right away
+    let mut block_start = true;
+    for line in output.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            block_start = true;
+            continue;
+        }
+
+        if block_start && line.starts_with(root) && line.ends_with(':') {
+            current_folder_root = UnixPathBuf::from(&line[..line.len() - 1]);
+            folders.insert(current_folder_root.clone());
+        } else {
+            file_list.push(current_folder_root.join(line));
+        }
+        block_start = false;
+    }
+
+    file_list.retain(|path| !folders.contains(path));
+    file_list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<UnixPathBuf> {
+        paths.iter().map(UnixPathBuf::from).collect()
+    }
+
+    #[test]
+    fn parses_ls_recursive_output() {
+        let output = "\
+/sdcard/DCIM:
+Camera
+Empty
+Screenshots
+
+/sdcard/DCIM/Camera:
+IMG_0001.jpg
+VID_0002.mp4
+
+/sdcard/DCIM/Empty:
+
+/sdcard/DCIM/Screenshots:
+Screenshot_0003.png
+";
+        let expected = paths(&[
+            "/sdcard/DCIM/Camera/IMG_0001.jpg",
+            "/sdcard/DCIM/Camera/VID_0002.mp4",
+            "/sdcard/DCIM/Screenshots/Screenshot_0003.png",
+        ]);
+        let root = UnixPathBuf::from("/sdcard/DCIM");
+        assert_eq!(parse_ls_output(output, &root), expected);
+        assert_eq!(parse_ls_output(&output.replace('\n', "\r\n"), &root), expected);
+    }
+
+    #[test]
+    fn ls_recursive_folders_may_have_colons_in_their_names() {
+        let output = "\
+/sdcard/Music:
+Live: 2020
+intro.mp3
+
+/sdcard/Music/Live: 2020:
+track: 1.mp3
+ends with a colon:
+";
+        assert_eq!(
+            parse_ls_output(output, &UnixPathBuf::from("/sdcard/Music")),
+            paths(&[
+                "/sdcard/Music/intro.mp3",
+                "/sdcard/Music/Live: 2020/track: 1.mp3",
+                "/sdcard/Music/Live: 2020/ends with a colon:"
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_find_print0_output() {
+        let output = b"/sdcard/DCIM/Camera/IMG_0001.jpg\0/sdcard/DCIM/My Photos/a b.jpg\0";
+        assert_eq!(
+            parse_find_print0_output(output),
+            paths(&["/sdcard/DCIM/Camera/IMG_0001.jpg", "/sdcard/DCIM/My Photos/a b.jpg"])
+        );
+        assert!(parse_find_print0_output(b"").is_empty());
+    }
+
+    #[test]
+    fn find_print0_keeps_newlines_in_file_names() {
+        let output = b"/sdcard/DCIM/line\nbreak.jpg\0/sdcard/DCIM/IMG_0001.jpg\0";
+        assert_eq!(
+            parse_find_print0_output(output),
+            paths(&["/sdcard/DCIM/line\nbreak.jpg", "/sdcard/DCIM/IMG_0001.jpg"])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn source_kind_of_file_folder_and_missing() {
+        use crate::adb::tests::{local_shell_adb, test_dir};
+
+        let dir = test_dir("source-kind");
+        let adb = local_shell_adb(&dir);
+        fs::create_dir(dir.join("DCIM")).unwrap();
+        fs::write(dir.join("DCIM/it's a photo.jpg"), "").unwrap();
+        let path = |name: &str| UnixPathBuf::from(dir.join(name).to_str().unwrap());
+
+        assert_eq!(get_source_kind(&adb, &path("DCIM/it's a photo.jpg")).unwrap(), SourceKind::File);
+        assert_eq!(get_source_kind(&adb, &path("DCIM")).unwrap(), SourceKind::Directory);
+        assert_eq!(get_source_kind(&adb, &path("DCIM/missing.jpg")).unwrap(), SourceKind::Missing);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::adb::shell_quote;
+use crate::template::civil_date;
+
+/// How many entries of a listing are printed at verbosity 2
+pub const LISTING_PREVIEW_LINES: usize = 10;
+
+/// Set by --verbose, 0 if it wasn't given
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// The --log-file. Lines are written as soon as they are complete, so that they aren't lost if the
+/// run is interrupted, e.g. with Ctrl-C
+static LOG_FILE: Mutex<Option<LineWriter<File>>> = Mutex::new(None);
+
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Prints `message` on stderr if --verbose was given at least `level` times
+pub fn debug(level: u8, message: impl fmt::Display) {
+    if verbosity() >= level {
+        eprintln!("{message}");
+    }
+}
+
+/// Opens the --log-file at `path` for appending, creating it together with its parent folders
+pub fn open_log_file(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).with_context(|| format!("Unable to create {:?}", parent))?;
+    }
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("Unable to open the log file {:?}", path))?;
+    *LOG_FILE.lock().unwrap() = Some(LineWriter::new(file));
+    Ok(())
+}
+
+/// Writes `message` to the --log-file, if given, prefixing each of its lines with the current time.
+/// Errors are ignored, as the log mustn't stop the copy
+pub fn log(message: impl fmt::Display) {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    let Some(file) = log_file.as_mut() else {
+        return;
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let timestamp = format_timestamp(now);
+    for line in message.to_string().lines() {
+        let _ = writeln!(file, "[{timestamp}] {line}");
+    }
+}
+
+/// Formats the seconds since the Unix epoch as YYYY-MM-DD HH:MM:SS in UTC
+pub fn format_timestamp(seconds: i64) -> String {
+    let (year, month, day) = civil_date(seconds);
+    let time = seconds.rem_euclid(24 * 60 * 60);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        time / (60 * 60),
+        time % (60 * 60) / 60,
+        time % 60
+    )
+}
+
+/// Formats the command so that it can be pasted in a shell to run it again
+fn format_command(cmd: &process::Command) -> String {
+    quote_words(std::iter::once(cmd.get_program()).chain(cmd.get_args()))
+}
+
+/// Joins `words` with spaces, quoting those which the shell would otherwise split or expand
+pub fn quote_words<S: AsRef<OsStr>>(words: impl IntoIterator<Item = S>) -> String {
+    words
+        .into_iter()
+        .map(|word| {
+            let word = word.as_ref().to_string_lossy();
+            let plain = !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+            if plain {
+                word.into_owned()
+            } else {
+                shell_quote(&word)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub trait LoggedCommand {
+    /// Prints the command with --verbose, to be called right before running it
+    fn logged(&mut self) -> &mut Self;
+}
+
+impl LoggedCommand for process::Command {
+    fn logged(&mut self) -> &mut Self {
+        debug(1, format!("$ {}", format_command(self)));
+        self
+    }
+}
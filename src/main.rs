@@ -1,446 +1,4481 @@
-use anyhow::{anyhow, Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
-use std::collections::HashSet;
-use std::fs::{read_to_string, OpenOptions};
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use std::process::exit;
-use std::time::Duration;
-
-use std::{env, process};
-use unix_path::{Path as UnixPath, PathBuf as UnixPathBuf};
-
-use which::which;
-
-use clap::{ArgAction, Args, Parser};
-use colored::Colorize;
-
-use normpath::BasePathBuf;
-
-#[derive(Args, Debug)]
-#[group(required = true, multiple = true)]
-struct Sources {
-    /// The folder(s) or item(s) to copy
-    #[arg(short, long, num_args = 0..,)]
-    sources: Vec<UnixPathBuf>,
-
-    /// Add /sdcard/DCIM and /sdcard/Pictures to the sources
-    #[arg(short = 'm', long = "copy-media")]
-    media_preset: bool,
-
-    /// Add Whatsapp Audio, Images, Video and Voice Notes to the sources
-    #[arg(short = 'w', long = "copy-whatsapp")]
-    whatsapp_preset: bool,
-
-    /// Add Whatsapp Backup and Databases folders to the sources
-    #[arg(short = 'b', long = "copy-whatsapp-backups")]
-    whatsapp_backups_preset: bool,
-}
-
-/// Pull files from android using ADB drivers
-#[derive(Parser, Debug)]
-#[command(version, about)]
-#[command(long_about = "Pull files from android using ADB drivers
-
-Example:
-    ./adb_puller.exe -s /sdcard/DCIM")]
-struct Cli {
-    #[command(flatten)]
-    source: Sources,
-
-    /// The folder in which to copy the files
-    #[arg(short, long, default_value = ".")]
-    dest: PathBuf,
-
-    /// Skip files written in a file
-    #[arg(long, value_parser, num_args = 0..)]
-    skip: Option<Vec<PathBuf>>,
-
-    /// Print which files would be copied and where
-    #[arg(long, action = ArgAction::SetTrue)]
-    dry_run: bool,
-
-    /// Overwrite files already present in the destination folder.
-    #[arg(short, long = "force", action = ArgAction::SetTrue)]
-    force: bool,
-
-    /// Don't copy metadata such as last modification date ecc..
-    #[arg(long = "no-metadata", action = ArgAction::SetTrue)]
-    no_metadata: bool,
-}
-
-impl Cli {
-    fn check_sources(&mut self) {
-        let mut sources: Vec<UnixPathBuf> = Vec::new();
-
-        if self.source.media_preset {
-            sources.extend([UnixPathBuf::from("/sdcard/DCIM"), UnixPathBuf::from("/sdcard/Pictures")])
-        }
-
-        if self.source.whatsapp_preset {
-            sources.extend([
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Audio"),
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Images"),
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Video"),
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Voice Notes"),
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Video Notes"),
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Documents"),
-            ])
-        }
-
-        if self.source.whatsapp_backups_preset {
-            sources.extend([
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Backups"),
-                UnixPathBuf::from("/sdcard/Android/media/com.whatsapp/WhatsApp/Databases"),
-            ])
-        }
-
-        self.source.sources.extend(sources);
-    }
-}
-
-fn get_files_from_adb(adb_path: &PathBuf, root_path: &UnixPathBuf) -> Vec<UnixPathBuf> {
-    let mut file_list: Vec<UnixPathBuf> = Vec::new();
-
-    let mut cmd = process::Command::new(adb_path);
-    cmd.arg("shell");
-    cmd.arg("ls");
-    cmd.arg("-R");
-    cmd.arg(format!("\"{}\"", root_path.as_unix_str().to_str().unwrap()));
-
-    // println!("Running {:#?}", cmd);
-
-    let output = cmd.output().expect("Failed to execute the command").stdout;
-
-    let mut lines: Vec<String> = Vec::new();
-    match String::from_utf8(output) {
-        Ok(s) => lines.extend(s.lines().map(|x| x.trim().to_string())),
-        Err(err) => {
-            println!("Unable to read the output of `adb shell ls -R <path>`: {:#?}", err);
-            return file_list;
-        }
-    }
-
-    lines.retain(|x| !x.is_empty());
-
-    if lines.len() == 1 {
-        file_list.push(UnixPathBuf::from(&lines[0]))
-    }
-
-    let mut current_folder_root: UnixPathBuf = UnixPathBuf::from(&root_path); // default, but should be changed right away
-    for line in lines.into_iter() {
-        if line.starts_with('/') {
-            current_folder_root = UnixPathBuf::from(&line[..line.len() - 1]);
-            if let Some(i) = file_list.iter().position(|x| x == &current_folder_root) {
-                file_list.remove(i);
-            }
-        } else {
-            let file_path = current_folder_root.join(line);
-            file_list.push(file_path);
-        }
-    }
-
-    file_list
-}
-
-struct SrcDestFiles {
-    src_files: Vec<UnixPathBuf>,
-    dest_files: Vec<BasePathBuf>,
-}
-
-impl SrcDestFiles {
-    fn new() -> Self {
-        Self {
-            src_files: vec![],
-            dest_files: vec![],
-        }
-    }
-
-    /// Moves all the elements of `other` into `self`, leaving `other` empty.
-    fn append(&mut self, other: &mut SrcDestFiles) {
-        self.src_files.append(&mut other.src_files);
-        self.dest_files.append(&mut other.dest_files);
-    }
-
-    fn is_empty(&self) -> bool {
-        self.src_files.is_empty()
-    }
-
-    fn len(&self) -> usize {
-        self.src_files.len()
-    }
-}
-
-impl IntoIterator for SrcDestFiles {
-    type Item = (UnixPathBuf, BasePathBuf);
-    type IntoIter = SrcDestFilesIntoIterator;
-
-    fn into_iter(self) -> Self::IntoIter {
-        SrcDestFilesIntoIterator { files: self, index: 0 }
-    }
-}
-
-struct SrcDestFilesIntoIterator {
-    files: SrcDestFiles,
-    index: usize,
-}
-
-impl Iterator for SrcDestFilesIntoIterator {
-    type Item = (UnixPathBuf, BasePathBuf);
-
-    fn next(&mut self) -> Option<(UnixPathBuf, BasePathBuf)> {
-        let src_path = match self.files.src_files.get(self.index) {
-            Some(path) => path.clone(),
-            None => return None,
-        };
-        let dest_path = match self.files.dest_files.get(self.index) {
-            Some(path) => path.clone(),
-            None => return None,
-        };
-        self.index += 1;
-        Some((src_path, dest_path))
-    }
-}
-
-fn get_files_to_skip(skip: &Option<Vec<PathBuf>>) -> HashSet<String> {
-    let mut hs: HashSet<String> = HashSet::new();
-    if let Some(skip_inside) = skip {
-        for path in skip_inside {
-            for line in read_to_string(path).unwrap_or_default().lines().map(String::from) {
-                hs.insert(line);
-            }
-        }
-    }
-    hs
-}
-
-fn connected_to_adb_server(adb_path: &PathBuf, retries: Option<usize>) -> bool {
-    let retries = retries.unwrap_or(1);
-
-    let output = match process::Command::new(adb_path).arg("devices").stdout(process::Stdio::piped()).output() {
-        Ok(output) => output,
-        Err(_) => {
-            println!(
-                "Unable to check if adb is connected. \nADB path: \"{}\"",
-                adb_path.as_path().to_str().unwrap()
-            );
-            exit(1);
-        }
-    };
-
-    let out_vec = output.stdout.to_vec();
-    let out_string = String::from_utf8(out_vec).unwrap();
-
-    // `adb devices` outputs the devices attached to the adb server after `List of devices attached`
-    // If that line is the last line it means that no device is attached
-    if !out_string.trim_end().ends_with("List of devices attached") {
-        true
-    } else if retries > 0 {
-        connected_to_adb_server(adb_path, Some(retries - 1))
-    } else {
-        false
-    }
-}
-
-fn get_adb_path() -> Result<PathBuf> {
-    let adb_name = if cfg!(windows) {
-        "adb.exe"
-    } else if cfg!(unix) {
-        "adb"
-    } else {
-        return Err(anyhow!("OS is not supported"));
-    };
-
-    let adb_path = env::current_exe()
-        .context("Failed to get path of the adbpuller executable")?
-        .parent()
-        .context("Unable to get the parent folder of the adbpuller executable")?
-        .join(adb_name);
-
-    if adb_path.exists() {
-        Ok(adb_path)
-    } else {
-        which("adb").context("Unable to find adb drivers. Download and add them to $PATH")
-    }
-}
-
-fn build_file_list(adb_path: &PathBuf, args: &Cli) -> SrcDestFiles {
-    let files_to_skip = get_files_to_skip(&args.skip);
-    let mut files = SrcDestFiles::new();
-
-    for root_src in args.source.sources.iter() {
-        let mut file_list = get_files_from_adb(adb_path, root_src);
-        println!("{:7} files found in {:?}", file_list.len(), &root_src);
-        file_list.retain(|x| !files_to_skip.contains(x.to_str().unwrap()));
-
-        let mut temp_files = build_destination_files(&file_list, args.dest.as_path(), root_src, args.force);
-        println!("{:7} to copy", temp_files.len());
-
-        files.append(&mut temp_files)
-    }
-    files
-}
-
-fn build_destination_files(file_list: &[UnixPathBuf], root_dest: &Path, root_src: &UnixPathBuf, force: bool) -> SrcDestFiles {
-    let mut files = SrcDestFiles::new();
-
-    for file in file_list.iter() {
-        let file_rel_to_src: &UnixPath = match file.strip_prefix(root_src.parent().unwrap()) {
-            Ok(path) => path,
-            Err(_) => {
-                println!(
-                    "Unable to strip the prefix {:?} from {:?} when tying to find its corresponding destination",
-                    &root_src, &file
-                );
-                continue;
-            }
-        };
-
-        let dest = root_dest.join(file_rel_to_src.as_unix_str().to_str().unwrap());
-
-        if dest.exists() && !force {
-            continue;
-        }
-
-        files.src_files.push(file.to_owned());
-        files.dest_files.push(BasePathBuf::new(dest).unwrap());
-    }
-
-    files
-}
-
-fn main() {
-    let args: Cli = {
-        // Limit scope to remove mutability
-        let mut args = Cli::parse();
-        args.check_sources();
-        args
-    };
-
-    let adb_path = match get_adb_path() {
-        Ok(path) => {
-            println!("Using adb from: {path:?}");
-            path
-        }
-        Err(err) => {
-            eprintln!("{}", err);
-            exit(1)
-        }
-    };
-
-    println!("Checking if a device is attached to adb server..");
-    if !connected_to_adb_server(&adb_path, None) {
-        println!("No device found. Try executing \"{} devices\"", adb_path.as_path().to_str().unwrap());
-        exit(1);
-    }
-
-    println!("Building file list, it may take some time...");
-
-    let files = build_file_list(&adb_path, &args);
-
-    if args.source.sources.len() > 1 {
-        println!("\n{} total files to copy", files.dest_files.len());
-    }
-
-    // Print files to copy if --dry-run
-    if args.dry_run && !files.is_empty() {
-        let mut user_input = String::new();
-
-        while user_input.trim().to_lowercase() != "y" && user_input.trim().to_lowercase() != "n" {
-            print!("Do you want to print the files and their destinations? [y/N]: ");
-            let _ = std::io::stdout().flush();
-            user_input.clear();
-            let _ = std::io::stdin().read_line(&mut user_input);
-        }
-
-        if user_input.trim().to_lowercase() == "y" {
-            for (src_file, dest_file) in files.into_iter() {
-                println!(
-                    "{}  {}  {}",
-                    src_file.to_str().unwrap().green(),
-                    "->".cyan(),
-                    dest_file.as_path().to_str().unwrap()
-                );
-            }
-        }
-        exit(0)
-    }
-
-    if files.is_empty() {
-        println!("No files found to copy. Exiting..");
-        exit(0)
-    }
-
-    let mut files_done: Vec<UnixPathBuf> = Vec::new();
-    let mut files_failed: Vec<UnixPathBuf> = Vec::new();
-
-    let pb = ProgressBar::new(files.len() as u64);
-    pb.set_style(
-        ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {human_pos:>7}/{human_len:7} ({eta}) {wide_msg}")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
-    pb.enable_steady_tick(Duration::from_millis(50));
-
-    for (src_file, dest_file) in files.into_iter() {
-        pb.set_message(format!("{}", src_file.display()));
-        pb.inc(1);
-
-        if let Err(err) = std::fs::create_dir_all(dest_file.parent().unwrap().unwrap().as_path()) {
-            println!(
-                "Error in creating directory: \"{}\". Skipping file: {} \nErr:{err}",
-                dest_file.parent().unwrap().unwrap().as_path().display(),
-                src_file.display(),
-            );
-            files_failed.push(src_file);
-            continue;
-        };
-
-        let status = process::Command::new(&adb_path)
-            .arg("pull")
-            .arg("-a")
-            .arg(src_file.as_path().as_unix_str().to_str().unwrap())
-            .arg(dest_file.as_path().to_str().unwrap())
-            .stdout(process::Stdio::null())
-            .status()
-            .expect("Failed to start process to pull files using adb");
-
-        if status.success() {
-            files_done.push(src_file)
-        } else {
-            files_failed.push(src_file)
-        }
-    }
-
-    pb.finish();
-
-    let success_path = PathBuf::from("./files_done.txt");
-    let failed_path = PathBuf::from("./files_failed.txt");
-    println!(
-        "Done! Successfully copied {} files. Files written to {:?}",
-        files_done.len(),
-        success_path
-    );
-
-    if !files_failed.is_empty() {
-        println!("Failed to copy {} files. Failed files written to {:?}", files_failed.len(), failed_path);
-    }
-
-    let mut file = OpenOptions::new().append(true).create(true).open(success_path.as_path()).unwrap();
-
-    for path in files_done {
-        if let Err(e) = writeln!(file, "{}", path.as_path().to_str().unwrap()) {
-            eprintln!("Couldn't write to file: {}", e);
-        }
-    }
-
-    if !files_failed.is_empty() {
-        let mut file = OpenOptions::new().append(true).create(true).open(failed_path.as_path()).unwrap();
-
-        for path in files_failed {
-            if let Err(e) = writeln!(file, "{}", path.as_path().to_str().unwrap()) {
-                eprintln!("Couldn't write to file: {}", e);
-            }
-        }
-    }
-}
+mod adb;
+mod browse;
+mod capture_date;
+mod checksum;
+mod dedupe;
+mod disk_space;
+mod events;
+mod interrupt;
+mod listing;
+mod logging;
+mod manifest;
+mod pull;
+mod report;
+mod sanitize;
+mod template;
+
+use anyhow::{anyhow, Context, Result};
+use indicatif::{HumanBytes, HumanCount, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, read_to_string, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use std::env;
+use unix_path::{Path as UnixPath, PathBuf as UnixPathBuf};
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use colored::Colorize;
+use console::Term;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use normpath::BasePathBuf;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use adb::{connected_to_adb_server, get_adb_path, get_devices, print_devices, Adb, ConnectionStatus, PullFailure};
+use browse::browse;
+use capture_date::{capture_date, date_folder, UNKNOWN_DATE_DIR};
+use checksum::{device_hashes, ChecksumFile, HashAlgorithm};
+use dedupe::{DedupeIndex, DEDUPE_HASH};
+use disk_space::available_space;
+use events::{Event, FileStatus};
+use logging::{debug, format_timestamp, log, open_log_file, quote_words, set_verbosity, verbosity};
+use manifest::{Manifest, RunInfo, Status};
+use pull::{move_into_place, plan_pulls, remove_partial_files, restore_mtime, with_attempts, PullOutcome, PullResult, PullUnit, Pulls, RateWindow};
+use report::{DeviceCleanup, PathListWriter, ReportFiles};
+use sanitize::{device_folder, parse_substitute, sanitize_name, NameSanitizer};
+use template::{civil_date, DestTemplate};
+use listing::{
+    canonicalize, expand_glob, find_missing, get_dirs_from_adb, get_file_stats, get_files_from_adb, get_source_kind, is_glob, is_older_than, load_listings, save_listings, FileStat,
+    Listing, ListingOptions, SourceKind,
+};
+
+#[derive(Args, Debug)]
+#[group(required = true, multiple = true)]
+struct Sources {
+    /// The folder(s) or item(s) to copy, wildcards are expanded on the device. Use - to read them from stdin, one per line.
+    /// SOURCE=FOLDER copies the source into FOLDER of the destination instead, write \= for an = in the path
+    #[arg(short, long, num_args = 0..,)]
+    sources: Vec<UnixPathBuf>,
+
+    /// The folder inside the destination given to each source with SOURCE=FOLDER
+    #[arg(skip)]
+    dests: Vec<(UnixPathBuf, PathBuf)>,
+
+    /// Add the folders written in FILE, one per line, to the sources
+    #[arg(long = "sources-file", value_name = "FILE")]
+    sources_files: Vec<PathBuf>,
+
+    /// Add /sdcard/DCIM and /sdcard/Pictures to the sources
+    #[arg(short = 'm', long = "copy-media")]
+    media_preset: bool,
+
+    /// Add Whatsapp Audio, Images, Video and Voice Notes to the sources
+    #[arg(short = 'w', long = "copy-whatsapp")]
+    whatsapp_preset: bool,
+
+    /// Add Whatsapp Backup and Databases folders to the sources
+    #[arg(short = 'b', long = "copy-whatsapp-backups")]
+    whatsapp_backups_preset: bool,
+
+    /// Use the listing saved with --save-listing instead of listing the files on the device
+    #[arg(long, value_name = "FILE")]
+    cached_listing: Option<PathBuf>,
+
+    /// Copy the device files written in FILE, one per line, such as the one written by --list-only.
+    /// Sources given with --sources are only used to choose the destination of the files
+    #[arg(long, value_name = "FILE", conflicts_with = "cached_listing")]
+    from_list: Option<PathBuf>,
+
+    /// Print the globs of the files excluded by default and exit
+    #[arg(long, action = ArgAction::SetTrue, exclusive = true)]
+    show_default_excludes: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// List the devices attached to the adb server
+    Devices,
+
+    /// Pair with a device using Android 11+ wireless debugging
+    Pair {
+        /// The address shown in "Pair device with pairing code" on the phone
+        #[arg(value_name = "HOST:PORT")]
+        endpoint: String,
+
+        /// The 6-digit pairing code shown on the phone, asked interactively if not given
+        #[arg(long)]
+        code: Option<String>,
+
+        /// Connect to the device once paired, using the address shown under "Wireless debugging"
+        #[arg(long, value_name = "HOST:PORT")]
+        connect: Option<String>,
+    },
+
+    /// Pick the folders to copy by navigating the device, then copy them
+    Browse {
+        /// The folder to start from
+        #[arg(long, default_value = "/sdcard")]
+        start: UnixPathBuf,
+
+        /// Print the selected paths, one per line, instead of copying them. They can be saved and used with --sources-file
+        #[arg(long, action = ArgAction::SetTrue)]
+        print_selection: bool,
+    },
+}
+
+/// Set by --quiet, silences [`info!`]
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Set by --no-progress
+static NO_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Set by --yes, answers yes to every question
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Set by --progress-json and --dry-run, whose output on stdout is meant for other programs
+static MESSAGES_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Like `println!`, but on stderr with --progress-json and --dry-run, so that stdout carries only the events or
+/// the files
+macro_rules! say {
+    () => {
+        $crate::say!("")
+    };
+    ($($arg:tt)*) => {
+        if $crate::MESSAGES_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!("{}", $crate::for_stream(format!($($arg)*), true));
+        } else {
+            println!("{}", $crate::for_stream(format!($($arg)*), false));
+        }
+    };
+}
+
+/// Like `eprintln!`, for the errors and warnings, with their colors only if stderr takes them
+macro_rules! alert {
+    ($($arg:tt)*) => {
+        eprintln!("{}", $crate::for_stream(format!($($arg)*), true))
+    };
+}
+
+/// `message` without its colors if they are disabled for stderr, or for stdout when `stderr` is false. `colored`
+/// has one setting for both, so it colors whenever either of them takes colors, e.g. `2>run.log` from a terminal
+pub fn for_stream(message: String, stderr: bool) -> String {
+    let enabled = match stderr {
+        true => console::colors_enabled_stderr(),
+        false => console::colors_enabled(),
+    };
+    match enabled {
+        true => message,
+        false => console::strip_ansi_codes(&message).into_owned(),
+    }
+}
+
+/// Like [`say!`], for the messages which aren't warnings or errors, so that --quiet can silence them
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            $crate::say!($($arg)*);
+        }
+    };
+}
+
+// So that the modules can print through them too
+pub(crate) use {alert, info, say};
+
+/// How the run ended, each with its own exit code so that scripts can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Every file was copied, or there was nothing to copy
+    Success,
+    /// The run completed but some files couldn't be copied
+    PartialFailure,
+    /// Nothing was copied because of an error in the setup, e.g. no adb, no device or invalid
+    /// arguments. Also used by clap for invalid arguments
+    Fatal,
+    /// The copy was stopped with Ctrl-C
+    Interrupted,
+    /// The copy was stopped midway because the device was disconnected and didn't come back
+    DeviceLost,
+}
+
+impl ExitStatus {
+    pub fn code(self) -> i32 {
+        match self {
+            ExitStatus::Success => 0,
+            ExitStatus::PartialFailure => 1,
+            ExitStatus::Fatal => 2,
+            ExitStatus::Interrupted => 3,
+            ExitStatus::DeviceLost => 4,
+        }
+    }
+}
+
+/// Exits with the code of `status`. Only [`main`] does, with what the run returned, and the second Ctrl-C
+/// which can't wait for the run to end
+pub fn exit_with(status: ExitStatus) -> ! {
+    exit(status.code())
+}
+
+/// Pull files from android using ADB drivers
+#[derive(Parser, Debug)]
+#[command(version, about)]
+#[command(long_about = "Pull files from android using ADB drivers
+
+Example:
+    ./adb_puller.exe -s /sdcard/DCIM")]
+#[command(after_long_help = "Exit codes:
+    0  Every file was copied, or there was nothing to copy
+    1  The run completed but some files couldn't be copied
+    2  Fatal error before copying, e.g. adb or the device not found or invalid arguments
+    3  Interrupted with Ctrl-C
+    4  The device was disconnected during the copy and didn't come back
+
+Events printed with --progress-json, one JSON object per line. Fields are only ever added:
+    {\"event\":\"listing\",\"source\":PATH,\"count\":N,\"to_copy\":N}
+        A source was listed: the files found in it and those which will be copied
+    {\"event\":\"file_start\",\"source_path\":PATH,\"dest_path\":PATH,\"size_bytes\":N|null,\"index\":N,\"total\":N}
+        A file is about to be pulled, index counts from 0. With --jobs the next files can start before it's done
+    {\"event\":\"file_done\",\"source_path\":PATH,\"dest_path\":PATH,\"bytes\":N,\"status\":\"ok\"|\"failed\",\"error\":TEXT|null}
+        A file was pulled, bytes is the size of the copy
+    {\"event\":\"summary\",\"copied\":N,\"failed\":N,\"skipped\":N,\"bytes\":N,\"elapsed_secs\":SECONDS}
+        The run is over, skipped counts the files already in the destination")]
+#[command(subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    source: Sources,
+
+    /// The folder in which to copy the files
+    #[arg(short, long, default_value = ".")]
+    dest: PathBuf,
+
+    /// Create the destination folder, with its parents, when it doesn't exist
+    #[arg(long, action = ArgAction::SetTrue)]
+    create_dest: bool,
+
+    /// Copy the folders of a preset into FOLDER of the destination, as PRESET=FOLDER where PRESET is media, whatsapp or
+    /// whatsapp-backups. Can be repeated
+    #[arg(long, value_name = "PRESET=FOLDER", value_parser = parse_preset_dest)]
+    preset_dest: Vec<(Preset, PathBuf)>,
+
+    /// Copy the files into a folder of the destination named after the model and serial of the device, such as
+    /// DEST/Pixel_7_ABC123. The done and failed files go in it too, as do the --done-file, --failed-file, --checksum-file
+    /// and manifests given as relative paths
+    #[arg(long, action = ArgAction::SetTrue)]
+    dest_per_device: bool,
+
+    /// Skip files written in a file
+    #[arg(long, value_parser, num_args = 0..)]
+    skip: Option<Vec<PathBuf>>,
+
+    /// Skip the files copied by the previous run, listed in the done file.
+    /// With --force the remaining files are still overwritten
+    #[arg(long, action = ArgAction::SetTrue)]
+    resume: bool,
+
+    /// Where to append the copied files [default: DEST/files_done.txt]
+    #[arg(long, value_name = "PATH")]
+    done_file: Option<PathBuf>,
+
+    /// Where to append the files which couldn't be copied, each followed by a tab and the reason, next to the folders
+    /// which couldn't be listed in dirs_unreadable.txt [default: DEST/files_failed.txt]
+    #[arg(long, value_name = "PATH")]
+    failed_file: Option<PathBuf>,
+
+    /// Don't write the done and failed files, nor the folders which couldn't be listed
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["done_file", "failed_file"])]
+    no_report_files: bool,
+
+    /// Append a timestamped log of the run to PATH: the arguments, the files found in each source, every file copied or
+    /// not, with the full error printed by adb, and the summary
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Write to FILE a JSON manifest of the run, listing every file considered together with what happened to it
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Write to FILE the same manifest as --manifest, as CSV
+    #[arg(long, value_name = "FILE")]
+    manifest_csv: Option<PathBuf>,
+
+    /// Skip the file at DEVICE_PATH, can be given more than once
+    #[arg(long, value_name = "DEVICE_PATH")]
+    skip_path: Vec<UnixPathBuf>,
+
+    /// Copy only the files whose device path matches at least one of the regexes
+    #[arg(long, value_name = "REGEX", num_args = 1..)]
+    include: Vec<String>,
+
+    /// Don't copy the files whose device path matches any of the regexes
+    #[arg(long, value_name = "REGEX", num_args = 1..)]
+    exclude: Vec<String>,
+
+    /// Copy only the files whose device path matches at least one of the globs, e.g. "**/*.jpg"
+    #[arg(long, value_name = "GLOB", num_args = 1..)]
+    include_glob: Vec<String>,
+
+    /// Don't copy the files whose device path matches any of the globs, e.g. "WhatsApp Images/Sent/**"
+    #[arg(long, value_name = "GLOB", num_args = 1..)]
+    exclude_glob: Vec<String>,
+
+    /// Copy only the files with one of the extensions, e.g. jpg,png,mp4
+    #[arg(long, value_name = "EXT", value_delimiter = ',', num_args = 1..)]
+    ext: Vec<String>,
+
+    /// Copy only the files of the given types, together with those given with --ext
+    #[arg(long = "type", value_name = "TYPE", value_enum, value_delimiter = ',', num_args = 1..)]
+    media_types: Vec<MediaType>,
+
+    /// Don't copy the files with any of the extensions, e.g. nomedia,tmp
+    #[arg(long, value_name = "EXT", value_delimiter = ',', num_args = 1..)]
+    exclude_ext: Vec<String>,
+
+    /// Copy only the files of at least SIZE, e.g. 10K. The units are K, M, G and T, powers of 1024
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Copy only the files of at most SIZE, e.g. 2G. The units are K, M, G and T, powers of 1024
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Copy at most N files, the first ones in the order they would be copied
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Copy files, in the order they would be copied, until the next one would take their total over SIZE, e.g. 5G.
+    /// The units are K, M, G and T, powers of 1024
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_total_size: Option<u64>,
+
+    /// The order the files are copied in: by device path, by size or by modification time, smallest and oldest first,
+    /// or as listed on the device. The files whose size or time is unknown are copied last
+    #[arg(long, value_name = "ORDER", value_enum, default_value_t = SortBy::Unsorted)]
+    sort: SortBy,
+
+    /// Copy the files in the reverse order of --sort, e.g. the newest first with --sort mtime
+    #[arg(long, action = ArgAction::SetTrue)]
+    reverse: bool,
+
+    /// Copy only the files modified after DATE, given as YYYY-MM-DD [HH:MM[:SS]] in UTC or as a time ago such as 30d, 12h or 2w
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    newer_than: Option<i64>,
+
+    /// Copy only the files modified before DATE, given as YYYY-MM-DD [HH:MM[:SS]] in UTC or as a time ago such as 30d, 12h or 2w
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    older_than: Option<i64>,
+
+    /// Exclude the files whose modification date can't be read when filtering by date, instead of copying them
+    #[arg(long, action = ArgAction::SetTrue)]
+    strict_dates: bool,
+
+    /// Don't copy hidden files and the files inside hidden folders, or only the latter with --exclude-hidden=dirs
+    #[arg(long, value_name = "WHICH", value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "all")]
+    exclude_hidden: Option<HiddenMode>,
+
+    /// Don't copy the folders containing a .nomedia file, which apps use to mark their private media
+    #[arg(long, action = ArgAction::SetTrue)]
+    respect_nomedia: bool,
+
+    /// Copy the thumbnails, trash and cache folders too, which are otherwise excluded. See --show-default-excludes
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_default_excludes: bool,
+
+    /// Match the include and exclude patterns ignoring case. A single regex can be made case-insensitive with (?i)
+    #[arg(short = 'i', long, action = ArgAction::SetTrue)]
+    ignore_case: bool,
+
+    /// Match the include and exclude patterns against the file name only instead of the full device path
+    #[arg(long, action = ArgAction::SetTrue)]
+    match_basename: bool,
+
+    /// The compiled include and exclude patterns, filled by check_filters
+    #[arg(skip)]
+    filters: Filters,
+
+    /// Copy the files inside symlinked folders too
+    #[arg(long, action = ArgAction::SetTrue)]
+    follow_symlinks: bool,
+
+    /// Descend at most N folders below each source, 1 means only the files directly inside it
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_depth: Option<u64>,
+
+    /// Copy only the files directly inside each source, ignoring its subfolders
+    #[arg(long, conflicts_with = "max_depth", action = ArgAction::SetTrue)]
+    no_recursive: bool,
+
+    /// Save the files found on the device, to be reused with --cached-listing
+    #[arg(long, value_name = "FILE", conflicts_with = "cached_listing")]
+    save_listing: Option<PathBuf>,
+
+    /// Abort if some folders can't be listed (e.g. because of Permission denied) instead of skipping them
+    #[arg(long, action = ArgAction::SetTrue)]
+    strict_listing: bool,
+
+    /// Skip the sources which don't exist on the device instead of aborting
+    #[arg(long, action = ArgAction::SetTrue)]
+    ignore_missing_sources: bool,
+
+    /// Write the files which would be copied to FILE, one per line, without copying them
+    #[arg(long, value_name = "FILE")]
+    list_only: Option<PathBuf>,
+
+    /// Print on stdout which files would be copied and where, without copying them or writing anything. The other
+    /// messages are printed on stderr
+    #[arg(short = 'n', long, action = ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// With --dry-run, print each file and its destination on a line, separated by a tab. Tabs, newlines and
+    /// backslashes in them are escaped as \t, \n and \\
+    #[arg(long, action = ArgAction::SetTrue, requires = "dry_run", conflicts_with = "print0")]
+    porcelain: bool,
+
+    /// With --dry-run, print each file and its destination followed by a NUL character, without escaping them
+    #[arg(long, action = ArgAction::SetTrue, requires = "dry_run")]
+    print0: bool,
+
+    /// With --dry-run, also print the size and modification time of each file, then the total size of each folder of
+    /// the device and of all the files. With --porcelain the size is in bytes and the lines start with their kind,
+    /// after a header line
+    #[arg(short, long, action = ArgAction::SetTrue, requires = "dry_run", conflicts_with = "print0")]
+    long: bool,
+
+    /// Print how many files would be copied from each source and their size, without copying them or writing anything.
+    /// The sources whose files have an unknown size are measured with du on the device, counting all their files
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["dry_run", "list_only", "move_files"])]
+    summary_only: bool,
+
+    /// Print only warnings, errors and a one-line summary on stderr, without progress bars or questions
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    quiet: bool,
+
+    /// Answer yes to the questions: the confirmation before copying and deleting the files with --move
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    yes: bool,
+
+    /// Don't show progress bars, print the progress every --progress-interval seconds instead, as done when not in a terminal
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_progress: bool,
+
+    /// Print the progress as JSON events on stdout, one per line, instead of the progress bar. The
+    /// other messages are printed on stderr. The events are described in --help
+    #[arg(long, action = ArgAction::SetTrue)]
+    progress_json: bool,
+
+    /// Seconds between the progress lines printed when the progress bar isn't shown
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    progress_interval: u64,
+
+    /// When to color the output. With auto only when printing to a terminal and NO_COLOR isn't set
+    #[arg(long, value_name = "WHEN", value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Print every adb command before running it. Given twice, also print the start of each listing and
+    /// the exit code of each pull
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Overwrite files already present in the destination folder, the same as --on-conflict overwrite
+    #[arg(short, long = "force", action = ArgAction::SetTrue, conflicts_with = "on_conflict")]
+    force: bool,
+
+    /// What to do with the files already in the destination which are copied again
+    #[arg(long, value_name = "ACTION", value_enum, default_value_t = OnConflict::Skip)]
+    on_conflict: OnConflict,
+
+    /// What to do when files of different sources would be copied to the same destination: abort before copying,
+    /// skip all but the first one, or give the others the first free name like --on-conflict rename. Defaults to
+    /// rename with --flatten, to skip otherwise
+    #[arg(long, value_name = "ACTION", value_enum)]
+    on_collision: Option<OnCollision>,
+
+    /// Copy all the files directly inside the destination folder, without the folders of the device. The files
+    /// with the same name are given the first free one, like --on-conflict rename
+    #[arg(long, action = ArgAction::SetTrue)]
+    flatten: bool,
+
+    /// Where to copy each file inside the destination folder, e.g. `{device_model}/{date}/{source_name}/{rel_path}`.
+    /// The placeholders are {device_model} and {device_serial}, {date} of the copy as YYYY-MM-DD in UTC,
+    /// {source_name} the name of the source folder, {rel_path} the path of the file in it and {file_name} its
+    /// name, {year} and {month} of its modification time. It has to contain {rel_path} or {file_name}
+    #[arg(long, value_name = "TEMPLATE", value_parser = DestTemplate::parse, conflicts_with = "flatten")]
+    dest_template: Option<DestTemplate>,
+
+    /// Copy the files to folders named after the year and month they were taken, such as 2024/07, instead of
+    /// the folders of the device. The files with the same name are given the first free one, like --on-conflict
+    /// rename, and those without a date are copied to unknown-date
+    #[arg(long, value_name = "DATE", value_enum, conflicts_with_all = ["flatten", "dest_template"])]
+    organize_by_date: Option<OrganizeBy>,
+
+    /// Copy the files to their device path without its first N folders, e.g. 4 keeps WhatsApp/Media/WhatsApp Images
+    /// of /sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Images. By default the path is kept from
+    /// the name of the source
+    #[arg(long, value_name = "N", conflicts_with_all = ["flatten", "dest_template", "organize_by_date"])]
+    strip_components: Option<usize>,
+
+    /// Copy the files to their device path after PREFIX, e.g. /sdcard/Android/media/com.whatsapp keeps
+    /// WhatsApp/Media/WhatsApp Images. Every file has to be inside PREFIX
+    #[arg(long, value_name = "PREFIX", conflicts_with_all = ["flatten", "dest_template", "organize_by_date", "strip_components"])]
+    keep_after: Option<UnixPathBuf>,
+
+    /// Create in the destination the folders of the device which no copied file is in, such as the empty
+    /// ones, to mirror the whole tree
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["flatten", "dest_template", "organize_by_date"])]
+    keep_empty_dirs: bool,
+
+    /// Give the files names valid on Windows, replacing the invalid characters, removing the trailing dots and
+    /// spaces and changing the reserved names such as CON or NUL. Always done on Windows
+    #[arg(long, action = ArgAction::SetTrue)]
+    sanitize_names: bool,
+
+    /// The character which replaces those invalid in the file names
+    #[arg(long, value_name = "CHAR", value_parser = parse_substitute, default_value = "_")]
+    name_substitute: char,
+
+    /// Which files already in the destination are skipped: those which exist, those identical to the ones on
+    /// the device, or none. Defaults to exists with --on-conflict skip, to never otherwise
+    #[arg(long, value_name = "WHEN", value_enum, conflicts_with = "force")]
+    skip_if: Option<SkipIf>,
+
+    /// Copy again the files already in the destination whose size differs from the one on the device, e.g.
+    /// cut short by a crash
+    #[arg(long, action = ArgAction::SetTrue)]
+    verify_existing: bool,
+
+    /// Copy again the files already in the destination which are older than on the device, leaving the others
+    #[arg(short = 'u', long, action = ArgAction::SetTrue, conflicts_with = "force")]
+    update: bool,
+
+    /// Skip the files already anywhere under DIR, such as a folder of photos copied before and rearranged.
+    /// They are compared by size and hash, or by size and name if the device lacks md5sum. The hashes of the
+    /// files in DIR are kept in DIR/.adbpuller-index.tsv for the next runs
+    #[arg(long, value_name = "DIR")]
+    dedupe_against: Option<PathBuf>,
+
+    /// Don't compare the size of each pulled file with the one on the device, which may need asking the
+    /// device for it
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_verify_size: bool,
+
+    /// Also check each pulled file against the device. `checksum` compares its hash, computed on the device
+    /// and here, recording it in the manifest
+    #[arg(long, value_name = "MODE")]
+    verify: Option<VerifyMode>,
+
+    /// The hash used by --verify checksum. Older devices may lack sha256sum
+    #[arg(long, value_name = "ALGORITHM", default_value = "sha256")]
+    hash: HashAlgorithm,
+
+    /// Write the SHA-256 of every pulled file to FILE, in the format of sha256sum with the paths relative to the
+    /// destination, so that `sha256sum -c FILE` run there checks them. The runs append to it
+    #[arg(long, value_name = "FILE")]
+    checksum_file: Option<PathBuf>,
+
+    /// How many threads hash the pulled files for --checksum-file, while the next ones are pulled
+    #[arg(long, value_name = "N", default_value_t = 1, requires = "checksum_file", value_parser = clap::value_parser!(u64).range(1..))]
+    checksum_jobs: u64,
+
+    /// Delete each file from the device once pulled and verified, recording it in files_deleted.txt. Asks for
+    /// confirmation first, unless --yes is given
+    #[arg(long = "move", action = ArgAction::SetTrue, conflicts_with = "dry_run")]
+    move_files: bool,
+
+    /// With --move, remove the folders of the sources left empty by it
+    #[arg(long, action = ArgAction::SetTrue, requires = "move_files")]
+    prune_empty_dirs: bool,
+
+    /// Copy even if the destination hasn't enough free space for the files, only warning about it
+    #[arg(long, action = ArgAction::SetTrue)]
+    ignore_disk_space: bool,
+
+    /// How much free space is left in the destination, on top of the size of the files, as a percentage of it
+    #[arg(long, value_name = "PERCENT", default_value_t = 5)]
+    disk_space_margin: u64,
+
+    /// Don't keep the modification time and permissions the files have on the device. They are copied by adb pull -a
+    /// and the tar stream, and the modification time is set again after each pull, with the fraction of the second,
+    /// as some devices and adb versions lose it. The files get the time they are copied instead, and --skip-if
+    /// identical compares only the size. Use it when the destination can't take them, e.g. some FAT or exFAT drives
+    /// where setting the permissions can fail, and with it the whole pull
+    #[arg(long = "no-metadata", action = ArgAction::SetTrue)]
+    no_metadata: bool,
+
+    /// Path to the adb executable, or to the folder containing it. Can also be set with the ADB environment variable
+    #[arg(long, global = true)]
+    adb_path: Option<PathBuf>,
+
+    /// Serial of the device to pull from, needed when more than one device is attached [env: ANDROID_SERIAL]
+    #[arg(long)]
+    serial: Option<String>,
+
+    /// Name of the adb server host [env: ANDROID_ADB_SERVER_ADDRESS] [default: localhost]
+    #[arg(short = 'H', long)]
+    adb_host: Option<String>,
+
+    /// Port of the adb server [env: ANDROID_ADB_SERVER_PORT] [default: 5037]
+    #[arg(short = 'P', long)]
+    adb_port: Option<u16>,
+
+    /// Connect to a device over the network and pull from it
+    #[arg(long, value_name = "HOST:PORT", conflicts_with = "serial")]
+    connect: Option<String>,
+
+    /// Compress the transfer, with adb pull -z on platform-tools 34 or later and with zstd or gzip on the
+    /// device with --tar-stream. Files are copied uncompressed when neither is available
+    #[arg(long, action = ArgAction::SetTrue)]
+    compress: bool,
+
+    /// Stream the files with tar on the device, unpacking them here, which is much faster than pulling
+    /// them one by one when they are many and small. Falls back to adb pull without tar on the device
+    #[arg(long, action = ArgAction::SetTrue)]
+    tar_stream: bool,
+
+    /// How many files to pull at the same time. More jobs keep the connection busy when copying many small files
+    #[arg(short, long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    jobs: u64,
+
+    /// Seconds to wait for the device to come back when it drops during the transfer
+    #[arg(long, value_name = "SECONDS", default_value_t = 120)]
+    reconnect_timeout: u64,
+
+    /// Seconds after which a pull still running is killed and its file recorded as failed
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    file_timeout: Option<u64>,
+
+    /// Seconds added to --file-timeout for each GiB of the file, so that large files get more time
+    #[arg(long, value_name = "SECONDS", requires = "file_timeout")]
+    file_timeout_per_gb: Option<u64>,
+
+    /// How many times to pull again a file which failed for a reason that may go away, such as an I/O error,
+    /// waiting longer before each attempt
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Pull again once at the end of the run the files that failed, only reporting as failed those failing twice
+    #[arg(long, action = ArgAction::SetTrue)]
+    retry_failed_pass: bool,
+
+    /// Disconnect from the device given with --connect once done
+    #[arg(long, requires = "connect", action = ArgAction::SetTrue)]
+    disconnect_after: bool,
+
+    /// Wait up to SECONDS for a device to be attached before giving up
+    #[arg(long, value_name = "SECONDS")]
+    wait_for_device: Option<u64>,
+
+    /// How many times to look for a device before giving up
+    #[arg(long, default_value_t = 3)]
+    device_retries: usize,
+
+    /// Milliseconds to wait between the attempts to find a device
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 1000)]
+    retry_delay: u64,
+}
+
+impl Cli {
+    fn check_sources(&mut self) -> Result<()> {
+        let mut lines: Vec<String> = Vec::new();
+
+        // `--sources -` reads the sources from stdin, one per line
+        if self.source.sources.iter().any(|source| source.as_unix_str() == "-") {
+            let mut input = String::new();
+            io::stdin()
+                .read_to_string(&mut input)
+                .map_err(|err| anyhow!("Unable to read the sources from stdin: {err}"))?;
+
+            self.source.sources.retain(|source| source.as_unix_str() != "-");
+            lines.extend(read_source_lines(&input));
+        }
+
+        for sources_file in &self.source.sources_files {
+            let content = read_to_string(sources_file)
+                .map_err(|err| anyhow!("Unable to read the sources file {:?}: {err}", sources_file))?;
+            lines.extend(read_source_lines(&content));
+        }
+
+        // SOURCE=FOLDER gives the source its own folder in the destination. The lines are split before being
+        // unescaped, so that `\=` is still told apart
+        let given: Vec<(String, bool)> = std::mem::take(&mut self.source.sources)
+            .into_iter()
+            .map(|source| (source.to_str().unwrap().to_string(), false))
+            .chain(lines.into_iter().map(|line| (line, true)))
+            .collect();
+        for (source, escaped) in given {
+            let (mut source, folder) = split_source_dest(&source)?;
+            if escaped {
+                source = UnixPathBuf::from(unescape_path(source.to_str().unwrap()));
+            }
+            if let Some(folder) = folder {
+                self.source.dests.push((source.clone(), folder));
+            }
+            self.source.sources.push(source);
+        }
+
+        let presets = [
+            (Preset::Media, self.source.media_preset),
+            (Preset::Whatsapp, self.source.whatsapp_preset),
+            (Preset::WhatsappBackups, self.source.whatsapp_backups_preset),
+        ];
+        for (preset, _) in presets.iter().filter(|(_, enabled)| *enabled) {
+            let folder = self.preset_dest.iter().rev().find(|(other, _)| other == preset).map(|(_, folder)| folder);
+            for source in preset.sources() {
+                if let Some(folder) = folder {
+                    self.source.dests.push((source.clone(), folder.clone()));
+                }
+                self.source.sources.push(source);
+            }
+        }
+        if let Some((preset, _)) = self.preset_dest.iter().find(|(preset, _)| !presets.contains(&(*preset, true))) {
+            let name = preset.to_possible_value().unwrap();
+            return Err(anyhow!("--preset-dest maps {:?}, which isn't copied", name.get_name()));
+        }
+
+        // Collapse repeated and trailing slashes, so that equal paths compare as equal
+        for source in self.source.sources.iter_mut() {
+            *source = source.components().collect();
+        }
+        for (source, _) in self.source.dests.iter_mut() {
+            *source = source.components().collect();
+        }
+
+        let mut seen: HashSet<UnixPathBuf> = HashSet::new();
+        self.source.sources.retain(|source| seen.insert(source.clone()));
+
+        if self.source.sources.is_empty()
+            && self.source.cached_listing.is_none()
+            && self.source.from_list.is_none()
+            && self.command.is_none()
+        {
+            return Err(anyhow!("No sources given"));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the run only tells what it would copy, with --dry-run and --summary-only, so that nothing is written
+    fn writes_nothing(&self) -> bool {
+        self.dry_run || self.summary_only
+    }
+
+    /// Whether the size and modification time of the files are needed, as they take another adb call.
+    /// Besides the filters, the manifests and the progress bar use them whenever files are copied
+    fn needs_stats(&self) -> bool {
+        self.filters.needs_stats()
+            || self.list_only.is_none()
+            || self.max_total_size.is_some()
+            || matches!(self.sort, SortBy::Size | SortBy::Mtime)
+            || self.verify_existing
+            || self.update
+            || self.skip_if() == SkipIf::Identical
+            || self.dest_template.as_ref().is_some_and(DestTemplate::needs_mtime)
+            || self.organize_by_date == Some(OrganizeBy::Mtime)
+            || self.dedupe_against.is_some()
+    }
+
+    /// --on-conflict, or overwrite with --force
+    fn on_conflict(&self) -> OnConflict {
+        if self.force {
+            return OnConflict::Overwrite;
+        }
+        self.on_conflict
+    }
+
+    /// --skip-if, which by default skips the files already in the destination only with --on-conflict skip
+    fn skip_if(&self) -> SkipIf {
+        self.skip_if.unwrap_or(match self.on_conflict() {
+            OnConflict::Skip => SkipIf::Exists,
+            _ => SkipIf::Never,
+        })
+    }
+
+    /// The folder in which to copy the files of `root_src`: the one given to it with SOURCE=FOLDER or
+    /// --preset-dest, also when it was matched by a wildcard, otherwise --dest
+    fn source_dest(&self, root_src: &UnixPath) -> PathBuf {
+        let matches = |source: &UnixPathBuf| {
+            source == root_src
+                || (is_glob(source)
+                    && GlobBuilder::new(source.to_str().unwrap())
+                        .literal_separator(true)
+                        .build()
+                        .is_ok_and(|glob| glob.compile_matcher().is_match(root_src.to_str().unwrap())))
+        };
+        match self.source.dests.iter().find(|(source, _)| matches(source)) {
+            Some((_, folder)) => self.dest.join(folder),
+            None => self.dest.clone(),
+        }
+    }
+
+    /// --on-collision, which by default renames the files with the same name with --flatten and --organize-by-date
+    fn on_collision(&self) -> OnCollision {
+        self.on_collision.unwrap_or(match self.flatten || self.organize_by_date.is_some() {
+            true => OnCollision::Rename,
+            false => OnCollision::Skip,
+        })
+    }
+
+    /// The character replacing the invalid ones when the file names are sanitized, on Windows or with
+    /// --sanitize-names
+    fn name_substitute(&self) -> Option<char> {
+        (cfg!(windows) || self.sanitize_names).then_some(self.name_substitute)
+    }
+
+    /// Whether the files already in the destination are all copied again
+    fn overwrite(&self) -> bool {
+        self.skip_if() == SkipIf::Never
+    }
+
+    /// The file listing the copied files, None with --no-report-files
+    fn done_file(&self) -> Option<PathBuf> {
+        if self.no_report_files {
+            return None;
+        }
+        Some(self.done_file.clone().unwrap_or_else(|| self.dest.join("files_done.txt")))
+    }
+
+    /// The file listing the files which couldn't be copied, None with --no-report-files
+    fn failed_file(&self) -> Option<PathBuf> {
+        if self.no_report_files {
+            return None;
+        }
+        Some(self.failed_file.clone().unwrap_or_else(|| self.dest.join("files_failed.txt")))
+    }
+
+    /// The file listing the folders which couldn't be listed, next to the failed file, None with --no-report-files
+    fn unreadable_dirs_file(&self) -> Option<PathBuf> {
+        Some(self.failed_file()?.with_file_name("dirs_unreadable.txt"))
+    }
+
+    /// The file listing the files deleted from the device with --move, None without it or with --no-report-files
+    fn deleted_file(&self) -> Option<PathBuf> {
+        if !self.move_files || self.no_report_files {
+            return None;
+        }
+        Some(self.dest.join("files_deleted.txt"))
+    }
+
+    /// Compiles the include and exclude patterns, reporting all the invalid ones at once
+    fn check_filters(&mut self) -> Result<()> {
+        let mut errors: Vec<String> = Vec::new();
+        let ignore_case = self.ignore_case;
+        let mut compile_regexes = |flag: &str, patterns: &[String]| -> Vec<Regex> {
+            patterns
+                .iter()
+                .enumerate()
+                .filter_map(|(index, pattern)| match RegexBuilder::new(pattern).case_insensitive(ignore_case).build() {
+                    Ok(regex) => Some(regex),
+                    Err(err) => {
+                        errors.push(format!("Invalid {flag} pattern #{} {:?}:\n{err}", index + 1, pattern));
+                        None
+                    }
+                })
+                .collect()
+        };
+
+        self.filters.include = compile_regexes("--include", &self.include);
+        self.filters.exclude = compile_regexes("--exclude", &self.exclude);
+
+        let mut compile_globs = |flag: &str, patterns: &[String]| -> GlobSet {
+            let mut builder = GlobSetBuilder::new();
+            for (index, pattern) in patterns.iter().enumerate() {
+                // Globs not starting with / can match anywhere in the path
+                let anchored = if pattern.starts_with('/') { pattern.clone() } else { format!("**/{pattern}") };
+                match GlobBuilder::new(&anchored).literal_separator(true).case_insensitive(ignore_case).build() {
+                    Ok(glob) => {
+                        builder.add(glob);
+                    }
+                    Err(err) => errors.push(format!("Invalid {flag} pattern #{} {:?}: {}", index + 1, pattern, err.kind())),
+                }
+            }
+            builder.build().unwrap_or_default()
+        };
+
+        self.filters.include_globs = compile_globs("--include-glob", &self.include_glob);
+        self.filters.exclude_globs = compile_globs("--exclude-glob", &self.exclude_glob);
+
+        let normalize = |extensions: &[String]| -> HashSet<String> {
+            extensions.iter().map(|ext| ext.trim().trim_start_matches('.').to_lowercase()).filter(|ext| !ext.is_empty()).collect()
+        };
+        self.filters.min_size = self.min_size;
+        self.filters.max_size = self.max_size;
+        self.filters.newer_than = self.newer_than;
+        self.filters.older_than = self.older_than;
+        self.filters.strict_dates = self.strict_dates;
+        self.filters.exclude_hidden = self.exclude_hidden;
+
+        if !self.no_default_excludes {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in DEFAULT_EXCLUDES {
+                builder.add(GlobBuilder::new(pattern).literal_separator(true).build().unwrap());
+            }
+            self.filters.default_excludes = builder.build().unwrap();
+        }
+        self.filters.extensions = normalize(&self.ext);
+        for media_type in &self.media_types {
+            self.filters.extensions.extend(media_type.extensions().iter().map(|ext| ext.to_string()));
+        }
+        self.filters.excluded_extensions = normalize(&self.exclude_ext);
+
+        if !errors.is_empty() {
+            return Err(anyhow!("{}", errors.join("\n\n")));
+        }
+        Ok(())
+    }
+
+    /// Makes --dest absolute, expanding `~` and the environment variables, and checks that it's a folder that can be
+    /// written, creating it with --create-dest. Without this a bad destination fails every file, one at a time.
+    /// A dry run, --summary-only or --list-only writes nothing in it, so it may still be missing then
+    fn check_dest(&mut self) -> Result<()> {
+        if let Some(dest) = self.dest.to_str() {
+            let expanded = shellexpand::full(dest).map_err(|err| anyhow!("Unable to expand the destination {:?}: {err}", dest))?;
+            self.dest = PathBuf::from(expanded.as_ref());
+        }
+        if self.dest.is_relative() {
+            let current = env::current_dir().map_err(|err| anyhow!("Unable to get the current folder: {err}"))?;
+            self.dest = current.join(&self.dest);
+        }
+        self.dest = path_clean::clean(&self.dest);
+
+        match fs::metadata(&self.dest) {
+            Ok(metadata) if !metadata.is_dir() => return Err(anyhow!("The destination {:?} is a file, not a folder", self.dest)),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if self.writes_nothing() || self.list_only.is_some() {
+                    return Ok(());
+                }
+                if !self.create_dest {
+                    return Err(anyhow!("The destination {:?} does not exist. Use --create-dest to create it", self.dest));
+                }
+                fs::create_dir_all(&self.dest).map_err(|err| anyhow!("Unable to create the destination {:?}: {err}", self.dest))?;
+                info!("Created the destination {:?}", self.dest);
+            }
+            Err(err) => return Err(anyhow!("Unable to access the destination {:?}: {err}", self.dest)),
+        }
+        if self.writes_nothing() || self.list_only.is_some() {
+            return Ok(());
+        }
+
+        // Permissions alone don't tell, e.g. on a read-only mount or with ACLs
+        let probe = self.dest.join(format!(".adbpuller-write-test-{}", std::process::id()));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&probe)
+            .map_err(|err| anyhow!("The destination {:?} can't be written: {err}", self.dest))?;
+        let _ = fs::remove_file(&probe);
+        Ok(())
+    }
+}
+
+/// Drops the failure reason that follows the path in files_failed.txt. Tabs inside paths are
+/// escaped, so the first one can only be the separator
+fn strip_reason(line: &str) -> &str {
+    line.split('\t').next().unwrap_or(line)
+}
+
+/// The lines of a sources file, still escaped, ignoring blank lines and comments starting with `#`
+fn read_source_lines(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| strip_reason(line).to_string())
+}
+
+/// Reads one device path per line, ignoring blank lines and comments starting with `#`
+fn read_path_lines(content: &str) -> impl Iterator<Item = UnixPathBuf> + '_ {
+    read_source_lines(content).map(|line| UnixPathBuf::from(unescape_path(&line)))
+}
+
+/// A file to copy and where it goes
+#[derive(Debug, Clone)]
+struct FileEntry {
+    src: UnixPathBuf,
+    dest: BasePathBuf,
+    /// The source the file was found in
+    root_src: UnixPathBuf,
+}
+
+struct SrcDestFiles {
+    /// The files to copy
+    entries: Vec<FileEntry>,
+    /// Files not copied because their destination already exists
+    existing: Vec<(UnixPathBuf, BasePathBuf)>,
+    /// Files excluded by the filters or the skip list
+    filtered: Vec<UnixPathBuf>,
+    /// Files of --from-list which don't exist on the device
+    missing: Vec<UnixPathBuf>,
+    /// Size and modification time of the files, when they were fetched
+    stats: HashMap<UnixPathBuf, FileStat>,
+    /// Folders whose files are all to be copied, which can be pulled with a single adb pull
+    complete_dirs: HashSet<UnixPathBuf>,
+    /// Files already in the destination but with another size than on the device, copied again with --verify-existing
+    damaged: HashSet<UnixPathBuf>,
+    /// How many of the files already in the destination are as new as on the device, with --update
+    up_to_date: usize,
+    /// What is done with the destination of the files to copy which already exists, by --on-conflict
+    conflicts: HashMap<UnixPathBuf, OnConflict>,
+    /// Files not copied with --on-collision skip, with their destination and the file copied there instead
+    collisions: Vec<(UnixPathBuf, BasePathBuf, UnixPathBuf)>,
+    /// How many files were given another name with --on-collision rename
+    renamed_collisions: usize,
+    /// The destination the files whose names were sanitized would have had otherwise
+    sanitized: HashMap<UnixPathBuf, BasePathBuf>,
+    /// Files not copied with --dedupe-against, with the file already there
+    duplicates: Vec<(UnixPathBuf, PathBuf)>,
+    /// Whether the duplicates were found by size and name, as the device couldn't hash the files
+    duplicates_by_name: bool,
+    /// The folders of the device and their destination, to be created with --keep-empty-dirs if no file is copied in them
+    empty_dirs: Vec<(UnixPathBuf, PathBuf)>,
+    /// Files left out by --limit and --max-total-size
+    truncated: Vec<UnixPathBuf>,
+}
+
+impl SrcDestFiles {
+    fn new() -> Self {
+        Self {
+            entries: vec![],
+            existing: vec![],
+            filtered: vec![],
+            missing: vec![],
+            stats: HashMap::new(),
+            complete_dirs: HashSet::new(),
+            damaged: HashSet::new(),
+            up_to_date: 0,
+            conflicts: HashMap::new(),
+            collisions: vec![],
+            renamed_collisions: 0,
+            sanitized: HashMap::new(),
+            duplicates: vec![],
+            duplicates_by_name: false,
+            empty_dirs: vec![],
+            truncated: vec![],
+        }
+    }
+
+    /// Moves all the elements of `other` into `self`, leaving `other` empty.
+    fn append(&mut self, other: &mut SrcDestFiles) {
+        self.entries.append(&mut other.entries);
+        self.existing.append(&mut other.existing);
+        self.filtered.append(&mut other.filtered);
+        self.missing.append(&mut other.missing);
+        self.stats.extend(other.stats.drain());
+        self.complete_dirs.extend(other.complete_dirs.drain());
+        self.damaged.extend(other.damaged.drain());
+        self.up_to_date += std::mem::take(&mut other.up_to_date);
+        self.conflicts.extend(other.conflicts.drain());
+        self.collisions.append(&mut other.collisions);
+        self.renamed_collisions += std::mem::take(&mut other.renamed_collisions);
+        self.sanitized.extend(other.sanitized.drain());
+        self.duplicates.append(&mut other.duplicates);
+        self.duplicates_by_name |= other.duplicates_by_name;
+        self.empty_dirs.append(&mut other.empty_dirs);
+        self.truncated.append(&mut other.truncated);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Adds `src` to the files to copy, to `dest`
+    fn push(&mut self, src: UnixPathBuf, dest: BasePathBuf, root_src: UnixPathBuf) {
+        self.entries.push(FileEntry { src, dest, root_src });
+    }
+
+    /// The device paths of the files to copy
+    fn src_files(&self) -> impl Iterator<Item = &UnixPathBuf> {
+        self.entries.iter().map(|entry| &entry.src)
+    }
+
+    /// Forgets the complete folders holding `src_file`, which is left out of the copy. They can't be pulled whole
+    /// anymore, as it would be copied anyway
+    fn forget_complete_dirs_of(&mut self, src_file: &UnixPath) {
+        self.complete_dirs.retain(|dir| !src_file.starts_with(dir));
+    }
+}
+
+impl IntoIterator for SrcDestFiles {
+    type Item = FileEntry;
+    type IntoIter = std::vec::IntoIter<FileEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+/// What the --include and --exclude patterns are matched against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchTarget {
+    FullPath,
+    FileName,
+}
+
+impl fmt::Display for MatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchTarget::FullPath => write!(f, "the full path"),
+            MatchTarget::FileName => write!(f, "the file name"),
+        }
+    }
+}
+
+/// The include and exclude patterns, both as regexes and as globs
+#[derive(Debug, Clone, Default)]
+struct Filters {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    include_globs: GlobSet,
+    exclude_globs: GlobSet,
+    /// Lowercase extensions, without the leading dot
+    extensions: HashSet<String>,
+    excluded_extensions: HashSet<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// Seconds since the Unix epoch
+    newer_than: Option<i64>,
+    older_than: Option<i64>,
+    strict_dates: bool,
+    exclude_hidden: Option<HiddenMode>,
+    /// Compiled from DEFAULT_EXCLUDES, empty with --no-default-excludes
+    default_excludes: GlobSet,
+}
+
+impl Filters {
+    fn has_patterns(&self) -> bool {
+        !(self.include.is_empty() && self.exclude.is_empty() && self.include_globs.is_empty() && self.exclude_globs.is_empty())
+    }
+
+    fn has_date_filters(&self) -> bool {
+        self.newer_than.is_some() || self.older_than.is_some()
+    }
+
+    /// Whether the size or the modification time of the files is needed
+    fn needs_stats(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some() || self.has_date_filters()
+    }
+}
+
+/// The sets of folders added to the sources by --copy-media, --copy-whatsapp and --copy-whatsapp-backups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Preset {
+    Media,
+    Whatsapp,
+    WhatsappBackups,
+}
+
+impl Preset {
+    fn sources(&self) -> Vec<UnixPathBuf> {
+        let paths: &[&str] = match self {
+            Preset::Media => &["/sdcard/DCIM", "/sdcard/Pictures"],
+            Preset::Whatsapp => &[
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Audio",
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Images",
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Video",
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Voice Notes",
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Video Notes",
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Documents",
+            ],
+            Preset::WhatsappBackups => &[
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Backups",
+                "/sdcard/Android/media/com.whatsapp/WhatsApp/Databases",
+            ],
+        };
+        paths.iter().map(UnixPathBuf::from).collect()
+    }
+}
+
+/// Parses PRESET=FOLDER of --preset-dest
+fn parse_preset_dest(value: &str) -> Result<(Preset, PathBuf), String> {
+    let Some((preset, folder)) = value.split_once('=') else {
+        return Err(format!("expected PRESET=FOLDER, got {value:?}"));
+    };
+    let preset = Preset::from_str(preset, true)?;
+    Ok((preset, check_dest_folder(folder)?))
+}
+
+/// Splits SOURCE=FOLDER at the first `=` not written as `\=`, which is an `=` of the path
+fn split_source_dest(source: &str) -> Result<(UnixPathBuf, Option<PathBuf>)> {
+    let mut path = String::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'=') => {
+                path.push('=');
+                chars.next();
+            }
+            '=' => {
+                let folder = check_dest_folder(&chars.collect::<String>()).map_err(|err| anyhow!("Invalid source {source:?}: {err}"))?;
+                return Ok((UnixPathBuf::from(path), Some(folder)));
+            }
+            c => path.push(c),
+        }
+    }
+    Ok((UnixPathBuf::from(path), None))
+}
+
+/// The folder given to a source, which has to be inside the destination
+fn check_dest_folder(folder: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(folder);
+    let inside = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if folder.is_empty() || !inside {
+        return Err(format!("the folder {folder:?} has to be relative to the destination, without `..`"));
+    }
+    Ok(path)
+}
+
+/// Categories of files selected by their extension with --type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum MediaType {
+    Images,
+    Videos,
+    Audio,
+    Documents,
+}
+
+impl MediaType {
+    fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            MediaType::Images => &["jpg", "jpeg", "png", "gif", "heic", "webp", "dng"],
+            MediaType::Videos => &["mp4", "mkv", "3gp", "webm"],
+            MediaType::Audio => &["mp3", "ogg", "opus", "m4a", "wav", "flac"],
+            MediaType::Documents => &["pdf", "doc", "docx", "txt"],
+        }
+    }
+}
+
+/// Thumbnails, trash and cache files which are excluded unless --no-default-excludes is given
+const DEFAULT_EXCLUDES: &[&str] = &[
+    "**/.thumbnails/**",
+    "**/.trashed-*",
+    "**/.Trash/**",
+    "**/.globalTrash/**",
+    "**/[Cc]ache/**",
+    "**/.cache/**",
+];
+
+/// When the output is colored, set with --color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Enables or disables the colors of the messages and of the progress bars
+    fn apply(self) {
+        self.apply_to(io::stdout().is_terminal(), io::stderr().is_terminal());
+    }
+
+    /// Like [`ColorMode::apply`], given whether stdout and stderr are terminals
+    fn apply_to(self, stdout_terminal: bool, stderr_terminal: bool) {
+        let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        let (stdout, stderr) = match self {
+            ColorMode::Always => (true, true),
+            ColorMode::Never => (false, false),
+            ColorMode::Auto => (!no_color && stdout_terminal, !no_color && stderr_terminal),
+        };
+        // The messages are stripped of the colors of the stream they go to by `for_stream`
+        colored::control::set_override(stdout || stderr);
+        console::set_colors_enabled(stdout);
+        console::set_colors_enabled_stderr(stderr);
+    }
+}
+
+/// What is done with a file already in the destination which is copied again, set with --on-conflict. It's
+/// recorded in the manifests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    /// Leave the file alone, unless --skip-if, --update or --verify-existing tell to copy it again
+    Skip,
+    /// Replace it
+    Overwrite,
+    /// Copy the new one next to it, adding a number to its name, e.g. `IMG_0001 (1).jpg`
+    Rename,
+    /// Move it to `<name>.bak.N` before replacing it
+    Backup,
+}
+
+/// Where the date of the files comes from with --organize-by-date
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OrganizeBy {
+    /// The EXIF date of the photos and the creation time of the videos, read once they are copied
+    Exif,
+    /// The modification time on the device
+    Mtime,
+}
+
+/// The order the files are copied in, set with --sort
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortBy {
+    /// By device path
+    Name,
+    /// Smallest first
+    Size,
+    /// Oldest first
+    Mtime,
+    /// As listed on the device
+    #[value(name = "none")]
+    Unsorted,
+}
+
+/// What is done when files of different sources have the same destination, set with --on-collision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OnCollision {
+    /// Exit before copying anything
+    Abort,
+    /// Copy only the first one
+    Skip,
+    /// Copy the others next to it, adding a number to their name
+    Rename,
+}
+
+/// Which files already in the destination are skipped, set with --skip-if
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SkipIf {
+    /// Those with the same size and modification time as on the device, the others are copied again.
+    /// With --no-metadata only the size is compared
+    Identical,
+    /// All of them
+    Exists,
+    /// None of them, copying every file again
+    Never,
+}
+
+/// The extra check of the pulled files, set with --verify
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum VerifyMode {
+    /// Compare the hash of the file on the device and here
+    Checksum,
+}
+
+/// Which hidden entries --exclude-hidden applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HiddenMode {
+    /// Both hidden files and hidden folders
+    All,
+    /// Only hidden folders
+    Dirs,
+}
+
+/// Whether `file` is hidden or inside a hidden folder, according to `mode`
+fn is_hidden(file: &UnixPath, mode: HiddenMode) -> bool {
+    let checked = match mode {
+        HiddenMode::All => Some(file),
+        HiddenMode::Dirs => file.parent(),
+    };
+    checked.is_some_and(|path| {
+        path.components()
+            .any(|component| component.as_unix_str().to_str().is_some_and(|component| component.starts_with('.')))
+    })
+}
+
+/// The lowercase extension of the file, `.nomedia` having extension `nomedia`
+fn file_extension(file: &UnixPath) -> Option<String> {
+    let name = file.file_name()?.to_str()?;
+    name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase())
+}
+
+/// Keeps the files matching at least one of the include patterns, or all of them if there are none,
+/// and having one of the extensions, if any are given. Then removes those matching any of the
+/// exclude patterns or excluded extensions, and those in `skip`. Files whose size is unknown are kept
+/// by the size filters, while those whose modification time is unknown are kept unless `strict_dates` is set
+fn apply_filters(
+    mut files: Vec<UnixPathBuf>,
+    filters: &Filters,
+    skip: &SkipList,
+    target: MatchTarget,
+    stats: Option<&HashMap<UnixPathBuf, FileStat>>,
+) -> Vec<UnixPathBuf> {
+    let no_include = filters.include.is_empty() && filters.include_globs.is_empty();
+    files.retain(|file| {
+        let path = file.to_str().unwrap();
+        let matched = match target {
+            MatchTarget::FullPath => path,
+            MatchTarget::FileName => file.file_name().and_then(|name| name.to_str()).unwrap_or(path),
+        };
+        let included = filters.include.iter().any(|regex| regex.is_match(matched)) || filters.include_globs.is_match(matched);
+        let excluded = filters.exclude.iter().any(|regex| regex.is_match(matched)) || filters.exclude_globs.is_match(matched);
+        let extension = file_extension(file);
+        let extension_kept = filters.extensions.is_empty() || extension.as_ref().is_some_and(|ext| filters.extensions.contains(ext));
+        let extension_excluded = extension.is_some_and(|ext| filters.excluded_extensions.contains(&ext));
+
+        let stat = stats.and_then(|stats| stats.get(file));
+        let size_kept = stat.is_none_or(|stat| {
+            filters.min_size.is_none_or(|min_size| stat.size >= min_size) && filters.max_size.is_none_or(|max_size| stat.size <= max_size)
+        });
+        let date_kept = match stat {
+            Some(stat) => {
+                filters.newer_than.is_none_or(|newer_than| stat.mtime > newer_than)
+                    && filters.older_than.is_none_or(|older_than| stat.mtime < older_than)
+            }
+            None => !filters.strict_dates || !filters.has_date_filters(),
+        };
+
+        let hidden = filters.exclude_hidden.is_some_and(|mode| is_hidden(file, mode));
+        let default_excluded = filters.default_excludes.is_match(path);
+
+        (no_include || included)
+            && !hidden
+            && !default_excluded
+            && extension_kept
+            && size_kept
+            && date_kept
+            && !excluded
+            && !extension_excluded
+            && !skip.contains(path)
+    });
+    files
+}
+
+/// Removes the files inside folders containing a `.nomedia` file, which apps use to mark their
+/// private media, together with their subfolders
+fn prune_nomedia(mut files: Vec<UnixPathBuf>) -> Vec<UnixPathBuf> {
+    let nomedia_dirs: HashSet<UnixPathBuf> = files
+        .iter()
+        .filter(|file| file.file_name().is_some_and(|name| name == ".nomedia"))
+        .filter_map(|file| file.parent().map(|parent| parent.to_path_buf()))
+        .collect();
+    if nomedia_dirs.is_empty() {
+        return files;
+    }
+
+    let found = files.len();
+    files.retain(|file| !file.ancestors().skip(1).any(|dir| nomedia_dirs.contains(dir)));
+    info!("{:7} skipped because of a .nomedia file in {} folders", found - files.len(), nomedia_dirs.len());
+    files
+}
+
+/// Applies the filters given on the command line, printing how many files they removed. Returns the
+/// files kept and those removed
+fn filter_files(
+    args: &Cli,
+    files: Vec<UnixPathBuf>,
+    skip: &SkipList,
+    stats: Option<&HashMap<UnixPathBuf, FileStat>>,
+) -> (Vec<UnixPathBuf>, Vec<UnixPathBuf>) {
+    let target = if args.match_basename { MatchTarget::FileName } else { MatchTarget::FullPath };
+    let all_files = files.clone();
+    let files = if args.respect_nomedia { prune_nomedia(files) } else { files };
+    let found = files.len();
+    let total_bytes = |files: &[UnixPathBuf]| -> u64 {
+        stats.map_or(0, |stats| files.iter().filter_map(|file| stats.get(file)).map(|stat| stat.size).sum())
+    };
+
+    if args.filters.has_date_filters() {
+        let unknown = files.iter().filter(|file| stats.is_none_or(|stats| !stats.contains_key(*file))).count();
+        if unknown > 0 {
+            let action = if args.filters.strict_dates { "excluded" } else { "kept, use --strict-dates to exclude them" };
+            say!("{}", format!("{:7} files have an unknown modification date and were {action}", unknown).yellow());
+        }
+    }
+
+    // Hidden files are reported on their own, as they are usually many
+    let hidden = match args.filters.exclude_hidden {
+        Some(mode) => files.iter().filter(|file| is_hidden(file, mode)).count(),
+        None => 0,
+    };
+    if hidden > 0 {
+        info!("{:7} hidden files excluded", hidden);
+    }
+
+    let default_excluded = files
+        .iter()
+        .filter(|file| args.filters.exclude_hidden.is_none_or(|mode| !is_hidden(file, mode)))
+        .filter(|file| args.filters.default_excludes.is_match(file.to_str().unwrap()))
+        .count();
+    if default_excluded > 0 {
+        info!("{:7} thumbnails, trash or cache files excluded, use --no-default-excludes to copy them", default_excluded);
+    }
+
+    let not_excluded: Vec<&str> = files
+        .iter()
+        .filter(|file| args.filters.exclude_hidden.is_none_or(|mode| !is_hidden(file, mode)))
+        .filter(|file| !args.filters.default_excludes.is_match(file.to_str().unwrap()))
+        .map(|file| file.to_str().unwrap())
+        .collect();
+    let already_done = not_excluded.iter().filter(|path| skip.done.contains(**path)).count();
+    if already_done > 0 {
+        info!("{:7} skipped, as already copied by the previous run", already_done);
+    }
+    let listed = not_excluded.iter().filter(|path| !skip.done.contains(**path) && skip.listed.contains(**path)).count();
+    if listed > 0 {
+        info!("{:7} skipped, as listed in --skip or --skip-path", listed);
+    }
+    let skipped = already_done + listed;
+
+    let bytes_found = total_bytes(&files);
+    let files = apply_filters(files, &args.filters, skip, target, stats);
+    let bytes_kept = total_bytes(&files);
+    let found = found - hidden - default_excluded - skipped;
+
+    if files.len() < found {
+        let bytes = match stats {
+            Some(_) => format!(" ({} in total)", HumanBytes(bytes_found - bytes_kept)),
+            None => String::new(),
+        };
+        if args.filters.has_patterns() {
+            info!("{:7} filtered out{bytes}, matching the patterns against {target}", found - files.len());
+        } else {
+            info!("{:7} filtered out{bytes}", found - files.len());
+        }
+    }
+
+    let kept: HashSet<&UnixPathBuf> = files.iter().collect();
+    let removed = all_files.into_iter().filter(|file| !kept.contains(file)).collect();
+    (files, removed)
+}
+
+/// Parses a date as YYYY-MM-DD, optionally followed by HH:MM or HH:MM:SS, in UTC, or a time ago
+/// such as 30d, 12h or 2w. Returns the seconds since the Unix epoch
+fn parse_date(value: &str) -> Result<i64, String> {
+    let value = value.trim();
+    let invalid = || format!("invalid date {value:?}, expected YYYY-MM-DD [HH:MM[:SS]] or a time ago such as 30d, 12h or 2w");
+
+    if let Some(unit) = value.chars().last().filter(|unit| unit.is_ascii_alphabetic()) {
+        let amount: i64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+        let seconds = match unit.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        return Ok(now - amount * seconds);
+    }
+
+    let (date, time) = value.split_once([' ', 'T']).unwrap_or((value, "00:00"));
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+    let time: Vec<i64> = time.split(':').map(|part| part.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+    let (&[year, month, day], &[hour, minute, ..]) = (date.as_slice(), time.as_slice()) else {
+        return Err(invalid());
+    };
+    let second = time.get(2).copied().unwrap_or(0);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid());
+    }
+
+    // Days since the epoch of the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Ok(days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60 + second)
+}
+
+/// Parses sizes such as 500, 10K, 1.5M or 2G, where the units are powers of 1024
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let upper = value.to_uppercase();
+    let number = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, multiplier) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1u64 << 10),
+        Some('M') => (&number[..number.len() - 1], 1 << 20),
+        Some('G') => (&number[..number.len() - 1], 1 << 30),
+        Some('T') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+
+    match number.trim().parse::<f64>() {
+        Ok(number) if number >= 0.0 => Ok((number * multiplier as f64) as u64),
+        _ => Err(format!("invalid size {value:?}, expected a number optionally followed by K, M, G or T")),
+    }
+}
+
+/// The device paths to skip, split by where they come from
+#[derive(Debug, Default)]
+struct SkipList {
+    /// Given with --skip and --skip-path
+    listed: HashSet<String>,
+    /// Copied by the previous run, with --resume
+    done: HashSet<String>,
+}
+
+impl SkipList {
+    fn contains(&self, path: &str) -> bool {
+        self.listed.contains(path) || self.done.contains(path)
+    }
+}
+
+/// Normalizes a device path, so that repeated or trailing slashes don't prevent it from matching
+fn normalize_path(path: &str) -> String {
+    let path: UnixPathBuf = UnixPath::new(path).components().collect();
+    path.to_str().unwrap().to_string()
+}
+
+/// Reads the device paths written in `path`, one per line, ignoring blank lines and comments starting with `#`
+fn read_skip_file(path: &Path) -> Result<Vec<String>> {
+    let content = read_to_string(path).map_err(|err| anyhow!("Unable to read the skip file {:?}: {err}", path))?;
+
+    // Files edited on Windows end their lines with \r\n
+    Ok(content
+        .lines()
+        .map(|line| strip_reason(line).trim_end())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| normalize_path(&unescape_path(line)))
+        .collect())
+}
+
+/// The done file written by the previous run, looked for where this run will write it and then in
+/// the current folder, where older versions wrote it
+fn find_done_file(args: &Cli) -> Option<PathBuf> {
+    [args.done_file(), Some(PathBuf::from("./files_done.txt"))].into_iter().flatten().find(|path| path.is_file())
+}
+
+/// Reads the device paths to skip from the files given with --skip, adds those given with --skip-path
+/// and, with --resume, those copied by the previous run
+fn get_files_to_skip(args: &Cli) -> Result<SkipList> {
+    let mut skip_list = SkipList::default();
+    skip_list.listed.extend(args.skip_path.iter().map(|path| normalize_path(path.to_str().unwrap())));
+
+    if let Some(skip_inside) = &args.skip {
+        for path in skip_inside {
+            let entries = read_skip_file(path)?;
+            info!("{:7} entries to skip loaded from {:?}", entries.len(), path);
+            skip_list.listed.extend(entries);
+        }
+    }
+
+    if args.resume {
+        match find_done_file(args) {
+            Some(path) => {
+                skip_list.done.extend(read_skip_file(&path)?);
+                info!("{:7} files copied by the previous run loaded from {:?}", skip_list.done.len(), path);
+            }
+            None => say!("{}", "No done file found from a previous run, nothing to resume".yellow()),
+        }
+    }
+
+    Ok(skip_list)
+}
+
+/// How many sources are listed at the same time
+const LISTING_JOBS: usize = 4;
+
+/// The progress bars, hidden with --quiet, --no-progress and --verbose, as they would mix with the
+/// commands printed. indicatif hides them by itself when stderr isn't a terminal
+fn new_progress() -> MultiProgress {
+    if QUIET.load(Ordering::Relaxed) || NO_PROGRESS.load(Ordering::Relaxed) || verbosity() > 0 || events::enabled() {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    }
+}
+
+/// Prints `message` above the progress bars, or on stderr when they are hidden, e.g. with --quiet,
+/// as indicatif drops the lines printed above hidden bars
+fn print_above(progress: &MultiProgress, message: impl fmt::Display) {
+    let message = for_stream(message.to_string(), true);
+    if progress.is_hidden() {
+        eprintln!("{message}");
+    } else {
+        let _ = progress.println(message);
+    }
+}
+
+/// Lists the sources concurrently, as each listing spends most of its time waiting on the device.
+/// The listings are returned in the same order as the sources
+fn list_sources(adb: &Adb, sources: &[(UnixPathBuf, SourceKind)], options: &ListingOptions) -> Vec<Result<Listing>> {
+    let multi_progress = new_progress();
+    let next_source = AtomicUsize::new(0);
+    let listings: Mutex<Vec<Option<Result<Listing>>>> = Mutex::new(sources.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..LISTING_JOBS.min(sources.len()) {
+            scope.spawn(|| loop {
+                let index = next_source.fetch_add(1, Ordering::SeqCst);
+                let Some((root_src, kind)) = sources.get(index) else {
+                    break;
+                };
+
+                let spinner = multi_progress.add(ProgressBar::new_spinner());
+                spinner.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {human_pos} files found in {msg}").unwrap());
+                spinner.set_message(format!("{:?}", root_src));
+                spinner.enable_steady_tick(Duration::from_millis(100));
+
+                let mut listing = get_files_from_adb(adb, root_src, *kind, options, &spinner);
+                if let (true, Ok(listing)) = (options.with_stats, &mut listing) {
+                    spinner.set_message(format!("{:?}, getting their size and modification time", root_src));
+                    match get_file_stats(adb, &listing.files) {
+                        Ok(stats) => listing.stats = Some(stats),
+                        Err(err) => print_above(
+                            &multi_progress,
+                            format!("Unable to get the size and modification time of the files in {:?}: {:#}", root_src, err).yellow(),
+                        ),
+                    }
+                }
+                spinner.finish_and_clear();
+                multi_progress.remove(&spinner);
+
+                listings.lock().unwrap()[index] = Some(listing);
+            });
+        }
+    });
+
+    listings.into_inner().unwrap().into_iter().map(|listing| listing.unwrap()).collect()
+}
+
+/// Loads the listings saved with --save-listing. If sources were given too, only their listings are kept
+fn load_cached_listings(cache_path: &Path, sources: &[UnixPathBuf]) -> Result<Vec<(UnixPathBuf, Result<Listing>)>> {
+    let mut listings = load_listings(cache_path)?;
+    info!("Using the listing saved in {:?}", cache_path);
+
+    if is_older_than(cache_path, Duration::from_secs(24 * 60 * 60)) {
+        say!(
+            "{}",
+            "The saved listing is more than a day old, files added or removed since then on the device won't be noticed".yellow()
+        );
+    }
+
+    if sources.is_empty() {
+        return Ok(listings.into_iter().map(|(root_src, listing)| (root_src, Ok(listing))).collect());
+    }
+
+    Ok(sources
+        .iter()
+        .map(|root_src| match listings.iter().position(|(cached_src, _)| cached_src == root_src) {
+            Some(index) => (root_src.clone(), Ok(listings.swap_remove(index).1)),
+            None => (root_src.clone(), Err(anyhow!("It is not in the saved listing {:?}", cache_path))),
+        })
+        .collect())
+}
+
+/// Builds the file list from the device paths written in `list_path`, one per line, instead of
+/// listing the sources. Each file is copied as if listed from the longest source containing it,
+/// or keeping its full path inside the destination folder if there is none
+fn build_file_list_from_list(adb: &Adb, args: &Cli, list_path: &Path) -> Result<SrcDestFiles> {
+    let files_to_skip = get_files_to_skip(args)?;
+
+    let content = read_to_string(list_path).with_context(|| format!("Unable to read {:?}", list_path))?;
+    let file_list: Vec<UnixPathBuf> = read_path_lines(&content).collect();
+    let found = file_list.len();
+    info!("{:7} files found in {:?}", found, list_path);
+    let stats = if args.needs_stats() {
+        match get_file_stats(adb, &file_list) {
+            Ok(stats) => Some(stats),
+            Err(err) => {
+                say!("{}", format!("Unable to get the size and modification time of the files: {:#}", err).yellow());
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let (mut file_list, filtered) = filter_files(args, file_list, &files_to_skip, stats.as_ref());
+
+    let mut missing_list = Vec::new();
+    let missing = find_missing(adb, &file_list)?;
+    if !missing.is_empty() {
+        let mut missing_files: Vec<UnixPathBuf> = file_list.iter().filter(|file| missing.contains(*file)).cloned().collect();
+        missing_files.dedup();
+        match args.failed_file().filter(|_| !args.writes_nothing()).map(PathListWriter::open) {
+            Some(Ok(mut failed)) => {
+                say!(
+                    "{}",
+                    format!("{:7} files not found on the device, written to {:?}", missing_files.len(), failed.path).yellow()
+                );
+                for file in &missing_files {
+                    failed.append(file, Some(&PullFailure::NotFound.to_string()));
+                }
+            }
+            Some(Err(err)) => alert!("{}", format!("{:#}", err).red()),
+            None => say!("{}", format!("{:7} files not found on the device", missing_files.len()).yellow()),
+        }
+        file_list.retain(|file| !missing.contains(file));
+        missing_list = missing_files;
+    }
+
+    // Group the files by the source they belong to, keeping the order of the list
+    let root = UnixPathBuf::from("/");
+    let mut groups: Vec<(&UnixPathBuf, Vec<UnixPathBuf>)> = Vec::new();
+    for file in file_list {
+        let root_src = args
+            .source
+            .sources
+            .iter()
+            .filter(|root_src| file.starts_with(root_src))
+            .max_by_key(|root_src| root_src.components().count())
+            .unwrap_or(&root);
+
+        match groups.last_mut() {
+            Some((group_src, group)) if *group_src == root_src => group.push(file),
+            _ => groups.push((root_src, vec![file])),
+        }
+    }
+
+    let mut files = SrcDestFiles::new();
+    for (root_src, group) in groups {
+        let mut group_files = build_destination_files(&group, args, root_src, stats.as_ref())?;
+        requeue_existing(adb, args, &mut group_files, root_src, stats.as_ref());
+        resolve_conflicts(&mut group_files, args.on_conflict());
+        events::emit(&Event::Listing {
+            source: root_src.to_str().unwrap(),
+            count: group.len(),
+            to_copy: group_files.len(),
+        });
+        files.append(&mut group_files);
+    }
+    files.filtered = filtered;
+    files.missing = missing_list;
+    files.stats.extend(stats.unwrap_or_default());
+    report_existing(&files);
+    info!("{:7} to copy", files.len());
+    log(format!("{} files found in {:?}, {} to copy", found, list_path, files.len()));
+
+    Ok(files)
+}
+
+/// Replaces the sources containing wildcards with the paths they match on the device
+fn expand_sources(adb: &Adb, sources: &[UnixPathBuf]) -> Vec<UnixPathBuf> {
+    let mut expanded: Vec<UnixPathBuf> = Vec::new();
+    for source in sources {
+        if !is_glob(source) {
+            expanded.push(source.clone());
+            continue;
+        }
+
+        match expand_glob(adb, source) {
+            Ok(matches) if matches.is_empty() => {
+                say!("{}", format!("No files match {:?}", source).yellow());
+            }
+            Ok(matches) => expanded.extend(matches),
+            Err(err) => alert!("{}", format!("Unable to expand {:?}: {:#}", source, err).red()),
+        }
+    }
+
+    let mut seen: HashSet<UnixPathBuf> = HashSet::new();
+    expanded.retain(|source| seen.insert(source.clone()));
+    expanded
+}
+
+/// Checks that every source exists on the device, before spending time listing the others.
+/// Missing sources are an error, unless `ignore_missing` is set in which case they are skipped
+fn check_sources_exist(adb: &Adb, sources: Vec<UnixPathBuf>, ignore_missing: bool) -> Result<Vec<(UnixPathBuf, SourceKind)>> {
+    let mut checked: Vec<(UnixPathBuf, SourceKind)> = Vec::new();
+    let mut missing: Vec<UnixPathBuf> = Vec::new();
+    for source in sources {
+        match get_source_kind(adb, &source)? {
+            SourceKind::Missing => missing.push(source),
+            kind => checked.push((source, kind)),
+        }
+    }
+
+    for source in &missing {
+        if ignore_missing {
+            say!("{}", format!("{:?} does not exist on the device, skipping it", source).yellow());
+        } else {
+            alert!("{}", format!("{:?} does not exist on the device", source).red());
+        }
+    }
+    if !missing.is_empty() && !ignore_missing {
+        return Err(anyhow!("Use --ignore-missing-sources to copy the other sources anyway"));
+    }
+
+    Ok(checked)
+}
+
+/// Drops the sources which point to the same folder as another one, e.g. /sdcard and /storage/emulated/0,
+/// or which are inside another source, as their files would be copied twice
+fn collapse_sources(adb: &Adb, sources: Vec<(UnixPathBuf, SourceKind)>) -> Vec<(UnixPathBuf, SourceKind)> {
+    let canonical: Vec<UnixPathBuf> = sources.iter().map(|(source, _)| canonicalize(adb, source)).collect();
+
+    let mut kept: Vec<(UnixPathBuf, SourceKind)> = Vec::new();
+    for (index, (source, kind)) in sources.iter().enumerate() {
+        // Among sources pointing to the same path, the first one is kept
+        let outer = canonical.iter().enumerate().find(|(other, other_canonical)| {
+            *other != index && canonical[index].starts_with(other_canonical) && (canonical[index] != **other_canonical || *other < index)
+        });
+
+        match outer {
+            Some((other, _)) if canonical[index] == canonical[other] => {
+                info!("{:?} is the same as {:?}, skipping it", source, sources[other].0);
+            }
+            Some((other, _)) => {
+                info!("{:?} is inside {:?}, its files will be copied with it", source, sources[other].0);
+            }
+            None => kept.push((source.clone(), *kind)),
+        }
+    }
+
+    kept
+}
+
+fn build_file_list(adb: &Adb, args: &Cli) -> Result<SrcDestFiles> {
+    if let Some(list_path) = &args.source.from_list {
+        return build_file_list_from_list(adb, args, list_path);
+    }
+
+    let files_to_skip = get_files_to_skip(args)?;
+    let mut files = SrcDestFiles::new();
+    let mut unreadable_dirs: Vec<UnixPathBuf> = Vec::new();
+    let listing_options = ListingOptions {
+        follow_symlinks: args.follow_symlinks,
+        max_depth: if args.no_recursive { Some(1) } else { args.max_depth.map(|depth| depth as usize) },
+        with_stats: args.needs_stats(),
+    };
+
+    // The same file may be reachable from different paths, e.g. /sdcard and /storage/emulated/0
+    let mut canonical_files: HashSet<UnixPathBuf> = HashSet::new();
+
+    let listings: Vec<(UnixPathBuf, Result<Listing>)> = match &args.source.cached_listing {
+        Some(cache_path) => load_cached_listings(cache_path, &args.source.sources)?,
+        None => {
+            let sources = check_sources_exist(adb, expand_sources(adb, &args.source.sources), args.ignore_missing_sources)?;
+            let sources = collapse_sources(adb, sources);
+            let listings = list_sources(adb, &sources, &listing_options);
+            sources.into_iter().map(|(source, _)| source).zip(listings).collect()
+        }
+    };
+
+    if let Some(save_path) = &args.save_listing {
+        let to_save: Vec<(UnixPathBuf, &Listing)> = listings
+            .iter()
+            .filter_map(|(root_src, listing)| listing.as_ref().ok().map(|listing| (root_src.clone(), listing)))
+            .collect();
+        match save_listings(save_path, &to_save) {
+            Ok(()) => info!("Listing saved to {:?}", save_path),
+            Err(err) => eprintln!("{:#}", err),
+        }
+    }
+
+    let mut listing_errors: Vec<(UnixPathBuf, anyhow::Error)> = Vec::new();
+
+    for (root_src, listing) in listings.iter() {
+        let listing = match listing {
+            Ok(listing) => listing,
+            Err(err) => {
+                listing_errors.push((root_src.clone(), anyhow!("{:#}", err)));
+                continue;
+            }
+        };
+
+        let mut file_list = listing.files.clone();
+        if args.no_recursive {
+            info!("{:7} files found directly in {:?}", file_list.len(), &root_src);
+        } else {
+            info!("{:7} files found in {:?}", file_list.len(), &root_src);
+        }
+
+        let canonical_root = canonicalize(adb, root_src);
+        let listed = file_list.len();
+        file_list.retain(|file| {
+            let canonical = match file.strip_prefix(root_src) {
+                Ok(rel) if rel.as_unix_str().is_empty() => canonical_root.clone(),
+                Ok(rel) => canonical_root.join(rel),
+                Err(_) => file.clone(),
+            };
+            canonical_files.insert(canonical)
+        });
+        if file_list.len() < listed {
+            info!("{:7} already found in another source", listed - file_list.len());
+        }
+
+        if !listing.unreadable_dirs.is_empty() {
+            say!(
+                "{}",
+                format!(
+                    "{:7} folders in {:?} were skipped because they couldn't be read (Permission denied)",
+                    listing.unreadable_dirs.len(),
+                    &root_src
+                )
+                .yellow()
+            );
+            unreadable_dirs.extend(listing.unreadable_dirs.iter().cloned());
+        }
+
+        let (file_list, filtered) = filter_files(args, file_list, &files_to_skip, listing.stats.as_ref());
+
+        let mut temp_files = build_destination_files(&file_list, args, root_src, listing.stats.as_ref())?;
+        temp_files.filtered = filtered;
+        temp_files.stats = listing.stats.clone().unwrap_or_default();
+        requeue_existing(adb, args, &mut temp_files, root_src, listing.stats.as_ref());
+        if args.keep_empty_dirs {
+            add_source_dirs(adb, args, root_src, &listing_options, &mut temp_files);
+        }
+        resolve_conflicts(&mut temp_files, args.on_conflict());
+        // Only a full and fresh listing tells whether a folder holds anything else, and folders flattened or
+        // rearranged by --dest-template can't be pulled whole
+        let same_layout = !args.flatten && args.dest_template.is_none() && args.organize_by_date.is_none();
+        if listing_options.max_depth.is_none() && !args.follow_symlinks && args.source.cached_listing.is_none() && same_layout {
+            temp_files.complete_dirs = find_complete_dirs(root_src, &listing.files, temp_files.src_files().collect(), &listing.unreadable_dirs);
+            // adb pull would keep the names of the files, so the folders of those renamed are pulled file by file
+            let renamed_dirs: HashSet<&UnixPath> = temp_files.sanitized.keys().flat_map(|file| file.ancestors().skip(1)).collect();
+            temp_files.complete_dirs.retain(|dir| !renamed_dirs.contains(dir.as_path()));
+        }
+        report_existing(&temp_files);
+        info!("{:7} to copy", temp_files.len());
+        log(format!("{} files found in {:?}, {} to copy", listing.files.len(), root_src, temp_files.len()));
+        events::emit(&Event::Listing {
+            source: root_src.to_str().unwrap(),
+            count: listing.files.len(),
+            to_copy: temp_files.len(),
+        });
+
+        files.append(&mut temp_files)
+    }
+
+    for (root_src, err) in listing_errors.iter() {
+        say!("{}", format!("Unable to list {:?}: {:#}", root_src, err).red());
+        log(format!("Unable to list {:?}: {:#}", root_src, err));
+    }
+
+    if !unreadable_dirs.is_empty() {
+        if let Some(unreadable_path) = args.unreadable_dirs_file().filter(|_| !args.writes_nothing()) {
+            info!("Unreadable folders written to {:?}", unreadable_path);
+            write_path_list(&unreadable_path, &unreadable_dirs);
+        }
+
+        if args.strict_listing {
+            return Err(anyhow!("Some folders couldn't be listed, aborting because of --strict-listing"));
+        }
+    }
+
+    Ok(files)
+}
+
+/// The folders of `root_src`, itself included, whose `listed` files are all `kept` and none of whose
+/// subfolders is unreadable
+fn find_complete_dirs(
+    root_src: &UnixPath,
+    listed: &[UnixPathBuf],
+    kept: HashSet<&UnixPathBuf>,
+    unreadable_dirs: &[UnixPathBuf],
+) -> HashSet<UnixPathBuf> {
+    // Files listed and kept under each folder
+    let mut counts: HashMap<&UnixPath, (usize, usize)> = HashMap::new();
+    for file in listed {
+        let is_kept = kept.contains(file);
+        for dir in file.ancestors().skip(1).take_while(|dir| dir.starts_with(root_src)) {
+            let count = counts.entry(dir).or_default();
+            count.0 += 1;
+            count.1 += is_kept as usize;
+        }
+    }
+
+    counts
+        .into_iter()
+        .filter(|(dir, (listed, kept))| listed == kept && !unreadable_dirs.iter().any(|unreadable| unreadable.starts_with(dir)))
+        .map(|(dir, _)| dir.to_path_buf())
+        .collect()
+}
+
+/// The destinations of the files of `root_src`, flattened with --flatten or laid out by --dest-template, and
+/// with the names sanitized on Windows or with --sanitize-names. `stats` give the modification time of the
+/// files to the template
+fn build_destination_files(
+    file_list: &[UnixPathBuf],
+    args: &Cli,
+    root_src: &UnixPathBuf,
+    stats: Option<&HashMap<UnixPathBuf, FileStat>>,
+) -> Result<SrcDestFiles> {
+    let mut files = SrcDestFiles::new();
+    let root_dest = args.source_dest(root_src);
+    let force = args.overwrite();
+
+    let mut relative_files = Vec::with_capacity(file_list.len());
+    for file in file_list.iter() {
+        match relative_destination(file, root_src, args, stats)? {
+            Some(file_rel_to_src) => relative_files.push((file, file_rel_to_src)),
+            None => say!(
+                "Unable to strip the prefix {:?} from {:?} when tying to find its corresponding destination",
+                &root_src, &file
+            ),
+        }
+    }
+    let mut sanitizer = args
+        .name_substitute()
+        .map(|substitute| NameSanitizer::new(substitute, relative_files.iter().map(|(_, relative)| relative.as_path())));
+
+    for (file, file_rel_to_src) in relative_files {
+        let mut dest = root_dest.join(file_rel_to_src.as_unix_str().to_str().unwrap());
+        if let Some(sanitizer) = sanitizer.as_mut() {
+            let sanitized = root_dest.join(sanitizer.sanitize(&file_rel_to_src));
+            if sanitized != dest {
+                log(format!("Copying {:?} to {:?}, as its name isn't valid on Windows", file, sanitized));
+                files.sanitized.insert(file.to_owned(), BasePathBuf::new(dest).unwrap());
+                dest = sanitized;
+            }
+        }
+
+        if dest.exists() && !force {
+            files.existing.push((file.to_owned(), BasePathBuf::new(dest).unwrap()));
+            continue;
+        }
+
+        files.push(file.to_owned(), BasePathBuf::new(dest).unwrap(), root_src.clone());
+    }
+
+    if !files.sanitized.is_empty() {
+        info!("{:7} renamed to be valid on Windows", files.sanitized.len());
+    }
+    Ok(files)
+}
+
+/// The path of `file` relative to the destination folder. A source which is a file is copied directly inside
+/// it, the files of a folder keeping the folder itself, unless the source is `/`, or with --flatten. With
+/// --dest-template it's the expanded template, with --organize-by-date the folder of its date, with
+/// --strip-components and --keep-after what is left of its device path. A file which doesn't fit the layout
+/// asked with these two is an error, as the files which fit would be copied to unexpected places
+fn relative_destination(
+    file: &UnixPath,
+    root_src: &UnixPath,
+    args: &Cli,
+    stats: Option<&HashMap<UnixPathBuf, FileStat>>,
+) -> Result<Option<UnixPathBuf>> {
+    if let Some(organize) = args.organize_by_date {
+        // With exif the files are moved to the folder of their date once copied and read
+        let mtime = stats
+            .and_then(|stats| stats.get(file))
+            .map(|stat| stat.mtime)
+            .filter(|_| organize == OrganizeBy::Mtime);
+        let mut relative: UnixPathBuf = date_folder(mtime.map(civil_date).map(|(year, month, _)| (year, month)))
+            .into_iter()
+            .collect();
+        return Ok(file.file_name().map(|name| {
+            relative.push(name);
+            relative
+        }));
+    }
+    if let Some(template) = &args.dest_template {
+        // A source which is a file is taken as the file of its folder
+        let source_dir = if file == root_src { root_src.parent() } else { Some(root_src) };
+        let Some(source_dir) = source_dir else { return Ok(None) };
+        let source_name = source_dir.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let mtime = stats.and_then(|stats| stats.get(file)).map(|stat| stat.mtime);
+        return Ok(file
+            .strip_prefix(source_dir)
+            .ok()
+            .map(|relative| template.expand(source_name, relative, mtime)));
+    }
+    if let Some(count) = args.strip_components {
+        return kept_device_path(file, root_src, args).map(Some).ok_or_else(|| {
+            let folders = file.iter().count().saturating_sub(2);
+            anyhow!("{file:?} is inside only {folders} folders, it can't be copied with --strip-components {count}")
+        });
+    }
+    if let Some(prefix) = &args.keep_after {
+        return kept_device_path(file, root_src, args)
+            .map(Some)
+            .ok_or_else(|| anyhow!("{file:?} isn't inside {prefix:?}, it can't be copied with --keep-after"));
+    }
+    if file == root_src || args.flatten {
+        return Ok(file.file_name().map(UnixPathBuf::from));
+    }
+    Ok(kept_device_path(file, root_src, args))
+}
+
+/// The part of the device `path` kept in the destination: without the first folders with --strip-components,
+/// after the prefix with --keep-after, otherwise from the name of the source. None if nothing is left
+fn kept_device_path(path: &UnixPath, root_src: &UnixPath, args: &Cli) -> Option<UnixPathBuf> {
+    let relative = if let Some(count) = args.strip_components {
+        path.strip_prefix("/").unwrap_or(path).iter().skip(count).collect()
+    } else if let Some(prefix) = &args.keep_after {
+        path.strip_prefix(prefix).ok()?.to_path_buf()
+    } else {
+        path.strip_prefix(root_src.parent().unwrap_or(root_src)).ok()?.to_path_buf()
+    };
+    Some(relative).filter(|relative| !relative.as_unix_str().is_empty())
+}
+
+/// Lists the folders of `root_src` with their destination, so that --keep-empty-dirs creates those no file
+/// is copied in
+fn add_source_dirs(adb: &Adb, args: &Cli, root_src: &UnixPath, options: &ListingOptions, files: &mut SrcDestFiles) {
+    let dirs = match get_dirs_from_adb(adb, &root_src.to_path_buf(), options) {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            say!("{}", format!("Unable to list the folders of {:?}: {:#}", root_src, err).yellow());
+            return;
+        }
+    };
+    let root_dest = args.source_dest(root_src);
+    for dir in dirs {
+        // The folders above the path kept in the destination have no place in it
+        let Some(relative) = kept_device_path(&dir, root_src, args) else {
+            continue;
+        };
+        let dest: PathBuf = match args.name_substitute() {
+            Some(substitute) => relative
+                .iter()
+                .filter_map(|name| name.to_str())
+                .map(|name| sanitize_name(name, substitute))
+                .collect(),
+            None => PathBuf::from(relative.to_str().unwrap()),
+        };
+        files.empty_dirs.push((dir, root_dest.join(dest)));
+    }
+}
+
+/// Keeps among the folders listed with --keep-empty-dirs those which no file to copy is in and which aren't
+/// in the destination yet
+fn find_empty_dirs(files: &mut SrcDestFiles) {
+    let filled: HashSet<&UnixPath> = files.entries.iter().flat_map(|entry| entry.src.ancestors().skip(1)).collect();
+    files.empty_dirs.retain(|(dir, dest)| !filled.contains(dir.as_path()) && !dest.exists());
+    if !files.empty_dirs.is_empty() {
+        info!("{:7} empty folders to create", files.empty_dirs.len());
+    }
+}
+
+/// Creates the folders kept by [`find_empty_dirs`], returning how many were created
+fn create_empty_dirs(dirs: &[(UnixPathBuf, PathBuf)]) -> usize {
+    let mut created = 0;
+    for (dir, dest) in dirs {
+        match fs::create_dir_all(dest) {
+            Ok(()) => {
+                log(format!("Created {:?} for the empty folder {:?}", dest, dir));
+                created += 1;
+            }
+            Err(err) => say!("{}", format!("Unable to create {:?}: {err}", dest).red()),
+        }
+    }
+    created
+}
+
+/// Queues again the files already in the destination which --verify-existing, --update or --skip-if identical
+/// tell to copy again
+fn requeue_existing(adb: &Adb, args: &Cli, files: &mut SrcDestFiles, root_src: &UnixPath, stats: Option<&HashMap<UnixPathBuf, FileStat>>) {
+    if args.verify_existing {
+        requeue_damaged(adb, files, root_src, stats);
+    }
+    // The files newer on the device differ from those in the destination too
+    if args.skip_if() == SkipIf::Identical {
+        requeue_changed(adb, files, root_src, stats, !args.no_metadata);
+    } else if args.update {
+        requeue_outdated(adb, files, root_src, stats);
+    }
+}
+
+/// Asks the device for the size and modification time of the files already in the destination missing from
+/// `stats`, adding them to the stats of `files`
+fn fetch_existing_stats(adb: &Adb, files: &mut SrcDestFiles, stats: Option<&HashMap<UnixPathBuf, FileStat>>) {
+    let unknown: Vec<UnixPathBuf> = files
+        .existing
+        .iter()
+        .map(|(file, _)| file)
+        .filter(|file| stats.is_none_or(|stats| !stats.contains_key(*file)) && !files.stats.contains_key(*file))
+        .cloned()
+        .collect();
+    if !unknown.is_empty() {
+        match get_file_stats(adb, &unknown) {
+            Ok(fetched) => files.stats.extend(fetched),
+            Err(err) => say!(
+                "{}",
+                format!(
+                    "Unable to get the size and modification time of the files already in the destination: {:#}",
+                    err
+                )
+                .yellow()
+            ),
+        }
+    }
+}
+
+/// Queues again the files already in the destination whose size differs from the one on the device, taken
+/// from `stats` or, for the files missing from it, asked to the device one by one
+fn requeue_damaged(adb: &Adb, files: &mut SrcDestFiles, root_src: &UnixPath, stats: Option<&HashMap<UnixPathBuf, FileStat>>) {
+    fetch_existing_stats(adb, files, stats);
+
+    let mut damaged = 0;
+    for (file, dest) in std::mem::take(&mut files.existing) {
+        let device_size = stats.and_then(|stats| stats.get(&file)).or_else(|| files.stats.get(&file)).map(|stat| stat.size);
+        let local_size = fs::metadata(dest.as_path()).ok().filter(|metadata| metadata.is_file()).map(|metadata| metadata.len());
+        match (device_size, local_size) {
+            (Some(device_size), Some(local_size)) if device_size != local_size => {
+                log(format!(
+                    "{:?} is {} in the destination but {} on the device, copying it again",
+                    file,
+                    HumanBytes(local_size),
+                    HumanBytes(device_size)
+                ));
+                files.damaged.insert(file.clone());
+                files.push(file, dest, root_src.to_path_buf());
+                damaged += 1;
+            }
+            _ => files.existing.push((file, dest)),
+        }
+    }
+    if damaged > 0 {
+        info!("{:7} damaged in the destination, copying them again", damaged);
+    }
+}
+
+/// How much newer a file has to be on the device to be copied again with --update, or how much its modification
+/// time can differ with --skip-if identical, as FAT destinations round it to 2 seconds
+const UPDATE_TOLERANCE_SECS: i64 = 2;
+
+/// Queues again the files already in the destination modified on the device after their copy, with --update.
+/// Those whose modification time is unknown are left alone
+fn requeue_outdated(adb: &Adb, files: &mut SrcDestFiles, root_src: &UnixPath, stats: Option<&HashMap<UnixPathBuf, FileStat>>) {
+    fetch_existing_stats(adb, files, stats);
+
+    let mut outdated = 0;
+    for (file, dest) in std::mem::take(&mut files.existing) {
+        let device_mtime = stats
+            .and_then(|stats| stats.get(&file))
+            .or_else(|| files.stats.get(&file))
+            .map(|stat| stat.mtime);
+        let local_mtime = fs::metadata(dest.as_path())
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs() as i64);
+        match (device_mtime, local_mtime) {
+            (Some(device_mtime), Some(local_mtime)) if device_mtime > local_mtime + UPDATE_TOLERANCE_SECS => {
+                log(format!(
+                    "{:?} was modified on the device {} after its copy, copying it again",
+                    file,
+                    HumanDuration(Duration::from_secs((device_mtime - local_mtime) as u64))
+                ));
+                files.push(file, dest, root_src.to_path_buf());
+                outdated += 1;
+            }
+            (Some(_), Some(_)) => {
+                files.up_to_date += 1;
+                files.existing.push((file, dest));
+            }
+            _ => files.existing.push((file, dest)),
+        }
+    }
+    if outdated > 0 {
+        info!("{:7} newer on the device, copying them again", outdated);
+    }
+}
+
+/// Queues again the files already in the destination whose size or, with `compare_mtime`, modification time
+/// differ from the ones on the device, with --skip-if identical. Those whose size is unknown are left alone
+fn requeue_changed(adb: &Adb, files: &mut SrcDestFiles, root_src: &UnixPath, stats: Option<&HashMap<UnixPathBuf, FileStat>>, compare_mtime: bool) {
+    fetch_existing_stats(adb, files, stats);
+
+    let mut changed = 0;
+    for (file, dest) in std::mem::take(&mut files.existing) {
+        let device = stats.and_then(|stats| stats.get(&file)).or_else(|| files.stats.get(&file)).copied();
+        let local = fs::metadata(dest.as_path()).ok().filter(|metadata| metadata.is_file());
+        let (Some(device), Some(local)) = (device, local) else {
+            files.existing.push((file, dest));
+            continue;
+        };
+        let local_mtime = local
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_secs() as i64);
+        let difference = if device.size != local.len() {
+            format!(
+                "is {} in the destination but {} on the device",
+                HumanBytes(local.len()),
+                HumanBytes(device.size)
+            )
+        } else if compare_mtime && (device.mtime - local_mtime).abs() > UPDATE_TOLERANCE_SECS {
+            "has another modification time in the destination than on the device".to_string()
+        } else {
+            files.up_to_date += 1;
+            files.existing.push((file, dest));
+            continue;
+        };
+        log(format!("{:?} {difference}, copying it again", file));
+        files.push(file, dest, root_src.to_path_buf());
+        changed += 1;
+    }
+    if changed > 0 {
+        info!("{:7} different from the device, copying them again", changed);
+    }
+}
+
+/// Records what is done with the destinations of the files to copy which already exist. With rename they
+/// are given the first free name, not taken by another file either
+fn resolve_conflicts(files: &mut SrcDestFiles, on_conflict: OnConflict) {
+    let mut taken: HashSet<PathBuf> = files.entries.iter().map(|entry| entry.dest.as_path().to_path_buf()).collect();
+    for FileEntry { src: src_file, dest, .. } in files.entries.iter_mut() {
+        if !dest.exists() {
+            continue;
+        }
+        // The files copied again with skip are the outdated or damaged ones, which are replaced
+        let action = match on_conflict {
+            OnConflict::Skip => OnConflict::Overwrite,
+            action => action,
+        };
+        if action == OnConflict::Rename {
+            let renamed = free_name(dest.as_path(), |path| taken.contains(path));
+            taken.insert(renamed.clone());
+            log(format!("{:?} is already in the destination, copying it to {:?}", src_file, renamed));
+            *dest = BasePathBuf::new(renamed).unwrap();
+        }
+        files.conflicts.insert(src_file.clone(), action);
+    }
+}
+
+/// Leaves out the files to copy which are already anywhere under `dir`, with --dedupe-against. Only those with
+/// the size of a file of `dir` are hashed, on the device with md5sum or, if it lacks it, compared by name
+fn skip_duplicates(adb: &Adb, dir: &Path, files: &mut SrcDestFiles) -> Result<()> {
+    let mut index = DedupeIndex::open(dir).context("Unable to read --dedupe-against")?;
+    let candidates: Vec<UnixPathBuf> = files
+        .src_files()
+        .filter(|file| files.stats.get(*file).is_some_and(|stat| index.has_size(stat.size)))
+        .cloned()
+        .collect();
+    info!("{:7} files with the size of one of the {} in {:?}", candidates.len(), index.len(), dir);
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let hashes = match adb.has_command(DEDUPE_HASH.command()) {
+        true => match device_hashes(adb, &candidates, DEDUPE_HASH) {
+            Ok(hashes) => Some(hashes),
+            Err(err) => {
+                say!("{}", format!("{:#}", err).yellow());
+                None
+            }
+        },
+        false => None,
+    };
+    if hashes.is_none() {
+        say!(
+            "{}",
+            "The files can't be hashed on the device, comparing them by size and name, which may take different files as the same".yellow()
+        );
+        files.duplicates_by_name = true;
+    }
+
+    let mut duplicates: HashMap<UnixPathBuf, PathBuf> = HashMap::new();
+    for file in candidates {
+        let size = files.stats[&file].size;
+        let local = match &hashes {
+            Some(hashes) => hashes.get(&file).and_then(|hash| index.find(size, hash)),
+            None => file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| index.find_by_name(size, name)),
+        };
+        if let Some(local) = local {
+            log(format!("{:?} is already in {:?}, skipping it", file, local));
+            duplicates.insert(file, local);
+        }
+    }
+    if let Err(err) = index.save() {
+        say!("{}", format!("Unable to save the hashes of {:?}: {:#}", dir, err).yellow());
+    }
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+
+    info!("{:7} skipped, as already in {:?}", duplicates.len(), dir);
+    for entry in std::mem::take(&mut files.entries) {
+        match duplicates.remove(&entry.src) {
+            Some(local) => {
+                files.forget_complete_dirs_of(&entry.src);
+                files.duplicates.push((entry.src, local));
+            }
+            None => files.entries.push(entry),
+        }
+    }
+    Ok(())
+}
+
+/// How a destination path is compared with the others to find collisions, ignoring the case where the
+/// file systems usually do
+fn collision_key(dest: &Path) -> String {
+    let dest = dest.to_string_lossy();
+    match cfg!(any(windows, target_os = "macos")) {
+        true => dest.to_lowercase(),
+        false => dest.into_owned(),
+    }
+}
+
+/// Sorts the files to copy as --sort and --reverse tell. Those whose size or modification time is unknown go
+/// last, in the order they were listed
+fn sort_files(files: &mut SrcDestFiles, sort: SortBy, reverse: bool) {
+    let stats = &files.stats;
+    let needs_stat = matches!(sort, SortBy::Size | SortBy::Mtime);
+    let (mut entries, unknown): (Vec<FileEntry>, Vec<FileEntry>) = std::mem::take(&mut files.entries)
+        .into_iter()
+        .partition(|entry| !needs_stat || stats.contains_key(&entry.src));
+    match sort {
+        SortBy::Name => entries.sort_by(|a, b| a.src.cmp(&b.src)),
+        SortBy::Size => entries.sort_by_key(|entry| stats[&entry.src].size),
+        SortBy::Mtime => entries.sort_by_key(|entry| (stats[&entry.src].mtime, stats[&entry.src].mtime_nanos)),
+        SortBy::Unsorted => (),
+    }
+    if reverse {
+        entries.reverse();
+    }
+    if !unknown.is_empty() {
+        say!(
+            "{}",
+            format!("{:7} files whose size or modification time is unknown will be copied last", unknown.len()).yellow()
+        );
+    }
+    entries.extend(unknown);
+    files.entries = entries;
+    // A folder pulled whole is copied at once, in the order adb chooses
+    files.complete_dirs.clear();
+}
+
+/// Leaves out the files after the first `limit`, or after those whose total size fits in `max_total_size`, keeping
+/// the order they would be copied in. The size of the files not known is counted as 0
+fn truncate_files(files: &mut SrcDestFiles, limit: Option<usize>, max_total_size: Option<u64>) {
+    let mut kept = limit.unwrap_or(usize::MAX).min(files.len());
+    if let Some(max_total_size) = max_total_size {
+        if files.src_files().any(|file| !files.stats.contains_key(file)) {
+            say!("{}", "The size of some files is unknown, --max-total-size counts them as empty".yellow());
+        }
+        let mut total = 0u64;
+        let fitting = files.src_files().position(|file| {
+            total = total.saturating_add(files.stats.get(file).map_or(0, |stat| stat.size));
+            total > max_total_size
+        });
+        kept = kept.min(fitting.unwrap_or(kept));
+    }
+    if kept == files.len() {
+        return;
+    }
+
+    let truncated: Vec<UnixPathBuf> = files.entries.split_off(kept).into_iter().map(|entry| entry.src).collect();
+    for file in &truncated {
+        files.forget_complete_dirs_of(file);
+        files.damaged.remove(file);
+        files.conflicts.remove(file);
+    }
+    let bytes: u64 = truncated.iter().filter_map(|file| files.stats.get(file)).map(|stat| stat.size).sum();
+    info!("{:7} left out, {}, by --limit or --max-total-size", truncated.len(), HumanBytes(bytes));
+    log(format!("{} files left out, {}, by --limit or --max-total-size", truncated.len(), HumanBytes(bytes)));
+    files.truncated = truncated;
+}
+
+/// Finds the files with the same destination, of different sources or flattened with --flatten, and handles
+/// all but the first one of each as --on-collision tells, reporting them. With abort they are an error
+fn resolve_collisions(files: &mut SrcDestFiles, on_collision: OnCollision) -> Result<()> {
+    let mut first_with_key: HashMap<String, usize> = HashMap::new();
+    let mut colliding: Vec<(usize, usize)> = Vec::new();
+    for (index, entry) in files.entries.iter().enumerate() {
+        match first_with_key.entry(collision_key(entry.dest.as_path())) {
+            Entry::Occupied(first) => colliding.push((index, *first.get())),
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+            }
+        }
+    }
+    if colliding.is_empty() {
+        return Ok(());
+    }
+
+    for &(index, first) in &colliding {
+        let message = format!(
+            "{:?} and {:?} would both be copied to {:?}",
+            files.entries[first].src,
+            files.entries[index].src,
+            files.entries[index].dest.as_path()
+        );
+        // The renamed files are only counted, as with --flatten there may be many
+        match on_collision {
+            OnCollision::Abort => alert!("{}", message.red()),
+            OnCollision::Skip => say!("{}", message.yellow()),
+            OnCollision::Rename => (),
+        }
+        log(message);
+    }
+
+    match on_collision {
+        OnCollision::Abort => return Err(anyhow!("Use --on-collision skip or rename to copy the other files")),
+        OnCollision::Skip => {
+            info!("{:7} skipped, as another file has the same destination", colliding.len());
+            let mut skipped: HashMap<usize, UnixPathBuf> = colliding
+                .into_iter()
+                .map(|(index, first)| (index, files.entries[first].src.clone()))
+                .collect();
+            for (index, entry) in std::mem::take(&mut files.entries).into_iter().enumerate() {
+                match skipped.remove(&index) {
+                    Some(first) => {
+                        files.forget_complete_dirs_of(&entry.src);
+                        files.collisions.push((entry.src, entry.dest, first));
+                    }
+                    None => files.entries.push(entry),
+                }
+            }
+        }
+        OnCollision::Rename => {
+            info!("{:7} renamed, as another file has the same destination", colliding.len());
+            files.renamed_collisions = colliding.len();
+            let mut taken: HashSet<String> = first_with_key.into_keys().collect();
+            for (index, _) in colliding {
+                let entry = &mut files.entries[index];
+                let renamed = free_name(entry.dest.as_path(), |path| taken.contains(&collision_key(path)));
+                taken.insert(collision_key(&renamed));
+                log(format!("Copying {:?} to {:?}", entry.src, renamed));
+                files.conflicts.insert(entry.src.clone(), OnConflict::Rename);
+                entry.dest = BasePathBuf::new(renamed).unwrap();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The first of `name (1).ext`, `name (2).ext` and so on next to `path` which doesn't exist and isn't `taken`
+fn free_name(path: &Path, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|number| path.with_file_name(format!("{stem} ({number}){extension}")))
+        .find(|candidate| !candidate.exists() && !taken(candidate))
+        .unwrap()
+}
+
+/// Where a file copied with --organize-by-date exif ended up
+enum Organized {
+    /// In the folder of its date
+    Moved(PathBuf),
+    /// Where it was copied, as --organize-by-date isn't exif
+    Kept,
+    /// In the unknown-date folder, as its date couldn't be read
+    Undated,
+    /// Removed, as the folder of its date already had it with --on-conflict skip
+    AlreadyThere(PathBuf),
+}
+
+/// Moves the file copied in the unknown-date folder of `root_dest` to the folder of the date it was taken.
+/// The file already there with its name is handled as --on-conflict tells
+fn organize_by_capture_date(copied: &Path, root_dest: &Path, on_conflict: OnConflict) -> io::Result<Organized> {
+    let Some(date) = capture_date(copied) else {
+        return Ok(Organized::Undated);
+    };
+    let mut dest = BasePathBuf::new(root_dest)?.into_path_buf();
+    dest.extend(date_folder(Some(date)));
+    dest.push(copied.file_name().unwrap());
+
+    if dest.exists() {
+        match on_conflict {
+            OnConflict::Skip => {
+                fs::remove_file(copied)?;
+                return Ok(Organized::AlreadyThere(dest));
+            }
+            OnConflict::Overwrite => (),
+            OnConflict::Rename => dest = free_name(&dest, |_| false),
+            OnConflict::Backup => {
+                let backup = backup_path(&dest);
+                log(format!("Moving {:?} to {:?} before replacing it", dest, backup));
+                fs::rename(&dest, backup)?;
+            }
+        }
+    }
+    move_into_place(copied, &dest)?;
+    Ok(Organized::Moved(dest))
+}
+
+/// The first of `<name>.bak.1`, `<name>.bak.2` and so on next to `path` which doesn't exist, where
+/// --on-conflict backup moves the file being replaced
+fn backup_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|number| path.with_file_name(format!("{name}.bak.{number}")))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// Prints how many files are skipped as already in the destination, telling apart those found up to date
+/// with --update or --skip-if identical
+fn report_existing(files: &SrcDestFiles) {
+    if files.up_to_date > 0 {
+        info!("{:7} skipped, up to date", files.up_to_date);
+    }
+    let existing = files.existing.len() - files.up_to_date;
+    if existing > 0 {
+        info!("{:7} skipped, already in the destination", existing);
+    }
+}
+
+/// Fills in the placeholders of --dest-template which are the same for all the files, and with --dest-per-device
+/// moves the destination into the folder of the device. The model and serial are asked once, and only if needed
+fn fill_device_dest(adb: &Adb, mut args: Cli, started: SystemTime) -> Cli {
+    let template = args.dest_template.as_ref();
+    let needs_model = args.dest_per_device || template.is_some_and(DestTemplate::needs_model);
+    let needs_serial = args.dest_per_device || template.is_some_and(DestTemplate::needs_serial);
+    let model = needs_model.then(|| adb.getprop("ro.product.model")).flatten();
+    let serial = match needs_serial {
+        true => adb.serial.clone().or_else(|| adb.getprop("ro.serialno")),
+        false => None,
+    };
+    let model = model.as_deref().unwrap_or("unknown");
+    let serial = serial.as_deref().unwrap_or("unknown");
+
+    if let Some(template) = args.dest_template.as_mut() {
+        let started = started.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        template.fill_run(model, serial, started, args.name_substitute);
+    }
+
+    if args.dest_per_device {
+        let folder = device_folder(model, serial, args.name_substitute);
+        info!("Copying to the folder of the device {:?}", folder);
+        args.dest.push(folder);
+        let reports = [&mut args.done_file, &mut args.failed_file, &mut args.checksum_file, &mut args.manifest, &mut args.manifest_csv];
+        for report in reports.into_iter().flatten() {
+            if report.is_relative() {
+                *report = args.dest.join(&*report);
+            }
+        }
+    }
+    args
+}
+
+/// Returns the device serial and the adb server host and port, with the flags taking precedence over
+/// the environment variables adb itself reads
+fn adb_endpoint_from_env(args: &Cli) -> Result<(Option<String>, Option<String>, Option<u16>)> {
+    let env_var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+    // --connect picks the device itself
+    let serial = match (&args.serial, env_var("ANDROID_SERIAL")) {
+        (Some(serial), _) => Some(serial.clone()),
+        (None, Some(serial)) if args.connect.is_none() => {
+            info!("Using device {serial:?} from ANDROID_SERIAL");
+            Some(serial)
+        }
+        _ => None,
+    };
+
+    let host = args.adb_host.clone().or_else(|| env_var("ANDROID_ADB_SERVER_ADDRESS"));
+
+    let port = match (args.adb_port, env_var("ANDROID_ADB_SERVER_PORT")) {
+        (Some(port), _) => Some(port),
+        (None, Some(port)) => Some(port.parse().map_err(|_| anyhow!("Invalid ANDROID_ADB_SERVER_PORT: {port:?}"))?),
+        (None, None) => None,
+    };
+
+    if host.is_some() || port.is_some() {
+        info!(
+            "Using adb server at {}:{}",
+            host.as_deref().unwrap_or("localhost"),
+            port.unwrap_or(5037)
+        );
+    }
+
+    Ok((serial, host, port))
+}
+
+/// Asks the user for the pairing code until a 6-digit one is given
+fn ask_pairing_code() -> Result<String> {
+    if QUIET.load(Ordering::Relaxed) {
+        return Err(anyhow!("Give the pairing code with --code when using --quiet"));
+    }
+    let mut user_input = String::new();
+
+    while user_input.trim().len() != 6 || !user_input.trim().chars().all(|c| c.is_ascii_digit()) {
+        print!("Enter the 6-digit pairing code shown on the phone: ");
+        let _ = std::io::stdout().flush();
+        user_input.clear();
+        if std::io::stdin().read_line(&mut user_input).unwrap_or(0) == 0 {
+            println!();
+            return Err(anyhow!("No pairing code given"));
+        }
+    }
+
+    Ok(user_input.trim().to_string())
+}
+
+fn pair_device(adb: &Adb, endpoint: &str, code: Option<String>, connect: Option<&str>) -> Result<()> {
+    let code = match code {
+        Some(code) => code,
+        None => ask_pairing_code()?,
+    };
+
+    println!("Pairing with {endpoint}..");
+    adb.pair(endpoint, &code)?;
+    say!("{}", format!("Paired with {endpoint}").green());
+
+    if let Some(connect) = connect {
+        println!("Connecting to {connect}..");
+        adb.connect(connect)?;
+        say!("{}", format!("Connected to {connect}, pull from it with --connect {connect}").green());
+    }
+    Ok(())
+}
+
+/// Escapes backslashes and control characters (e.g. newlines) so that each path fits on a single line
+fn escape_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverts [`escape_path`]
+fn unescape_path(line: &str) -> String {
+    let mut path = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => path.push('\n'),
+            Some('r') => path.push('\r'),
+            Some('t') => path.push('\t'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) => path.push(byte as char),
+                    Err(_) => {
+                        path.push_str("\\x");
+                        path.push_str(&hex);
+                    }
+                }
+            }
+            Some(other) => path.push(other),
+            None => path.push('\\'),
+        }
+    }
+    path
+}
+
+/// An error if the destination hasn't enough free space for the files and the margin of --disk-space-margin,
+/// only a warning with --ignore-disk-space. Skipped if the size of some files is unknown
+fn check_disk_space(files: &SrcDestFiles, args: &Cli) -> Result<()> {
+    let sizes: Option<Vec<u64>> = files.src_files().map(|file| files.stats.get(file).map(|stat| stat.size)).collect();
+    let Some(sizes) = sizes else {
+        info!("The size of some files is unknown, so the free space in the destination isn't checked");
+        return Ok(());
+    };
+    let available = match available_space(&args.dest) {
+        Ok(available) => available,
+        Err(err) => {
+            info!("Unable to get the free space in {:?}, so it isn't checked: {err}", args.dest);
+            return Ok(());
+        }
+    };
+    let total: u64 = sizes.iter().sum();
+    let needed = total.saturating_add(total / 100 * args.disk_space_margin);
+    if needed <= available {
+        return Ok(());
+    }
+
+    let message = format!(
+        "{:?} has {} free, but the files take {} and {}% more is kept free",
+        args.dest,
+        HumanBytes(available),
+        HumanBytes(total),
+        args.disk_space_margin
+    );
+    log(&message);
+    if args.ignore_disk_space {
+        say!("{}", format!("{message}. Copying anyway because of --ignore-disk-space").yellow());
+        return Ok(());
+    }
+    Err(anyhow!("{message}. Free some space, or use --ignore-disk-space to copy anyway"))
+}
+
+/// Shows what is about to be copied, with the size when known, and asks whether to go on, returning false
+/// unless the answer is yes. Without questions the copy goes on, except with --move which needs --yes then
+fn confirm_copy(files: &SrcDestFiles, args: &Cli) -> Result<bool> {
+    let bytes: Option<u64> = files.src_files().map(|file| files.stats.get(file).map(|stat| stat.size)).sum();
+    let sources = files.entries.iter().map(|entry| &entry.root_src).collect::<HashSet<_>>().len();
+    let mut about = format!("About to copy {} files", HumanCount(files.len() as u64));
+    if let Some(bytes) = bytes {
+        about = format!("{about} ({})", HumanBytes(bytes));
+    }
+    if sources > 1 {
+        about = format!("{about} from {sources} sources");
+    }
+    about = format!("{about} to {:?}", args.dest);
+    if args.move_files {
+        about = format!("{about}, deleting them from the device");
+    }
+
+    match ask_yes_no(&format!("{about}. Continue?")) {
+        Some(true) if args.yes => info!("{about}"),
+        Some(true) => {}
+        Some(false) => {
+            info!("Nothing copied");
+            return Ok(false);
+        }
+        None if args.move_files => return Err(anyhow!("Give --yes to delete the files with --move without questions")),
+        None => info!("{about}"),
+    }
+    Ok(true)
+}
+
+/// Asks `question` on stderr, apart from the output, until answered y or n. --yes answers yes to every question, while None is returned when
+/// questions can't be asked: with --quiet or --progress-json, when stdin isn't a terminal or without input
+fn ask_yes_no(question: &str) -> Option<bool> {
+    if ASSUME_YES.load(Ordering::Relaxed) {
+        return Some(true);
+    }
+    if QUIET.load(Ordering::Relaxed) || events::enabled() || !io::stdin().is_terminal() {
+        return None;
+    }
+    let mut user_input = String::new();
+
+    while user_input.trim().to_lowercase() != "y" && user_input.trim().to_lowercase() != "n" {
+        eprint!("{question} [y/N]: ");
+        let _ = std::io::stderr().flush();
+        user_input.clear();
+        if std::io::stdin().read_line(&mut user_input).unwrap_or(0) == 0 {
+            eprintln!();
+            return None;
+        }
+    }
+
+    Some(user_input.trim().to_lowercase() == "y")
+}
+
+/// Files, bytes and time spent pulling them, of a source or of the whole run
+#[derive(Debug, Default, Clone, Copy)]
+struct TransferTotals {
+    copied: usize,
+    failed: usize,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+impl fmt::Display for TransferTotals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} copied", self.copied)?;
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+        write!(f, ", {} in {}", HumanBytes(self.bytes), HumanDuration(self.elapsed))?;
+        if !self.elapsed.is_zero() {
+            write!(f, " ({}/s)", HumanBytes((self.bytes as f64 / self.elapsed.as_secs_f64()) as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Progress within the source being copied, shown below the overall bar when copying several
+/// sources. It's removed once the source is done
+struct SourceProgress {
+    root_src: UnixPathBuf,
+    bar: ProgressBar,
+    /// Files of the source not copied yet
+    remaining: usize,
+}
+
+impl SourceProgress {
+    /// `len` is the bytes to copy from the source, or its files if their sizes aren't known
+    fn new(progress: &MultiProgress, root_src: &UnixPathBuf, files: usize, len: u64, bytes: bool) -> Self {
+        let template = if bytes {
+            "  {prefix} [{bar:20.cyan/blue}] {binary_bytes}/{binary_total_bytes}, {msg}"
+        } else {
+            "  {prefix} [{bar:20.cyan/blue}] {human_pos}/{human_len}, {msg}"
+        };
+        let bar = progress
+            .add(ProgressBar::new(len))
+            .with_style(ProgressStyle::with_template(template).unwrap().progress_chars("#>-"))
+            .with_prefix(escape_path(root_src.to_str().unwrap()))
+            .with_message(format!("{files} files left"));
+        Self {
+            root_src: root_src.clone(),
+            bar,
+            remaining: files,
+        }
+    }
+
+    fn file_done(&mut self, advance: u64) {
+        self.remaining = self.remaining.saturating_sub(1);
+        self.bar.inc(advance);
+        self.bar.set_message(format!("{} files left", self.remaining));
+    }
+
+    fn finish(self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// The file_done event of --progress-json. `failure` is None if the file was copied
+fn emit_file_done(src_file: &UnixPath, dest_file: &Path, bytes: u64, failure: Option<&PullFailure>) {
+    events::emit(&Event::FileDone {
+        source_path: src_file.to_str().unwrap(),
+        dest_path: &dest_file.to_string_lossy(),
+        bytes,
+        status: if failure.is_some() { FileStatus::Failed } else { FileStatus::Ok },
+        error: failure.map(|failure| failure.to_string()),
+    });
+}
+
+/// Totals of the transfer, for each source and for the whole run
+struct TransferSummary {
+    started: Instant,
+    /// Files not copied because their destination already exists
+    skipped: usize,
+    /// In the order the sources were copied
+    sources: Vec<(UnixPathBuf, TransferTotals)>,
+    /// Bytes received compressed and those written from them, when measurable
+    compressed: Option<(u64, u64)>,
+    /// Files found damaged in the destination with --verify-existing, and those of them copied again
+    damaged: usize,
+    repaired: usize,
+    /// Files deleted from the device with --move and their bytes, those which couldn't be, and the
+    /// folders removed with --prune-empty-dirs
+    deleted: usize,
+    freed: u64,
+    not_deleted: usize,
+    pruned_dirs: usize,
+    /// Files given another name as another file had the same destination, with --on-collision rename
+    renamed_collisions: usize,
+    /// Files left in unknown-date with --organize-by-date exif, as their date couldn't be read
+    undated: usize,
+    /// Files not copied as already under the folder of --dedupe-against
+    duplicates: usize,
+    /// Folders of the device created empty with --keep-empty-dirs
+    created_dirs: usize,
+    /// Files left out by --limit and --max-total-size, and their bytes when known
+    truncated: usize,
+    truncated_bytes: u64,
+}
+
+impl TransferSummary {
+    fn new(skipped: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            skipped,
+            sources: Vec::new(),
+            compressed: None,
+            damaged: 0,
+            repaired: 0,
+            deleted: 0,
+            freed: 0,
+            not_deleted: 0,
+            pruned_dirs: 0,
+            renamed_collisions: 0,
+            undated: 0,
+            duplicates: 0,
+            created_dirs: 0,
+            truncated: 0,
+            truncated_bytes: 0,
+        }
+    }
+
+    /// Adds a file of `root_src` which took `elapsed` to pull. `bytes` is None if it wasn't copied
+    fn add(&mut self, root_src: &UnixPath, bytes: Option<u64>, elapsed: Duration) {
+        let totals = match self.sources.iter().position(|(source, _)| source == root_src) {
+            Some(index) => &mut self.sources[index].1,
+            None => {
+                self.sources.push((root_src.to_path_buf(), TransferTotals::default()));
+                &mut self.sources.last_mut().unwrap().1
+            }
+        };
+        match bytes {
+            Some(bytes) => {
+                totals.copied += 1;
+                totals.bytes += bytes;
+            }
+            None => totals.failed += 1,
+        }
+        totals.elapsed += elapsed;
+    }
+
+    /// The totals of all the sources, over the time elapsed since the start
+    fn total(&self) -> TransferTotals {
+        let mut total = self.sources.iter().fold(TransferTotals::default(), |mut total, (_, totals)| {
+            total.copied += totals.copied;
+            total.failed += totals.failed;
+            total.bytes += totals.bytes;
+            total
+        });
+        total.elapsed = self.started.elapsed();
+        total
+    }
+
+    /// The summary event of --progress-json
+    fn emit(&self) {
+        let total = self.total();
+        events::emit(&Event::Summary {
+            copied: total.copied,
+            failed: total.failed,
+            skipped: self.skipped,
+            bytes: total.bytes,
+            elapsed_secs: total.elapsed.as_secs_f64(),
+        });
+    }
+
+    fn print(&self) {
+        let total = self.total();
+
+        log(format!("Finished: {total}, {} skipped as already in the destination", self.skipped));
+        if self.damaged > 0 {
+            log(format!("{} damaged in the destination, {} repaired", self.damaged, self.repaired));
+        }
+        if self.deleted + self.not_deleted > 0 {
+            log(format!("{} deleted from the device, {} freed", self.deleted, HumanBytes(self.freed)));
+        }
+        if self.truncated > 0 {
+            log(format!("{} left out by --limit or --max-total-size", self.truncated));
+        }
+        self.emit();
+        if QUIET.load(Ordering::Relaxed) {
+            match self.skipped {
+                0 => eprintln!("{total}"),
+                skipped => eprintln!("{total}, {skipped} skipped as already in the destination"),
+            }
+            return;
+        }
+
+        info!("\nSummary:");
+        info!("    {total}");
+        if let Some((received, unpacked)) = self.compressed.filter(|(_, unpacked)| *unpacked > 0) {
+            info!(
+                "    {} received compressed for {} written ({:.0}%)",
+                HumanBytes(received),
+                HumanBytes(unpacked),
+                received as f64 / unpacked as f64 * 100.0
+            );
+        }
+        if self.skipped > 0 {
+            info!("    {} skipped, as already in the destination", self.skipped);
+        }
+        if self.duplicates > 0 {
+            info!("    {} skipped, as duplicates of files already copied", self.duplicates);
+        }
+        if self.truncated > 0 {
+            info!(
+                "    {} left out, {}, as over --limit or --max-total-size",
+                self.truncated,
+                HumanBytes(self.truncated_bytes)
+            );
+        }
+        if self.damaged > 0 {
+            info!("    {} damaged in the destination, {} repaired", self.damaged, self.repaired);
+        }
+        if self.renamed_collisions > 0 {
+            info!("    {} renamed, as another file had the same destination", self.renamed_collisions);
+        }
+        if self.undated > 0 {
+            info!(
+                "    {} left in {UNKNOWN_DATE_DIR}, as the date they were taken couldn't be read",
+                self.undated
+            );
+        }
+        if self.deleted > 0 {
+            info!("    {} deleted from the device, {} freed", self.deleted, HumanBytes(self.freed));
+        }
+        if self.not_deleted > 0 {
+            info!("    {}", format!("{} copied but couldn't be deleted from the device", self.not_deleted).yellow());
+        }
+        if self.pruned_dirs > 0 {
+            info!("    {} empty folders removed from the device", self.pruned_dirs);
+        }
+        if self.created_dirs > 0 {
+            info!("    {} empty folders created", self.created_dirs);
+        }
+        if self.sources.len() > 1 {
+            for (source, totals) in self.sources.iter() {
+                info!("    {:?}: {totals}", source);
+            }
+        }
+        info!();
+    }
+}
+
+/// Creates the --manifest and --manifest-csv, if requested, and adds the files which won't be copied:
+/// those filtered out, those already in the destination and those of --from-list missing on the device
+fn open_manifest(args: &Cli, adb: &Adb, started: SystemTime, files: &SrcDestFiles) -> Option<Manifest> {
+    let mut manifest = Manifest::new(
+        files.stats.clone(),
+        files.conflicts.clone(),
+        files
+            .sanitized
+            .iter()
+            .map(|(file, dest)| (file.clone(), dest.as_path().to_path_buf()))
+            .collect(),
+    );
+    let run = RunInfo {
+        timestamp: started.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        device_serial: adb.serial.clone(),
+        sources: args.source.sources.iter().map(|source| source.to_str().unwrap().to_string()).collect(),
+        destination: args.dest.to_string_lossy().into_owned(),
+        arguments: env::args().skip(1).collect(),
+    };
+
+    let opened = args
+        .manifest
+        .as_ref()
+        .map_or(Ok(()), |path| manifest.add_json(path, &run))
+        .and_then(|_| args.manifest_csv.as_ref().map_or(Ok(()), |path| manifest.add_csv(path)));
+    if let Err(err) = opened {
+        alert!("{}", format!("{:#}", err).red());
+        return None;
+    }
+    if manifest.is_empty() {
+        return None;
+    }
+
+    let result = files
+        .filtered
+        .iter()
+        .try_for_each(|file| manifest.write(file, None, Status::Filtered, None, None))
+        .and_then(|_| {
+            files
+                .existing
+                .iter()
+                .try_for_each(|(file, dest)| manifest.write(file, Some(dest.as_path()), Status::SkippedExisting, None, None))
+        })
+        .and_then(|_| {
+            files
+                .missing
+                .iter()
+                .try_for_each(|file| manifest.write(file, None, Status::Failed, Some(PullFailure::NotFound.to_string()), None))
+        })
+        .and_then(|_| {
+            files.collisions.iter().try_for_each(|(file, dest, first)| {
+                let error = format!("same destination as {:?}", first);
+                manifest.write(file, Some(dest.as_path()), Status::SkippedCollision, Some(error), None)
+            })
+        })
+        .and_then(|_| {
+            files.duplicates.iter().try_for_each(|(file, local)| {
+                let error = match files.duplicates_by_name {
+                    true => format!("duplicate of {:?}, by size and name", local),
+                    false => format!("duplicate of {:?}", local),
+                };
+                manifest.write(file, Some(local), Status::SkippedDuplicate, Some(error), None)
+            })
+        })
+        .and_then(|_| {
+            files
+                .truncated
+                .iter()
+                .try_for_each(|file| manifest.write(file, None, Status::SkippedLimit, None, None))
+        });
+    match result {
+        Ok(()) => Some(manifest),
+        Err(err) => {
+            alert!("{}", format!("{:#}", err).red());
+            None
+        }
+    }
+}
+
+fn finish_manifest(manifest: Manifest) {
+    match manifest.finish() {
+        Ok(paths) => {
+            for path in paths {
+                info!("Manifest written to {:?}", path);
+            }
+        }
+        Err(err) => alert!("{}", format!("{:#}", err).red()),
+    }
+}
+
+/// Prints the files to copy and their size for each source and for all of them, with --summary-only. The size of
+/// the sources with files of unknown size is asked to du on the device, marked with `~` as it counts all their files
+fn print_summary(adb: &Adb, files: &SrcDestFiles) {
+    if files.is_empty() {
+        println!("No files found to copy");
+        return;
+    }
+
+    // Files, bytes and whether the size of some is unknown, in the order of the sources
+    let mut sources: Vec<(&UnixPath, usize, u64, bool)> = Vec::new();
+    for FileEntry { src: file, root_src, .. } in files.entries.iter() {
+        let index = match sources.iter().position(|(source, ..)| *source == root_src.as_path()) {
+            Some(index) => index,
+            None => {
+                sources.push((root_src.as_path(), 0, 0, false));
+                sources.len() - 1
+            }
+        };
+        let entry = &mut sources[index];
+        entry.1 += 1;
+        match files.stats.get(file) {
+            Some(stat) => entry.2 += stat.size,
+            None => entry.3 = true,
+        }
+    }
+
+    let mut rows: Vec<(String, String, String)> = Vec::new();
+    let (mut total_bytes, mut total_estimated, mut total_unknown) = (0u64, false, false);
+    for (source, count, bytes, unknown) in sources {
+        let size = match unknown {
+            false => Some(bytes),
+            true => match adb.disk_usage(source.to_str().unwrap()) {
+                Ok(bytes) => {
+                    total_estimated = true;
+                    Some(bytes)
+                }
+                Err(err) => {
+                    say!("{}", format!("{:#}", err).yellow());
+                    None
+                }
+            },
+        };
+        let size = match size {
+            Some(size) => {
+                total_bytes += size;
+                format!("{}{}", if unknown { "~" } else { "" }, HumanBytes(size))
+            }
+            None => {
+                total_unknown = true;
+                "-".to_string()
+            }
+        };
+        rows.push((count.to_string(), size, source.to_str().unwrap().to_string()));
+    }
+    let total_size = match (total_unknown, total_estimated) {
+        (true, _) => format!("{}+", HumanBytes(total_bytes)),
+        (false, true) => format!("~{}", HumanBytes(total_bytes)),
+        (false, false) => HumanBytes(total_bytes).to_string(),
+    };
+    rows.push((files.len().to_string(), total_size, "total".to_string()));
+
+    let count_width = rows.iter().map(|(count, ..)| count.len()).chain(["Files".len()]).max().unwrap();
+    let size_width = rows.iter().map(|(_, size, _)| size.len()).chain(["Size".len()]).max().unwrap();
+    println!("{:>count_width$}  {:>size_width$}  Source", "Files", "Size");
+    for (count, size, source) in &rows {
+        println!("{count:>count_width$}  {size:>size_width$}  {source}");
+    }
+}
+
+/// Prints on stdout the files --dry-run would copy and their destinations. Stops early if stdout is closed, e.g. by `head`
+fn print_dry_run(files: &SrcDestFiles, args: &Cli) {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let written = match args.long {
+        true => write_long_listing(&mut out, files, args.porcelain),
+        false => write_listing(&mut out, files, args),
+    };
+    let _ = written.and_then(|_| out.flush());
+}
+
+/// Writes each file and its destination as `SOURCE  ->  DEST`, separated by a tab with --porcelain or each followed
+/// by a NUL with --print0
+fn write_listing(out: &mut impl Write, files: &SrcDestFiles, args: &Cli) -> io::Result<()> {
+    for entry in files.entries.iter() {
+        let src = entry.src.to_str().unwrap();
+        let dest = entry.dest.as_path().to_string_lossy();
+        if args.print0 {
+            write!(out, "{src}\0{dest}\0")?;
+        } else if args.porcelain {
+            writeln!(out, "{}\t{}", escape_path(src), escape_path(&dest))?;
+        } else {
+            writeln!(out, "{}", for_stream(format!("{}  {}  {}", src.green(), "->".cyan(), dest), false))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes each file with its size and modification time, `-` when unknown, then the files and bytes of each folder
+/// of the device they are in and of all of them, counting the sizes known. With `porcelain` the fields are separated
+/// by tabs, after a header line, and each line starts with its kind: file, dir or total
+fn write_long_listing(out: &mut impl Write, files: &SrcDestFiles, porcelain: bool) -> io::Result<()> {
+    let stats: Vec<Option<&FileStat>> = files.src_files().map(|file| files.stats.get(file)).collect();
+    let mtimes: Vec<String> = stats
+        .iter()
+        .map(|stat| stat.map_or("-".to_string(), |stat| format_timestamp(stat.mtime)))
+        .collect();
+
+    // In the order the folders are first met
+    let mut dirs: Vec<(&UnixPath, usize, u64)> = Vec::new();
+    let mut dir_index: HashMap<&UnixPath, usize> = HashMap::new();
+    for (file, stat) in files.src_files().zip(stats.iter()) {
+        let dir = file.parent().unwrap_or(file.as_path());
+        let index = *dir_index.entry(dir).or_insert_with(|| {
+            dirs.push((dir, 0, 0));
+            dirs.len() - 1
+        });
+        dirs[index].1 += 1;
+        dirs[index].2 += stat.map_or(0, |stat| stat.size);
+    }
+    let total_bytes: u64 = dirs.iter().map(|(_, _, bytes)| bytes).sum();
+
+    if porcelain {
+        writeln!(out, "kind\tsize\tmtime\tsource\tdest")?;
+        for (entry, (stat, mtime)) in files.entries.iter().zip(stats.iter().zip(mtimes.iter())) {
+            let size = stat.map_or("-".to_string(), |stat| stat.size.to_string());
+            let src = escape_path(entry.src.to_str().unwrap());
+            let dest = escape_path(&entry.dest.as_path().to_string_lossy());
+            writeln!(out, "file\t{size}\t{mtime}\t{src}\t{dest}")?;
+        }
+        for (dir, _, bytes) in &dirs {
+            writeln!(out, "dir\t{bytes}\t-\t{}\t-", escape_path(dir.to_str().unwrap()))?;
+        }
+        writeln!(out, "total\t{total_bytes}\t-\t-\t-")?;
+        return Ok(());
+    }
+
+    let sizes: Vec<String> = stats
+        .iter()
+        .map(|stat| stat.map_or("-".to_string(), |stat| HumanBytes(stat.size).to_string()))
+        .collect();
+    let dir_sizes: Vec<String> = dirs.iter().map(|(_, _, bytes)| HumanBytes(*bytes).to_string()).collect();
+    let total_size = HumanBytes(total_bytes).to_string();
+    let width = sizes.iter().chain(dir_sizes.iter()).chain([&total_size]).map(|size| size.len()).max().unwrap_or(1);
+
+    for (entry, (size, mtime)) in files.entries.iter().zip(sizes.iter().zip(mtimes.iter())) {
+        let line = format!(
+            "{size:>width$}  {mtime:19}  {}  {}  {}",
+            entry.src.to_str().unwrap().green(),
+            "->".cyan(),
+            entry.dest.as_path().to_string_lossy()
+        );
+        writeln!(out, "{}", for_stream(line, false))?;
+    }
+    writeln!(out)?;
+    for ((dir, count, _), size) in dirs.iter().zip(dir_sizes.iter()) {
+        writeln!(out, "{size:>width$}  {count:>7} files  {}", dir.to_str().unwrap())?;
+    }
+    writeln!(out, "{total_size:>width$}  {:>7} files  total", files.len())?;
+    Ok(())
+}
+
+/// Appends the paths to the file at `list_path`, one per line, leaving out those already in it
+fn write_path_list(list_path: &Path, paths: &[UnixPathBuf]) {
+    if let Some(parent) = list_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if let Err(e) = fs::create_dir_all(parent) {
+            eprintln!("Couldn't create {:?}: {}", parent, e);
+            return;
+        }
+    }
+
+    let mut written: HashSet<String> = read_to_string(list_path).unwrap_or_default().lines().map(String::from).collect();
+    let mut file = match OpenOptions::new().append(true).create(true).open(list_path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Couldn't open {:?}: {}", list_path, e);
+            return;
+        }
+    };
+
+    for path in paths {
+        let line = escape_path(path.as_path().to_str().unwrap());
+        if !written.insert(line.clone()) {
+            continue;
+        }
+        if let Err(e) = writeln!(file, "{}", line) {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+    }
+}
+
+/// Disconnects from the network device if the user asked to
+fn disconnect_if_requested(adb: &Adb, args: &Cli) {
+    if let (Some(endpoint), true) = (&args.connect, args.disconnect_after) {
+        if let Err(err) = adb.disconnect(endpoint) {
+            eprintln!("{err}");
+        }
+    }
+}
+
+fn main() {
+    let status = match run() {
+        Ok(status) => status,
+        Err(err) => {
+            alert!("{}", format!("{:#}", err).red());
+            ExitStatus::Fatal
+        }
+    };
+    exit_with(status);
+}
+
+/// Does what the command line asks, returning how the run ended. The errors are fatal, each stopping it
+/// before anything is copied
+fn run() -> Result<ExitStatus> {
+    let started = SystemTime::now();
+    let args: Cli = {
+        // Limit scope to remove mutability
+        let mut args = Cli::parse();
+        if args.source.show_default_excludes {
+            for pattern in DEFAULT_EXCLUDES {
+                println!("{pattern}");
+            }
+            return Ok(ExitStatus::Success);
+        }
+        args.color.apply();
+        QUIET.store(args.quiet, Ordering::Relaxed);
+        NO_PROGRESS.store(args.no_progress, Ordering::Relaxed);
+        ASSUME_YES.store(args.yes, Ordering::Relaxed);
+        if args.progress_json {
+            events::enable();
+        }
+        MESSAGES_TO_STDERR.store(args.progress_json || args.dry_run, Ordering::Relaxed);
+        set_verbosity(args.verbose);
+        // Opened before anything else, so that an unwritable path is reported before the copy starts
+        if let Some(path) = &args.log_file {
+            open_log_file(path)?;
+        }
+        log(format!("Started: {}", quote_words(env::args())));
+        args.check_sources()?;
+        args.check_filters()?;
+        args
+    };
+
+    let path = get_adb_path(args.adb_path.as_deref())?;
+    info!("Using adb from: {path:?}");
+    let (serial, host, port) = adb_endpoint_from_env(&args)?;
+    let mut adb = Adb::new(path, serial, host, port);
+
+    match &args.command {
+        Some(Commands::Devices) => {
+            print_devices(&get_devices(&adb)?);
+            return Ok(ExitStatus::Success);
+        }
+        Some(Commands::Pair { endpoint, code, connect }) => {
+            pair_device(&adb, endpoint, code.clone(), connect.as_deref())?;
+            return Ok(ExitStatus::Success);
+        }
+        Some(Commands::Browse { .. }) | None => {}
+    }
+
+    // Only for the runs which copy files, as the destination isn't used by the subcommands. With browse it's
+    // checked once the files are picked
+    let mut args = args;
+    if args.command.is_none() {
+        args.check_dest()?;
+    }
+
+    adb.start_server(Duration::from_secs(10)).context("Unable to start the adb server")?;
+
+    if let Some(endpoint) = &args.connect {
+        info!("Connecting to {endpoint}..");
+        adb.connect(endpoint)?;
+        adb.serial = Some(endpoint.clone());
+    }
+
+    if let Some(seconds) = args.wait_for_device {
+        info!("Waiting up to {seconds} seconds for a device..");
+        match adb.wait_for_device(Duration::from_secs(seconds)) {
+            Ok(true) => {}
+            Ok(false) => say!("No device attached after {seconds} seconds"),
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+
+    info!("Checking if a device is attached to adb server..");
+    let adb_path = adb.path.as_path().to_str().unwrap();
+    match connected_to_adb_server(&adb, Some(args.device_retries), Duration::from_millis(args.retry_delay))? {
+        ConnectionStatus::Connected => {}
+        ConnectionStatus::Unauthorized => {
+            return Err(anyhow!(
+                "The device is unauthorized. Unlock the phone and accept the \"Allow USB debugging\" prompt, then try again."
+            ));
+        }
+        ConnectionStatus::Offline => {
+            return Err(anyhow!(
+                "The device is offline. Try executing \"{adb_path} reconnect\" or reconnecting the cable."
+            ));
+        }
+        ConnectionStatus::NoDevice => match &adb.serial {
+            Some(serial) => return Err(anyhow!("Device \"{serial}\" not found. Try executing \"{adb_path} devices\"")),
+            None => return Err(anyhow!("No device found. Try executing \"{adb_path} devices\"")),
+        },
+    }
+
+    // Without a serial adb refuses to run any command when more than one device is attached
+    if adb.serial.is_none() {
+        let serials: Vec<String> = get_devices(&adb)?.into_iter().map(|device| device.serial).collect();
+        if serials.len() > 1 {
+            alert!("More than one device attached:");
+            for serial in serials.iter() {
+                alert!("    {serial}");
+            }
+            return Err(anyhow!("Select one with --serial <SERIAL>"));
+        }
+        adb.serial = serials.into_iter().next();
+    }
+
+    if let Some(serial) = &adb.serial {
+        info!("Using device: {serial}");
+        log(format!("Using device {serial}"));
+    }
+
+    let args = match &args.command {
+        Some(Commands::Browse { start, print_selection }) => {
+            let selection = match browse(&adb, start.clone())? {
+                Some(selection) if !selection.is_empty() => selection,
+                _ => {
+                    say!("Nothing selected. Exiting..");
+                    return Ok(ExitStatus::Success);
+                }
+            };
+
+            if *print_selection {
+                for path in &selection {
+                    println!("{}", escape_path(path.to_str().unwrap()));
+                }
+                return Ok(ExitStatus::Success);
+            }
+
+            let mut args = args;
+            args.source.sources = selection;
+            args.check_dest()?;
+            args
+        }
+        _ => args,
+    };
+    let args = fill_device_dest(&adb, args, started);
+
+    info!("Building file list, it may take some time...");
+
+    let mut files = build_file_list(&adb, &args)?;
+    resolve_collisions(&mut files, args.on_collision())?;
+    if let Some(dir) = &args.dedupe_against {
+        skip_duplicates(&adb, dir, &mut files)?;
+    }
+    if args.sort != SortBy::Unsorted || args.reverse {
+        sort_files(&mut files, args.sort, args.reverse);
+    }
+    // After all the files not to be copied are left out, so that the cap counts only those which are
+    if args.limit.is_some() || args.max_total_size.is_some() {
+        truncate_files(&mut files, args.limit, args.max_total_size);
+    }
+    if args.keep_empty_dirs {
+        find_empty_dirs(&mut files);
+    }
+
+    if args.source.sources.len() > 1 {
+        info!("\n{} total files to copy", files.len());
+    }
+
+    if args.summary_only {
+        print_summary(&adb, &files);
+        disconnect_if_requested(&adb, &args);
+        return Ok(ExitStatus::Success);
+    }
+
+    if let Some(list_path) = &args.list_only {
+        // Truncate the file, as write_path_list appends
+        File::create(list_path).with_context(|| format!("Unable to create {:?}", list_path))?;
+        let src_files: Vec<UnixPathBuf> = files.src_files().cloned().collect();
+        write_path_list(list_path, &src_files);
+        info!("{} files written to {:?}", files.len(), list_path);
+        disconnect_if_requested(&adb, &args);
+        return Ok(ExitStatus::Success);
+    }
+
+    if args.dry_run {
+        if !files.empty_dirs.is_empty() {
+            info!("{} empty folders would be created", files.empty_dirs.len());
+        }
+        match files.is_empty() {
+            true => info!("No files found to copy"),
+            false => print_dry_run(&files, &args),
+        }
+        disconnect_if_requested(&adb, &args);
+        return Ok(ExitStatus::Success);
+    }
+
+    let manifest = open_manifest(&args, &adb, started, &files);
+
+    if files.is_empty() {
+        let created = create_empty_dirs(&files.empty_dirs);
+        if created > 0 {
+            info!("{created} empty folders created");
+        }
+        info!("No files found to copy. Exiting..");
+        TransferSummary::new(files.existing.len()).emit();
+        if let Some(manifest) = manifest {
+            finish_manifest(manifest);
+        }
+        disconnect_if_requested(&adb, &args);
+        return Ok(ExitStatus::Success);
+    }
+
+    check_disk_space(&files, &args)?;
+    if !confirm_copy(&files, &args)? {
+        return Ok(ExitStatus::Success);
+    }
+
+    // A run killed midway leaves behind the files it was pulling
+    let removed = remove_partial_files(&args.dest);
+    if removed > 0 {
+        info!("Removed {removed} partial files left in the destination by a previous run");
+    }
+
+    let mut reports = ReportFiles::open(&args, manifest);
+    let mut summary = TransferSummary::new(files.existing.len());
+    summary.damaged = files.damaged.len();
+    summary.renamed_collisions = files.renamed_collisions;
+    summary.duplicates = files.duplicates.len();
+    summary.truncated = files.truncated.len();
+    summary.truncated_bytes = files.truncated.iter().filter_map(|file| files.stats.get(file)).map(|stat| stat.size).sum();
+    let damaged = std::mem::take(&mut files.damaged);
+    let empty_dirs = std::mem::take(&mut files.empty_dirs);
+
+    // The progress is measured in bytes when the size of every file is known, as a few big videos
+    // among many thumbnails make the file count misleading
+    let file_sizes: Vec<Option<u64>> = files.src_files().map(|file| files.stats.get(file).map(|stat| stat.size)).collect();
+    let sizes: Option<Vec<u64>> = file_sizes.iter().copied().collect();
+    let total_bytes = sizes.as_ref().map(|sizes| sizes.iter().sum::<u64>());
+
+    // Failures and reconnections are printed through it, so that they don't mess with the progress bar
+    let progress = new_progress();
+    let pb = match total_bytes {
+        Some(total_bytes) => progress.add(ProgressBar::new(total_bytes)).with_style(
+            ProgressStyle::with_template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {binary_bytes:>10}/{binary_total_bytes:10} ({binary_bytes_per_sec}, {eta}) {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+        ),
+        None => progress.add(ProgressBar::new(files.len() as u64)).with_style(
+            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {human_pos:>7}/{human_len:7} ({eta}) {wide_msg}")
+                .unwrap()
+                .progress_chars("#>-"),
+        ),
+    };
+    pb.enable_steady_tick(Duration::from_millis(50));
+
+    if let Some(path) = &args.checksum_file {
+        let root = BasePathBuf::new(&args.dest).unwrap().into_path_buf();
+        match ChecksumFile::open(path.clone(), root, args.checksum_jobs as usize, &progress) {
+            Ok(checksums) => reports.checksums = Some(checksums),
+            Err(err) => alert!("{}", format!("{:#}", err).red()),
+        }
+    }
+
+    // Files and bytes to copy from each source, for the per-source bar
+    let mut source_totals: HashMap<UnixPathBuf, (usize, u64)> = HashMap::new();
+    for (entry, size) in files.entries.iter().zip(file_sizes.iter()) {
+        let totals = source_totals.entry(entry.root_src.clone()).or_default();
+        totals.0 += 1;
+        totals.1 += size.unwrap_or(0);
+    }
+    let mut source_progress: Option<SourceProgress> = None;
+    let mut files_count = files.len();
+    // Without a progress bar, e.g. when redirecting the output to a file, the progress is printed now and then
+    let progress_lines = progress.is_hidden() && !args.quiet && !args.progress_json;
+    let mut last_progress_line = Instant::now();
+    let rate_window = Mutex::new(RateWindow::new());
+    // Room left for the message by the rest of the progress bar
+    let message_width = (Term::stderr().size().1 as usize).saturating_sub(if sizes.is_some() { 85 } else { 60 }).max(20);
+
+    // Without tar on the device the files are pulled as usual
+    let tar_stream = args.tar_stream && adb.has_command("tar");
+    if args.tar_stream && !tar_stream {
+        say!("{}", "tar isn't available on the device, pulling the files with adb pull".yellow());
+    }
+    adb.keep_metadata = !args.no_metadata;
+    let mut stream_compressor = None;
+    if args.compress {
+        adb.pull_compression = adb.supported_pull_compression();
+        if tar_stream {
+            stream_compressor = adb.stream_compressor();
+        }
+        match (tar_stream, stream_compressor, adb.pull_compression) {
+            (true, Some(compressor), _) => info!("Compressing the tar stream with {}", compressor.name()),
+            (true, None, _) => say!("{}", "Neither zstd nor gzip is available on the device, streaming uncompressed".yellow()),
+            (false, _, Some(algorithm)) => info!("Compressing the transfer with {algorithm}"),
+            (false, _, None) => say!(
+                "{}",
+                "adb pull can't compress with this adb or device, copying uncompressed. It needs platform-tools 34 or later".yellow()
+            ),
+        }
+    }
+    let checksum = args.verify.map(|_| args.hash);
+    if let Some(algorithm) = checksum {
+        if !adb.has_command(algorithm.command()) {
+            return Err(anyhow!(
+                "`{}` isn't available on the device, choose another algorithm with --hash",
+                algorithm.command()
+            ));
+        }
+        info!("Verifying the files with {}", algorithm.name());
+    }
+    let units = plan_pulls(&files, tar_stream);
+    let dir_units = units.iter().filter(|unit| matches!(unit, PullUnit::Dir { .. })).count();
+    if dir_units > 0 {
+        debug(1, format!("{dir_units} folders are pulled whole, as all their files are copied"));
+    }
+    let device_stats = std::mem::take(&mut files.stats);
+    let files: Vec<FileEntry> = files.into_iter().collect();
+    let mut last_with_dest: HashMap<&Path, usize> = HashMap::new();
+    let previous_same_dest = files
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| last_with_dest.insert(entry.dest.as_path(), index))
+        .collect();
+    let mut pulls = Pulls {
+        adb: &adb,
+        args: &args,
+        progress: &progress,
+        pb: &pb,
+        files: &files,
+        units,
+        file_sizes: &file_sizes,
+        count_bytes: sizes.is_some(),
+        message_width,
+        rate_window: &rate_window,
+        next: AtomicUsize::new(0),
+        device_lost: AtomicBool::new(false),
+        reconnecting: Mutex::new(()),
+        stream_compressor,
+        received_bytes: AtomicU64::new(0),
+        unpacked_bytes: AtomicU64::new(0),
+        previous_same_dest,
+        checksum,
+        finished: Mutex::new(HashSet::new()),
+        finished_changed: Condvar::new(),
+    };
+
+    let mut cleanup = args.move_files.then(|| DeviceCleanup::new(&adb));
+
+    // From now on Ctrl-C lets the files being pulled finish and writes the reports before exiting
+    interrupt::install();
+
+    // The files in the order their outcomes are handled: all of them, then those pulled again with --retry-failed-pass
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    let mut retrying = false;
+    let mut handled = 0;
+    let mut device_lost = false;
+    loop {
+        let mut failed_once = Vec::new();
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            for _ in 0..args.jobs {
+                let sender = sender.clone();
+                scope.spawn(|| pulls.work(sender));
+            }
+            drop(sender);
+
+            // The pulls are handled in the order of the files, whichever ends first, so that the done and
+            // failed files, the manifest and the events don't depend on the timing of the jobs
+            let mut pending: BTreeMap<usize, PullOutcome> = BTreeMap::new();
+            let mut position = 0;
+            'receiving: for received in receiver.into_iter().map(Some).chain([None]) {
+                let over = match received {
+                    Some((pulled, outcome)) => {
+                        pending.insert(pulled, outcome);
+                        false
+                    }
+                    None => true,
+                };
+                while let Some(&index) = order.get(position) {
+                    // Once the jobs are over, e.g. after Ctrl-C, the files never pulled are skipped
+                    let Some(outcome) = pending.remove(&index) else {
+                        if over {
+                            position += 1;
+                            continue;
+                        }
+                        break;
+                    };
+                    position += 1;
+                    let FileEntry {
+                        src: src_file,
+                        dest: dest_file,
+                        root_src,
+                    } = &files[index];
+                    if source_totals.len() > 1 && !retrying && source_progress.as_ref().is_none_or(|source| source.root_src != *root_src) {
+                        if let Some(source) = source_progress.take() {
+                            source.finish();
+                        }
+                        let (source_files, source_bytes) = source_totals[root_src];
+                        let len = if sizes.is_some() { source_bytes } else { source_files as u64 };
+                        source_progress = Some(SourceProgress::new(&progress, root_src, source_files, len, sizes.is_some()));
+                    }
+                    if progress_lines && last_progress_line.elapsed() >= Duration::from_secs(args.progress_interval) {
+                        let total = summary.total();
+                        let rate = HumanBytes((total.bytes as f64 / total.elapsed.as_secs_f64().max(1e-3)) as u64);
+                        eprintln!("{handled}/{files_count} files, {}, {rate}/s", HumanBytes(total.bytes));
+                        last_progress_line = Instant::now();
+                    }
+                    handled += 1;
+
+                    match outcome.result {
+                        PullResult::NoDirectory(_) | PullResult::Failed { .. } if args.retry_failed_pass && !retrying => {
+                            debug(1, format!("Failed to copy {:?}, trying again at the end", src_file));
+                            failed_once.push(index);
+                        }
+                        PullResult::Copied { bytes, checksum } => 'copied: {
+                            if !args.no_metadata {
+                                restore_mtime(src_file, dest_file.as_path(), device_stats.get(src_file));
+                            }
+                            // With --organize-by-date exif the date of the file can only be read now
+                            let organized = match args.organize_by_date {
+                                Some(OrganizeBy::Exif) => {
+                                    organize_by_capture_date(dest_file.as_path(), &args.source_dest(root_src), args.on_conflict())
+                                }
+                                _ => Ok(Organized::Kept),
+                            };
+                            let dest_file = match organized {
+                                Ok(Organized::Moved(dest)) => BasePathBuf::new(dest).unwrap(),
+                                Ok(Organized::Kept) => dest_file.clone(),
+                                Ok(Organized::Undated) => {
+                                    summary.undated += 1;
+                                    dest_file.clone()
+                                }
+                                Ok(Organized::AlreadyThere(existing)) => {
+                                    log(format!("{:?} is already in {:?}, removing the copy", src_file, existing));
+                                    summary.skipped += 1;
+                                    reports.record(src_file, Some(&existing), Status::SkippedExisting, None, None);
+                                    emit_file_done(src_file, &existing, 0, None);
+                                    break 'copied;
+                                }
+                                Err(err) => {
+                                    print_above(
+                                        &progress,
+                                        format!("Unable to move {:?} to the folder of its date: {err}", dest_file.as_path()).yellow(),
+                                    );
+                                    dest_file.clone()
+                                }
+                            };
+                            log(format!(
+                                "Copied {:?} to {:?}, {} in {:.2?}",
+                                src_file,
+                                dest_file.as_path(),
+                                HumanBytes(bytes),
+                                outcome.elapsed
+                            ));
+                            summary.add(root_src, Some(bytes), outcome.elapsed);
+                            summary.repaired += damaged.contains(src_file) as usize;
+                            rate_window.lock().unwrap().add(bytes, outcome.elapsed);
+                            emit_file_done(src_file, dest_file.as_path(), bytes, None);
+                            reports.done(src_file, dest_file.as_path(), checksum);
+                            if let Some(cleanup) = cleanup.as_mut() {
+                                cleanup.add(src_file, root_src, bytes, &mut reports, &mut summary);
+                            }
+                        }
+                        PullResult::NoDirectory(err) => {
+                            say!(
+                                "Error in creating directory: \"{}\". Skipping file: {} \nErr:{err}",
+                                dest_file.parent().unwrap().unwrap().as_path().display(),
+                                src_file.display(),
+                            );
+                            let failure = PullFailure::LocalIo(err.to_string());
+                            reports.failed(src_file, dest_file.as_path(), &failure, 1, "");
+                            emit_file_done(src_file, dest_file.as_path(), 0, Some(&failure));
+                            summary.add(root_src, None, outcome.elapsed);
+                        }
+                        PullResult::Failed { failure, stderr, attempts } => {
+                            print_above(
+                                &progress,
+                                format!("Failed to copy {:?}: {}", src_file, with_attempts(&failure, attempts)).red(),
+                            );
+                            if !stderr.trim().is_empty() {
+                                print_above(&progress, stderr.trim());
+                            }
+                            summary.add(root_src, None, outcome.elapsed);
+                            reports.failed(src_file, dest_file.as_path(), &failure, attempts, &stderr);
+                            emit_file_done(src_file, dest_file.as_path(), 0, Some(&failure));
+                        }
+                        PullResult::Interrupted => {
+                            log(format!("Interrupted while copying {:?}", src_file));
+                        }
+                        PullResult::DeviceLost { stderr } => {
+                            pb.abandon();
+                            say!(
+                                "{}",
+                                format!("The device didn't come back within {} seconds. Aborting..", args.reconnect_timeout).red()
+                            );
+                            reports.failed(src_file, dest_file.as_path(), &PullFailure::DeviceOffline, 1, &stderr);
+                            emit_file_done(src_file, dest_file.as_path(), 0, Some(&PullFailure::DeviceOffline));
+                            summary.add(root_src, None, outcome.elapsed);
+                            log(format!("Interrupted: the device didn't come back within {} seconds", args.reconnect_timeout));
+                            // The jobs stop once they see the device is lost
+                            device_lost = true;
+                            break 'receiving;
+                        }
+                    }
+                    // The part of the file reported by adb pull is already on the bar
+                    if let Some(sizes) = &sizes {
+                        pb.inc(sizes[index].saturating_sub(outcome.reported));
+                    }
+                    if let Some(source) = source_progress.as_mut() {
+                        source.file_done(sizes.as_ref().map_or(1, |sizes| sizes[index]));
+                    }
+                }
+            }
+        });
+
+        if device_lost {
+            summary.compressed = pulls.compression_totals();
+            reports.finish_checksums();
+            summary.print();
+            reports.print_summary();
+            disconnect_if_requested(&adb, &args);
+            return Ok(ExitStatus::DeviceLost);
+        }
+        if retrying || failed_once.is_empty() || interrupt::interrupted() {
+            break;
+        }
+        if !args.quiet {
+            print_above(&progress, format!("Retrying {} failed files...", failed_once.len()));
+        }
+        if let Some(source) = source_progress.take() {
+            source.finish();
+        }
+        // The bar grows by the files pulled again, so that the ETA stays sensible
+        pb.inc_length(match &sizes {
+            Some(sizes) => failed_once.iter().map(|index| sizes[*index]).sum(),
+            None => failed_once.len() as u64,
+        });
+        files_count += failed_once.len();
+        // The files pulled again wait only for the ones with the same destination that are pulled again too
+        let mut finished = pulls.finished.lock().unwrap();
+        for index in &failed_once {
+            finished.remove(index);
+        }
+        drop(finished);
+        pulls = Pulls {
+            units: failed_once.iter().map(|index| PullUnit::File(*index)).collect(),
+            next: AtomicUsize::new(0),
+            ..pulls
+        };
+        order = failed_once;
+        retrying = true;
+    }
+
+    if let Some(source) = source_progress {
+        source.finish();
+    }
+    summary.compressed = pulls.compression_totals();
+    if interrupt::interrupted() {
+        pb.abandon_with_message(for_stream("interrupted".red().to_string(), true));
+        say!("{}", "Interrupted with Ctrl-C. Run again to copy the remaining files".yellow());
+        log("Interrupted with Ctrl-C");
+        reports.finish_checksums();
+        if let Some(cleanup) = cleanup {
+            cleanup.finish(args.prune_empty_dirs, &mut reports, &mut summary);
+        }
+        summary.print();
+        reports.print_summary();
+        disconnect_if_requested(&adb, &args);
+        return Ok(ExitStatus::Interrupted);
+    }
+    pb.finish();
+    reports.finish_checksums();
+    if let Some(cleanup) = cleanup {
+        cleanup.finish(args.prune_empty_dirs, &mut reports, &mut summary);
+    }
+    summary.created_dirs = create_empty_dirs(&empty_dirs);
+
+    info!(
+        "Done! Successfully copied {} files from device \"{}\".",
+        reports.done_count,
+        adb.serial.as_deref().unwrap_or_default(),
+    );
+    summary.print();
+
+    reports.print_summary();
+
+    disconnect_if_requested(&adb, &args);
+
+    if reports.failed_count > 0 {
+        return Ok(ExitStatus::PartialFailure);
+    }
+    Ok(ExitStatus::Success)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Some files of a device, from different folders
+    const FILES: &[&str] = &[
+        "/sdcard/DCIM/Camera/IMG_0001.jpg",
+        "/sdcard/DCIM/Camera/VID_0002.mp4",
+        "/sdcard/DCIM/Screenshots/Screenshot_0003.png",
+        "/sdcard/Pictures/WhatsApp/IMG-0004.jpg",
+    ];
+
+    /// The command line with the arguments `args` after a source
+    fn parse_unchecked(args: &[&str]) -> Cli {
+        let mut argv = vec!["adbpuller", "-s", "/sdcard"];
+        argv.extend_from_slice(args);
+        Cli::try_parse_from(argv).unwrap()
+    }
+
+    /// The command line with the arguments `args` after a source, with its filters compiled
+    fn parse(args: &[&str]) -> Cli {
+        let mut cli = parse_unchecked(args);
+        cli.check_filters().unwrap();
+        cli
+    }
+
+    /// The paths of `files` kept by the filters of the command line with the arguments `args`
+    fn filtered(args: &[&str], files: &[&str]) -> Vec<String> {
+        let cli = parse(args);
+        let target = match cli.match_basename {
+            true => MatchTarget::FileName,
+            false => MatchTarget::FullPath,
+        };
+        let files = files.iter().map(UnixPathBuf::from).collect();
+        apply_filters(files, &cli.filters, &SkipList::default(), target, None)
+            .iter()
+            .map(|file| file.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    /// The names of the files to copy
+    fn file_names(files: &SrcDestFiles) -> Vec<String> {
+        files
+            .src_files()
+            .map(|file| file.file_name().unwrap().to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn without_patterns_every_file_is_kept() {
+        assert_eq!(filtered(&[], FILES), FILES);
+    }
+
+    #[test]
+    fn include_keeps_only_the_matching_files() {
+        assert_eq!(filtered(&["--include=\\.jpg$"], FILES), [FILES[0], FILES[3]]);
+        assert_eq!(
+            filtered(&["--include=\\.jpg$", "--include=Screenshots"], FILES),
+            [FILES[0], FILES[2], FILES[3]]
+        );
+    }
+
+    #[test]
+    fn exclude_removes_the_matching_files() {
+        assert_eq!(filtered(&["--exclude=Screenshots"], FILES), [FILES[0], FILES[1], FILES[3]]);
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        assert_eq!(filtered(&["--include=\\.jpg$", "--exclude=WhatsApp"], FILES), [FILES[0]]);
+        assert!(filtered(&["--include=DCIM", "--exclude=DCIM"], FILES).is_empty());
+    }
+
+    #[test]
+    fn ignore_case_matches_regexes_and_globs_in_any_case() {
+        let files = ["/sdcard/DCIM/PHOTO.JPG", "/sdcard/DCIM/photo.jpg", "/sdcard/DCIM/video.mp4"];
+        assert_eq!(filtered(&["--include=\\.jpg$"], &files), [files[1]]);
+        assert_eq!(filtered(&["-i", "--include=\\.jpg$"], &files), [files[0], files[1]]);
+        assert_eq!(filtered(&["--ignore-case", "--include-glob=*.jpg"], &files), [files[0], files[1]]);
+        assert_eq!(filtered(&["-i", "--exclude=PHOTO"], &files), [files[2]]);
+    }
+
+    #[test]
+    fn patterns_match_the_full_path_or_the_file_name_with_match_basename() {
+        // The arguments, then the files kept matching the full path and matching the file name
+        let cases: &[(&str, &[&str], &[&str])] = &[
+            ("--include=^IMG", &[], &[FILES[0], FILES[3]]),
+            ("--include=Camera", &[FILES[0], FILES[1]], &[]),
+            ("--include=\\.jpg$", &[FILES[0], FILES[3]], &[FILES[0], FILES[3]]),
+            ("--exclude-glob=IMG_*", &[FILES[1], FILES[2], FILES[3]], &[FILES[1], FILES[2], FILES[3]]),
+            ("--include-glob=/sdcard/DCIM/**", &[FILES[0], FILES[1], FILES[2]], &[]),
+        ];
+        for (arg, full_path, file_name) in cases {
+            assert_eq!(filtered(&[*arg], FILES), *full_path, "{arg} on {}", MatchTarget::FullPath);
+            assert_eq!(
+                filtered(&[*arg, "--match-basename"], FILES),
+                *file_name,
+                "{arg} on {}",
+                MatchTarget::FileName
+            );
+        }
+    }
+
+    #[test]
+    fn globs_and_regexes_are_combined() {
+        // A file is included by either kind of pattern, and excluded by either
+        assert_eq!(
+            filtered(&["--include-glob=Camera/*.mp4", "--include=Screenshot"], FILES),
+            [FILES[1], FILES[2]]
+        );
+        assert_eq!(filtered(&["--include=\\.jpg$", "--exclude-glob=WhatsApp/**"], FILES), [FILES[0]]);
+        assert_eq!(filtered(&["--include-glob=*.jpg", "--exclude=^/sdcard/Pictures/"], FILES), [FILES[0]]);
+        assert_eq!(filtered(&["--exclude-glob=*.png", "--exclude=VID_"], FILES), [FILES[0], FILES[3]]);
+    }
+
+    #[test]
+    fn hidden_files_and_files_in_hidden_folders() {
+        let in_hidden_dir = UnixPath::new("/sdcard/DCIM/.thumbnails/123.jpg");
+        assert!(is_hidden(in_hidden_dir, HiddenMode::All));
+        assert!(is_hidden(in_hidden_dir, HiddenMode::Dirs));
+
+        let hidden_file = UnixPath::new("/sdcard/DCIM/Camera/.pending-IMG_0001.jpg");
+        assert!(is_hidden(hidden_file, HiddenMode::All));
+        assert!(!is_hidden(hidden_file, HiddenMode::Dirs));
+
+        let same_name_as_hidden_dir = UnixPath::new("/sdcard/.cache/.cache");
+        assert!(is_hidden(same_name_as_hidden_dir, HiddenMode::All));
+        assert!(is_hidden(same_name_as_hidden_dir, HiddenMode::Dirs));
+
+        let visible = UnixPath::new("/sdcard/DCIM/Camera/IMG_0001.jpg");
+        assert!(!is_hidden(visible, HiddenMode::All));
+        assert!(!is_hidden(visible, HiddenMode::Dirs));
+    }
+
+    #[test]
+    fn default_excludes_match_junk_but_not_legitimate_files() {
+        let junk = [
+            "/sdcard/DCIM/.thumbnails/123.jpg",
+            "/sdcard/DCIM/Camera/.trashed-1712345678-IMG_0001.jpg",
+            "/sdcard/.Trash/IMG_0001.jpg",
+            "/sdcard/Pictures/.globalTrash/IMG_0001.jpg",
+            "/sdcard/Android/data/com.example/cache/image.tmp",
+            "/sdcard/Movies/Cache/part.mp4",
+            "/sdcard/Music/.cache/cover.jpg",
+        ];
+        let legitimate = [
+            "/sdcard/DCIM/Camera/IMG_0001.jpg",
+            "/sdcard/DCIM/thumbnails.jpg",
+            "/sdcard/Documents/cached.pdf",
+            "/sdcard/Music/Trash Talk.mp3",
+            "/sdcard/Pictures/trashed-car.jpg",
+        ];
+
+        let default_excludes = parse(&[]).filters.default_excludes;
+        for file in junk {
+            assert!(default_excludes.is_match(file), "{file}");
+        }
+        for file in legitimate {
+            assert!(!default_excludes.is_match(file), "{file}");
+        }
+        assert_eq!(filtered(&["--no-default-excludes"], &junk), junk);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sources_inside_or_aliasing_another_are_dropped() {
+        use crate::adb::tests::{local_shell_adb, test_dir};
+
+        let dir = test_dir("collapse-sources");
+        let adb = local_shell_adb(&dir);
+        let storage = dir.join("storage/emulated/0");
+        fs::create_dir_all(storage.join("DCIM/Camera")).unwrap();
+        fs::create_dir_all(storage.join("Pictures")).unwrap();
+        fs::write(storage.join("Pictures/a.jpg"), "").unwrap();
+        std::os::unix::fs::symlink(&storage, dir.join("sdcard")).unwrap();
+        let source = |path: &str, kind: SourceKind| (UnixPathBuf::from(dir.join(path).to_str().unwrap()), kind);
+
+        let sources = vec![
+            source("sdcard/DCIM", SourceKind::Directory),
+            source("storage/emulated/0/DCIM/Camera", SourceKind::Directory),
+            source("storage/emulated/0/DCIM", SourceKind::Directory),
+            source("sdcard/Pictures/a.jpg", SourceKind::File),
+            source("sdcard/Pictures", SourceKind::Directory),
+        ];
+        let kept = [
+            source("sdcard/DCIM", SourceKind::Directory),
+            source("sdcard/Pictures", SourceKind::Directory),
+        ];
+        assert_eq!(collapse_sources(&adb, sources), kept);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn dry_run_listing_and_messages_have_no_colors_where_disabled() {
+        let cli = parse(&["--color", "never", "-n"]);
+        cli.color.apply();
+        let mut files = SrcDestFiles::new();
+        for (name, size) in [("a.jpg", 2048), ("b.mp4", 5 << 20)] {
+            let src_file = UnixPathBuf::from("/sdcard/DCIM").join(name);
+            files.stats.insert(
+                src_file.clone(),
+                FileStat {
+                    size,
+                    mtime: 1_700_000_000,
+                    mtime_nanos: 0,
+                },
+            );
+            let dest = BasePathBuf::new(PathBuf::from("/backup/DCIM").join(name)).unwrap();
+            files.push(src_file, dest, UnixPathBuf::from("/sdcard/DCIM"));
+        }
+
+        let mut out: Vec<u8> = Vec::new();
+        write_listing(&mut out, &files, &cli).unwrap();
+        write_long_listing(&mut out, &files, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("/sdcard/DCIM/a.jpg  ->  "), "{out}");
+        assert!(!out.contains('\x1b'), "{out:?}");
+
+        // With --color auto from a terminal, as in `adbpuller -n 2>run.log`, only the listing on stdout is colored
+        ColorMode::Auto.apply_to(true, false);
+        let message = for_stream(format!("{}", "Failed to copy".red()), true);
+        assert_eq!(message, "Failed to copy");
+        if env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) {
+            let mut out: Vec<u8> = Vec::new();
+            write_listing(&mut out, &files, &cli).unwrap();
+            assert!(String::from_utf8(out).unwrap().contains('\x1b'));
+        }
+        ColorMode::Never.apply();
+    }
+
+    #[test]
+    fn files_of_another_size_or_time_in_the_destination_are_copied_again() {
+        let dir = crate::adb::tests::test_dir("requeue-changed");
+        let adb = Adb::new(PathBuf::from("adb"), None, None, None);
+        let mtime = 1_700_000_000;
+        let root_src = UnixPath::new("/sdcard/DCIM");
+        // The name, the size of the file in the destination, and its size and time on the device
+        let existing = [
+            ("same.jpg", 4, 4, mtime),
+            ("truncated.jpg", 2, 4, mtime),
+            ("edited.jpg", 4, 4, mtime + 3600),
+        ];
+        let mut stats = HashMap::new();
+        let mut files = SrcDestFiles::new();
+        for (name, local_size, size, device_mtime) in existing {
+            let dest = dir.join(name);
+            fs::write(&dest, vec![0u8; local_size]).unwrap();
+            filetime::set_file_mtime(&dest, filetime::FileTime::from_unix_time(mtime, 0)).unwrap();
+            stats.insert(
+                root_src.join(name),
+                FileStat {
+                    size,
+                    mtime: device_mtime,
+                    mtime_nanos: 0,
+                },
+            );
+            files.existing.push((root_src.join(name), BasePathBuf::new(dest).unwrap()));
+        }
+
+        let mut by_size = SrcDestFiles::new();
+        by_size.existing = files.existing.clone();
+        requeue_changed(&adb, &mut by_size, root_src, Some(&stats), false);
+        assert_eq!(file_names(&by_size), ["truncated.jpg"]);
+        assert_eq!(by_size.entries[0].root_src, root_src);
+        assert_eq!(by_size.up_to_date, 2);
+
+        requeue_changed(&adb, &mut files, root_src, Some(&stats), true);
+        assert_eq!(file_names(&files), ["truncated.jpg", "edited.jpg"]);
+        assert_eq!(files.up_to_date, 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn collision_keys_ignore_case_where_the_file_systems_do() {
+        let key = |path: &str| collision_key(Path::new(path));
+        let case_insensitive = cfg!(any(windows, target_os = "macos"));
+        assert_eq!(key("/backup/DCIM/IMG_0001.JPG") == key("/backup/DCIM/img_0001.jpg"), case_insensitive);
+        assert_eq!(key("/backup/Été/a.jpg") == key("/backup/été/A.JPG"), case_insensitive);
+        assert_eq!(key("/backup/DCIM/a.jpg"), key("/backup/DCIM/a.jpg"));
+        assert_ne!(key("/backup/DCIM/a.jpg"), key("/backup/DCIM/b.jpg"));
+    }
+
+    #[test]
+    fn files_keep_their_device_path_from_the_name_of_the_source_by_default() {
+        let cli = parse(&[]);
+        let relative = |file: &str, root_src: &str| relative_destination(UnixPath::new(file), UnixPath::new(root_src), &cli, None).unwrap();
+        let path = |path: &str| Some(UnixPathBuf::from(path));
+        assert_eq!(relative("/sdcard/DCIM/Camera/a.jpg", "/sdcard/DCIM"), path("DCIM/Camera/a.jpg"));
+        assert_eq!(relative("/sdcard/DCIM/a.jpg", "/sdcard/DCIM/a.jpg"), path("a.jpg"));
+        assert_eq!(relative("/sdcard/DCIM/a.jpg", "/"), path("sdcard/DCIM/a.jpg"));
+        assert_eq!(
+            kept_device_path(UnixPath::new("/sdcard/DCIM"), UnixPath::new("/sdcard/DCIM"), &cli),
+            path("DCIM")
+        );
+
+        let cli = parse(&["--strip-components", "2"]);
+        assert_eq!(
+            kept_device_path(UnixPath::new("/sdcard/DCIM/Camera/a.jpg"), UnixPath::new("/sdcard/DCIM"), &cli),
+            path("Camera/a.jpg")
+        );
+        // The example of the help of --strip-components
+        let cli = parse(&["--strip-components", "4"]);
+        let whatsapp_images = UnixPath::new("/sdcard/Android/media/com.whatsapp/WhatsApp/Media/WhatsApp Images");
+        assert_eq!(
+            kept_device_path(whatsapp_images, whatsapp_images, &cli),
+            path("WhatsApp/Media/WhatsApp Images")
+        );
+        let cli = parse(&["--keep-after", "/sdcard"]);
+        assert_eq!(
+            kept_device_path(UnixPath::new("/sdcard/DCIM/Camera/a.jpg"), UnixPath::new("/sdcard/DCIM"), &cli),
+            path("DCIM/Camera/a.jpg")
+        );
+    }
+
+    #[test]
+    fn sorting_by_size_leaves_the_files_of_unknown_size_last() {
+        for reverse in [false, true] {
+            let mut files = SrcDestFiles::new();
+            for (name, size) in [
+                ("big.jpg", Some(300)),
+                ("small.jpg", Some(100)),
+                ("unknown.jpg", None),
+                ("mid.jpg", Some(200)),
+            ] {
+                let src_file = UnixPathBuf::from("/sdcard/DCIM").join(name);
+                if let Some(size) = size {
+                    files.stats.insert(
+                        src_file.clone(),
+                        FileStat {
+                            size,
+                            mtime: 0,
+                            mtime_nanos: 0,
+                        },
+                    );
+                }
+                let dest = BasePathBuf::new(PathBuf::from("/backup/DCIM").join(name)).unwrap();
+                files.push(src_file, dest, UnixPathBuf::from("/sdcard/DCIM"));
+            }
+            sort_files(&mut files, SortBy::Size, reverse);
+
+            let mut sorted = vec!["small.jpg", "mid.jpg", "big.jpg"];
+            if reverse {
+                sorted.reverse();
+            }
+            sorted.push("unknown.jpg");
+            assert_eq!(file_names(&files), sorted);
+        }
+    }
+
+    #[test]
+    fn dest_template_gives_the_device_valid_folder_names() {
+        let mut template = DestTemplate::parse("{device_model}/{device_serial}/{date}/{rel_path}").unwrap();
+        template.fill_run("Galaxy: \"S24\"/Ultra.", "192.168.1.5:5555", 1_700_000_000, '_');
+
+        let expanded = template.expand("DCIM", UnixPath::new("Camera/a.jpg"), None);
+        assert_eq!(
+            expanded,
+            UnixPathBuf::from("Galaxy_ _S24__Ultra/192.168.1.5_5555/2023-11-14/Camera/a.jpg")
+        );
+    }
+
+    /// The error of check_filters for the command line with the arguments `args` after a source
+    fn filters_error(args: &[&str]) -> Option<String> {
+        parse_unchecked(args).check_filters().err().map(|err| err.to_string())
+    }
+
+    #[test]
+    fn valid_patterns_are_accepted() {
+        assert_eq!(filters_error(&["--include=^/sdcard/DCIM/.*\\.jpg$", "--exclude-glob=**/Sent/**"]), None);
+    }
+
+    #[test]
+    fn an_invalid_pattern_is_reported_with_its_flag_and_number() {
+        let error = filters_error(&["--include=Camera", "--include=(unclosed"]).unwrap();
+        assert!(error.starts_with("Invalid --include pattern #2 \"(unclosed\""), "{error}");
+        let error = filters_error(&["--exclude-glob=[unclosed"]).unwrap();
+        assert!(error.starts_with("Invalid --exclude-glob pattern #1 \"[unclosed\""), "{error}");
+    }
+
+    #[test]
+    fn all_the_invalid_patterns_are_reported_at_once() {
+        let args = [
+            "--include=\\.jpg$",
+            "--include=(",
+            "--exclude=[",
+            "--include-glob=*.jpg",
+            "--exclude-glob=a[b",
+        ];
+        let error = filters_error(&args).unwrap();
+        assert!(error.contains("Invalid --include pattern #2"), "{error}");
+        assert!(error.contains("Invalid --exclude pattern #1"), "{error}");
+        assert!(error.contains("Invalid --exclude-glob pattern #1"), "{error}");
+        assert!(!error.contains("--include pattern #1"), "{error}");
+        assert!(!error.contains("--include-glob"), "{error}");
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use unix_path::{Path as UnixPath, PathBuf as UnixPathBuf};
+
+use crate::listing::FileStat;
+use crate::OnConflict;
+
+/// Bumped whenever a field is renamed or removed, or its meaning changes. Adding fields doesn't
+/// change it
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// What happened to a file considered by the run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Copied,
+    /// The destination already existed and wasn't copied again
+    SkippedExisting,
+    /// Another file of the run had the same destination, with --on-collision skip
+    SkippedCollision,
+    /// Already anywhere under the folder of --dedupe-against, which is given as destination
+    SkippedDuplicate,
+    /// Left out by --limit or --max-total-size
+    SkippedLimit,
+    Failed,
+    /// Excluded by a filter, --skip, --skip-path or --resume
+    Filtered,
+}
+
+/// A single file considered by the run, shared by the JSON and the CSV manifest
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    pub source_path: String,
+    pub dest_path: Option<String>,
+    pub size_bytes: Option<u64>,
+    /// Last modification time on the device, in seconds since the Unix epoch
+    pub device_mtime: Option<i64>,
+    pub status: Status,
+    pub error: Option<String>,
+    /// The hash checked with --verify checksum, as `algorithm:hex`
+    pub checksum: Option<String>,
+    /// What was done with the destination, if it already existed
+    pub conflict: Option<OnConflict>,
+    /// The destination before the name was made valid on Windows, if it changed
+    pub original_dest_path: Option<String>,
+}
+
+/// The fields of [`Record`], in order
+const CSV_HEADER: [&str; 9] = [
+    "source_path",
+    "dest_path",
+    "size_bytes",
+    "device_mtime",
+    "status",
+    "error",
+    "checksum",
+    "conflict",
+    "original_dest_path",
+];
+
+/// What the run was asked to do
+#[derive(Debug, Serialize)]
+pub struct RunInfo {
+    /// When the run started, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub device_serial: Option<String>,
+    pub sources: Vec<String>,
+    pub destination: String,
+    /// The command line arguments, without the program name
+    pub arguments: Vec<String>,
+}
+
+/// Where the records are written
+enum Output {
+    Json { path: PathBuf, file: BufWriter<File>, entries: usize },
+    Csv { path: PathBuf, writer: Box<csv::Writer<File>> },
+}
+
+/// Writes the manifests one entry at a time, so that huge runs don't need to keep every record in
+/// memory. Every record is built once and written to all the manifests, so they can't disagree
+pub struct Manifest {
+    stats: HashMap<UnixPathBuf, FileStat>,
+    conflicts: HashMap<UnixPathBuf, OnConflict>,
+    original_dests: HashMap<UnixPathBuf, PathBuf>,
+    outputs: Vec<Output>,
+}
+
+impl Manifest {
+    /// `stats` are used to fill in the size and modification time of the files, `conflicts` what is done
+    /// with the destinations which already exist of the files to copy, `original_dests` the destinations
+    /// of the files whose names were sanitized
+    pub fn new(
+        stats: HashMap<UnixPathBuf, FileStat>,
+        conflicts: HashMap<UnixPathBuf, OnConflict>,
+        original_dests: HashMap<UnixPathBuf, PathBuf>,
+    ) -> Self {
+        Self {
+            stats,
+            conflicts,
+            original_dests,
+            outputs: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    /// Creates the JSON manifest at `path`, overwriting it, and writes the run metadata
+    pub fn add_json(&mut self, path: &Path, run: &RunInfo) -> Result<()> {
+        let mut file = BufWriter::new(create_file(path)?);
+        let run = serde_json::to_string(run)?;
+        write!(file, "{{\"schema_version\":{SCHEMA_VERSION},\"run\":{run},\"entries\":[")
+            .with_context(|| format!("Unable to write the manifest {:?}", path))?;
+        self.outputs.push(Output::Json {
+            path: path.to_path_buf(),
+            file,
+            entries: 0,
+        });
+        Ok(())
+    }
+
+    /// Creates the CSV manifest at `path`, overwriting it, and writes the header
+    pub fn add_csv(&mut self, path: &Path) -> Result<()> {
+        // The header is written here rather than with the first record, so that it's there even without records
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(create_file(path)?);
+        writer.write_record(CSV_HEADER).with_context(|| format!("Unable to write the manifest {:?}", path))?;
+        self.outputs.push(Output::Csv {
+            path: path.to_path_buf(),
+            writer: Box::new(writer),
+        });
+        Ok(())
+    }
+
+    /// Builds the record of `source` and appends it to the manifests
+    pub fn write(&mut self, source: &UnixPath, dest: Option<&Path>, status: Status, error: Option<String>, checksum: Option<String>) -> Result<()> {
+        let stat = self.stats.get(source);
+        let record = Record {
+            source_path: source.to_str().unwrap().to_string(),
+            dest_path: dest.map(|dest| dest.to_string_lossy().into_owned()),
+            size_bytes: stat.map(|stat| stat.size),
+            device_mtime: stat.map(|stat| stat.mtime),
+            status,
+            error,
+            checksum,
+            conflict: match status {
+                Status::SkippedExisting => Some(OnConflict::Skip),
+                _ => self.conflicts.get(source).copied(),
+            },
+            original_dest_path: self.original_dests.get(source).map(|dest| dest.to_string_lossy().into_owned()),
+        };
+
+        for output in self.outputs.iter_mut() {
+            match output {
+                Output::Json { path, file, entries } => {
+                    let separator: &[u8] = if *entries > 0 { b",\n" } else { b"\n" };
+                    file.write_all(separator)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| Ok(serde_json::to_writer(&mut *file, &record)?))
+                        .with_context(|| format!("Unable to write the manifest {:?}", path))?;
+                    *entries += 1;
+                }
+                Output::Csv { path, writer } => {
+                    writer.serialize(&record).with_context(|| format!("Unable to write the manifest {:?}", path))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes and flushes the manifests, returning their paths
+    pub fn finish(self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for output in self.outputs {
+            match output {
+                Output::Json { path, mut file, .. } => {
+                    file.write_all(b"\n]}\n")
+                        .and_then(|_| file.flush())
+                        .with_context(|| format!("Unable to write the manifest {:?}", path))?;
+                    paths.push(path);
+                }
+                Output::Csv { path, mut writer } => {
+                    writer.flush().with_context(|| format!("Unable to write the manifest {:?}", path))?;
+                    paths.push(path);
+                }
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Creates the file at `path` together with its parent folders, overwriting it
+fn create_file(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).with_context(|| format!("Unable to create {:?}", parent))?;
+    }
+    File::create(path).with_context(|| format!("Unable to create the manifest {:?}", path))
+}
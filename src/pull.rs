@@ -0,0 +1,967 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use filetime::FileTime;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar};
+use unix_path::{Path as UnixPath, PathBuf as UnixPathBuf};
+
+use crate::adb::{kill_after, Adb, Killed, PullFailure, StreamCompressor};
+use crate::checksum::{device_hashes, local_hash, HashAlgorithm};
+use crate::events::{self, Event};
+use crate::interrupt;
+use crate::listing::{get_file_stats, FileStat};
+use crate::logging::{debug, log};
+use crate::{backup_path, escape_path, print_above, Cli, FileEntry, OnConflict, SrcDestFiles};
+
+/// How many of the last files copied the transfer rate shown while copying is averaged over
+const RATE_WINDOW_FILES: usize = 20;
+
+/// Transfer rate averaged over the last files copied, so that a single slow file doesn't skew it
+pub struct RateWindow {
+    samples: VecDeque<(u64, Duration)>,
+}
+
+impl RateWindow {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(RATE_WINDOW_FILES),
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64, elapsed: Duration) {
+        if self.samples.len() == RATE_WINDOW_FILES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((bytes, elapsed));
+    }
+
+    /// Bytes per second, None until a file has been copied
+    fn rate(&self) -> Option<u64> {
+        let bytes: u64 = self.samples.iter().map(|(bytes, _)| bytes).sum();
+        let elapsed: Duration = self.samples.iter().map(|(_, elapsed)| *elapsed).sum();
+        (!elapsed.is_zero()).then(|| (bytes as f64 / elapsed.as_secs_f64()) as u64)
+    }
+}
+
+/// Shortens `text` to at most `width` characters, dropping its start, as the end of a path is the
+/// part which tells files apart
+fn truncate_left(text: &str, width: usize) -> String {
+    let length = text.chars().count();
+    if length <= width {
+        return text.to_string();
+    }
+    let kept: String = text.chars().skip(length - width.saturating_sub(1)).collect();
+    format!("…{kept}")
+}
+
+/// Limits of the files streamed by a single tar with --tar-stream, so that its command line stays
+/// well within what the device accepts
+const TAR_BATCH_FILES: usize = 1000;
+const TAR_BATCH_BYTES: usize = 100_000;
+
+/// The wait before the first retry of a failed pull with --retries, doubled at each further attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The wait before pulling again a file whose attempt number `attempt` failed with `failure`, None if it isn't pulled
+/// again as the failure won't go away or the --retries are used up
+fn retry_backoff(failure: &PullFailure, attempt: u32, retries: u32) -> Option<Duration> {
+    (failure.is_retryable() && attempt <= retries).then(|| RETRY_BACKOFF * 2u32.pow((attempt - 1).min(5)))
+}
+
+/// What a job pulls at once. The files are given by their index
+pub enum PullUnit {
+    File(usize),
+    /// A folder on the device, pulled inside `dest_parent` with a single adb pull
+    Dir { src_dir: UnixPathBuf, dest_parent: PathBuf, files: Vec<usize> },
+    /// Files streamed by tar, named relative to `dir`, with --tar-stream
+    Tar { dir: UnixPathBuf, files: Vec<usize> },
+}
+
+/// Splits the files into pulls. With `tar_stream` they are streamed by tar in batches, otherwise the
+/// topmost complete folders are pulled whole, as a single adb pull is much faster than one for each
+/// file. The units are in the order of their first file
+pub fn plan_pulls(files: &SrcDestFiles, tar_stream: bool) -> Vec<PullUnit> {
+    // Files sharing the destination with others are pulled one by one, in order
+    let mut dest_counts: HashMap<&Path, usize> = HashMap::new();
+    for entry in files.entries.iter() {
+        *dest_counts.entry(entry.dest.as_path()).or_default() += 1;
+    }
+    let shared = |index: usize| dest_counts[files.entries[index].dest.as_path()] > 1;
+
+    let mut units: Vec<PullUnit> = Vec::new();
+    if tar_stream {
+        let mut command_length = 0;
+        for (index, FileEntry { src, root_src, .. }) in files.entries.iter().enumerate() {
+            if shared(index) {
+                units.push(PullUnit::File(index));
+                continue;
+            }
+            // The paths are relative to the parent of the source, as they are in the destination
+            let dir = root_src.parent().unwrap_or(root_src);
+            let length = src.as_unix_str().len() - dir.as_unix_str().len() + 5;
+            match units.last_mut() {
+                Some(PullUnit::Tar { dir: batch_dir, files })
+                    if batch_dir == dir && files.len() < TAR_BATCH_FILES && command_length + length <= TAR_BATCH_BYTES =>
+                {
+                    files.push(index);
+                    command_length += length;
+                }
+                _ => {
+                    units.push(PullUnit::Tar {
+                        dir: dir.to_path_buf(),
+                        files: vec![index],
+                    });
+                    command_length = length;
+                }
+            }
+        }
+        return units;
+    }
+
+    // Nor are pulled whole the folders with files too long for adb, which are pulled one by one into a short path
+    let mut shared_dirs: HashSet<&UnixPath> = HashSet::new();
+    for (index, entry) in files.entries.iter().enumerate() {
+        if shared(index) || too_long_for_adb(&with_partial_extension(entry.dest.as_path())) {
+            shared_dirs.extend(entry.src.ancestors().skip(1));
+        }
+    }
+
+    let mut dir_units: HashMap<&UnixPath, usize> = HashMap::new();
+    for (index, FileEntry { src, dest, root_src }) in files.entries.iter().enumerate() {
+        // The matching folder of the destination, found going up as many levels
+        let topmost = src
+            .ancestors()
+            .skip(1)
+            .zip(dest.as_path().ancestors().skip(1))
+            .take_while(|(dir, _)| dir.starts_with(root_src))
+            .filter(|(dir, dest_dir)| {
+                files.complete_dirs.contains(*dir)
+                    && !shared_dirs.contains(dir)
+                    && dir.file_name().is_some()
+                    && dir.file_name().and_then(|name| name.to_str()) == dest_dir.file_name().and_then(|name| name.to_str())
+            })
+            .last();
+
+        match topmost {
+            Some((dir, dest_dir)) => match dir_units.get(dir) {
+                Some(unit) => {
+                    if let PullUnit::Dir { files, .. } = &mut units[*unit] {
+                        files.push(index);
+                    }
+                }
+                None => {
+                    dir_units.insert(dir, units.len());
+                    units.push(PullUnit::Dir {
+                        src_dir: dir.to_path_buf(),
+                        dest_parent: dest_dir.parent().unwrap().to_path_buf(),
+                        files: vec![index],
+                    });
+                }
+            },
+            None => units.push(PullUnit::File(index)),
+        }
+    }
+
+    // A folder with a single file is pulled as the file, so that its progress is shown
+    for unit in units.iter_mut() {
+        if let PullUnit::Dir { files, .. } = unit {
+            if files.len() == 1 {
+                *unit = PullUnit::File(files[0]);
+            }
+        }
+    }
+    units
+}
+
+/// How a pull ended, sent by the jobs to the main thread
+pub enum PullResult {
+    /// `checksum` is the hash checked with --verify checksum, as `algorithm:hex`
+    Copied { bytes: u64, checksum: Option<String> },
+    /// The folder of the destination couldn't be created
+    NoDirectory(io::Error),
+    /// `attempts` counts the pulls of the file, more than one with --retries
+    Failed { failure: PullFailure, stderr: String, attempts: u32 },
+    /// The device dropped and didn't come back within --reconnect-timeout
+    DeviceLost { stderr: String },
+    /// Ctrl-C was pressed and the pull killed before it ended
+    Interrupted,
+}
+
+pub struct PullOutcome {
+    pub result: PullResult,
+    pub elapsed: Duration,
+    /// Bytes of the file already added to the progress bar from the percentage printed by adb pull
+    pub reported: u64,
+}
+
+/// What the --jobs pulling the files share. Each job takes the next unit not taken yet and sends
+/// back how the pull of each file went, leaving the rest of the bookkeeping to the main thread
+pub struct Pulls<'a> {
+    pub adb: &'a Adb,
+    pub args: &'a Cli,
+    pub progress: &'a MultiProgress,
+    pub pb: &'a ProgressBar,
+    pub files: &'a [FileEntry],
+    pub units: Vec<PullUnit>,
+    pub file_sizes: &'a [Option<u64>],
+    /// Whether the bar counts bytes rather than files
+    pub count_bytes: bool,
+    pub message_width: usize,
+    pub rate_window: &'a Mutex<RateWindow>,
+    /// Index of the next unit to pull
+    pub next: AtomicUsize,
+    /// Set once the device didn't come back, to stop taking files
+    pub device_lost: AtomicBool,
+    /// Held while waiting for the device, so that the jobs hitting the same drop wait for it one at a time
+    pub reconnecting: Mutex<()>,
+    /// Compressor of the tar stream, with --compress
+    pub stream_compressor: Option<StreamCompressor>,
+    /// Bytes received from the tar streams, and those of the files unpacked from them
+    pub received_bytes: AtomicU64,
+    pub unpacked_bytes: AtomicU64,
+    /// For each file, the previous one with the same destination, as files of different sources can
+    /// end up in the same one. It's waited for, so that the last file wins as when pulling one at a time
+    pub previous_same_dest: Vec<Option<usize>>,
+    /// The hash compared with --verify checksum
+    pub checksum: Option<HashAlgorithm>,
+    /// The files whose pull ended
+    pub finished: Mutex<HashSet<usize>>,
+    pub finished_changed: Condvar,
+}
+
+impl Pulls<'_> {
+    /// Pulls files until there are none left, sending their outcome with their index
+    pub fn work(&self, sender: mpsc::Sender<(usize, PullOutcome)>) {
+        while !self.device_lost.load(Ordering::Relaxed) && !interrupt::interrupted() {
+            let Some(unit) = self.units.get(self.next.fetch_add(1, Ordering::Relaxed)) else {
+                return;
+            };
+            let mut outcomes = match unit {
+                PullUnit::File(index) => {
+                    self.start(&[*index]);
+                    vec![(*index, self.pull(*index))]
+                }
+                PullUnit::Dir { src_dir, dest_parent, files } => self.pull_dir(src_dir, dest_parent, files),
+                PullUnit::Tar { dir, files } => self.pull_tar(dir, files),
+            };
+            if let Some(algorithm) = self.checksum {
+                self.verify_checksums(algorithm, &mut outcomes);
+            }
+            for outcome in outcomes {
+                if sender.send(outcome).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves the bar, when counting files, and tells that the files are about to be pulled
+    fn start(&self, indices: &[usize]) {
+        // Counting files the bar moves when a file starts, counting bytes while it's copied
+        if !self.count_bytes {
+            self.pb.inc(indices.len() as u64);
+        }
+        for index in indices {
+            let (src_file, dest_file) = (&self.files[*index].src, &self.files[*index].dest);
+            events::emit(&Event::FileStart {
+                source_path: src_file.to_str().unwrap(),
+                dest_path: &dest_file.as_path().to_string_lossy(),
+                size_bytes: self.file_sizes[*index],
+                index: *index,
+                total: self.files.len(),
+            });
+        }
+    }
+
+    fn pull(&self, index: usize) -> PullOutcome {
+        let _finished = FinishedGuard { pulls: self, index };
+        let (src_file, dest_file) = (&self.files[index].src, &self.files[index].dest);
+        let dest_file = dest_file.as_path();
+        let pull_started = Instant::now();
+        let size = self.file_sizes[index];
+        let rate = self.rate_window.lock().unwrap().rate();
+        // The percentage reported by adb pull goes with the size, so that the path makes room for it
+        let render_message = |percent: Option<u8>| {
+            let mut details = Vec::new();
+            match (percent, size) {
+                (Some(percent), Some(size)) => details.push(format!("{percent}% of {}", HumanBytes(size))),
+                (Some(percent), None) => details.push(format!("{percent}%")),
+                (None, Some(size)) => details.push(HumanBytes(size).to_string()),
+                (None, None) => {}
+            }
+            if let Some(rate) = rate {
+                details.push(format!("{}/s avg", HumanBytes(rate)));
+            }
+            let details = if details.is_empty() { String::new() } else { format!(" ({})", details.join(", ")) };
+            let path_width = self.message_width.saturating_sub(details.chars().count()).max(10);
+            format!("{}{details}", truncate_left(&escape_path(src_file.to_str().unwrap()), path_width))
+        };
+        self.pb.set_message(render_message(None));
+
+        let mut reported = 0;
+        let outcome = |result, reported| PullOutcome {
+            result,
+            elapsed: pull_started.elapsed(),
+            reported,
+        };
+
+        if let Err(err) = fs::create_dir_all(dest_file.parent().unwrap()) {
+            return outcome(PullResult::NoDirectory(err), reported);
+        }
+        if let Some(previous) = self.previous_same_dest[index] {
+            let mut finished = self.finished.lock().unwrap();
+            while !finished.contains(&previous) {
+                finished = self.finished_changed.wait(finished).unwrap();
+            }
+        }
+
+        // The file gets its name only once complete, so that a pull which failed or was cut short
+        // doesn't leave it looking copied
+        let partial_file = partial_path(dest_file);
+        if let Err(err) = fs::create_dir_all(partial_file.parent().unwrap()) {
+            return outcome(PullResult::NoDirectory(err), reported);
+        }
+        let mut attempt = 1;
+        loop {
+            let mut last_percent = None;
+            let pulled = self.run_pull(src_file.to_str().unwrap(), &partial_file, self.timeout(1, size.unwrap_or(0)), |percent| {
+                if last_percent == Some(percent) {
+                    return;
+                }
+                last_percent = Some(percent);
+                self.pb.set_message(render_message(Some(percent)));
+                // A pull started again after a reconnection or a failure reports from 0% again
+                if let (true, Some(size)) = (self.count_bytes, size) {
+                    let bytes = size * percent as u64 / 100;
+                    if bytes > reported {
+                        self.pb.inc(bytes - reported);
+                        reported = bytes;
+                    }
+                }
+            });
+            let result = match pulled {
+                Ok(output) if output.status.success() => {
+                    let bytes = fs::metadata(&partial_file).map_or(0, |metadata| metadata.len());
+                    // adb pull may succeed having written only part of the file
+                    match self.size_mismatch(index, bytes).map_or(Ok(()), Err).and_then(|_| {
+                        self.put_in_place(&partial_file, dest_file)
+                            .map_err(|err| PullFailure::LocalIo(err.to_string()))
+                    }) {
+                        Ok(()) => PullResult::Copied { bytes, checksum: None },
+                        Err(failure) => PullResult::Failed {
+                            failure,
+                            stderr: String::new(),
+                            attempts: attempt,
+                        },
+                    }
+                }
+                Ok(output) => {
+                    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+                    let failure = PullFailure::from_pull(output.status.code(), &stderr);
+                    PullResult::Failed {
+                        failure,
+                        stderr,
+                        attempts: attempt,
+                    }
+                }
+                Err(abort) => abort.into_result(attempt),
+            };
+            if !matches!(result, PullResult::Copied { .. }) {
+                let _ = fs::remove_file(&partial_file);
+            }
+
+            if let PullResult::Failed { failure, .. } = &result {
+                if let Some(backoff) = retry_backoff(failure, attempt, self.args.retries).filter(|_| !interrupt::interrupted()) {
+                    debug(1, format!("    attempt {attempt} failed: {failure}, retrying in {backoff:.1?}"));
+                    thread::sleep(backoff);
+                    attempt += 1;
+                    continue;
+                }
+            }
+            if attempt > 1 {
+                let ended = if matches!(result, PullResult::Copied { .. }) { "succeeded" } else { "failed" };
+                debug(1, format!("    attempt {attempt} {ended}"));
+            }
+            return outcome(result, reported);
+        }
+    }
+
+    /// Pulls the folder `src_dir` inside `dest_parent`, then moves each of its files to the destination,
+    /// as a failed pull may have copied some of them
+    fn pull_dir(&self, src_dir: &UnixPath, dest_parent: &Path, indices: &[usize]) -> Vec<(usize, PullOutcome)> {
+        let pull_started = Instant::now();
+        self.start(indices);
+        self.set_batch_message(src_dir, indices.len());
+
+        // The time is split among the files, for the transfer rate
+        let outcomes = |result: &dyn Fn(usize) -> PullResult| {
+            let elapsed = pull_started.elapsed() / indices.len() as u32;
+            indices
+                .iter()
+                .map(|index| {
+                    let outcome = PullOutcome {
+                        result: result(*index),
+                        elapsed,
+                        reported: 0,
+                    };
+                    (*index, outcome)
+                })
+                .collect()
+        };
+
+        if let Err(err) = fs::create_dir_all(dest_parent) {
+            return outcomes(&|_| PullResult::NoDirectory(io::Error::new(err.kind(), err.to_string())));
+        }
+        // The folder is pulled next to its destination and its files moved once complete
+        let partial_dir = partial_path(&dest_parent.join(src_dir.file_name().unwrap().to_str().unwrap()));
+        // A folder left there by a previous pull would get this one nested inside it
+        let _ = fs::remove_dir_all(&partial_dir);
+        let bytes = indices.iter().filter_map(|index| self.file_sizes[*index]).sum();
+        let pulled = self.run_pull(src_dir.to_str().unwrap(), &partial_dir, self.timeout(indices.len() as u32, bytes), |_| {});
+        let succeeded = matches!(&pulled, Ok(output) if output.status.success());
+        let (failure, stderr) = match pulled {
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+                match output.status.success() {
+                    true => (PullFailure::MissingAfterPull(partial_dir.clone()), stderr),
+                    false => (PullFailure::from_pull(output.status.code(), &stderr), stderr),
+                }
+            }
+            Err(PullAbort::DeviceLost(stderr)) => return outcomes(&|_| PullResult::DeviceLost { stderr: stderr.clone() }),
+            Err(PullAbort::Timeout) => (PullFailure::Timeout, String::new()),
+            Err(PullAbort::Interrupted) => (PullFailure::Interrupted, String::new()),
+            Err(PullAbort::NotStarted(err)) => (PullFailure::LocalIo(format!("failed to start adb: {err}")), String::new()),
+        };
+        let mut pulled = outcomes(&|index| {
+            let (src_file, dest_file) = (&self.files[index].src, &self.files[index].dest);
+            let partial_file = partial_dir.join(src_file.strip_prefix(src_dir).unwrap().to_str().unwrap());
+            let mismatch = match fs::metadata(&partial_file) {
+                Ok(metadata) if metadata.is_file() && succeeded => self.size_mismatch(index, metadata.len()).map(|failure| (failure, String::new())),
+                // A pull killed halfway leaves its last file short
+                Ok(metadata) if metadata.is_file() && self.file_sizes[index].is_none_or(|size| size == metadata.len()) => None,
+                _ => Some((failure.clone(), stderr.clone())),
+            };
+            match mismatch {
+                None => match self.put_in_place(&partial_file, dest_file.as_path()) {
+                    Ok(()) => PullResult::Copied {
+                        bytes: fs::metadata(dest_file.as_path()).map_or(0, |metadata| metadata.len()),
+                        checksum: None,
+                    },
+                    Err(err) => PullResult::Failed {
+                        failure: PullFailure::LocalIo(err.to_string()),
+                        stderr: String::new(),
+                        attempts: 1,
+                    },
+                },
+                Some(_) if interrupt::interrupted() => PullResult::Interrupted,
+                Some((failure, stderr)) => PullResult::Failed {
+                    failure,
+                    stderr,
+                    attempts: 1,
+                },
+            }
+        });
+        let _ = fs::remove_dir_all(&partial_dir);
+
+        // With --retries the files left behind by a failure that may go away are pulled again one by one
+        if self.args.retries > 0 && failure.is_retryable() && !interrupt::interrupted() {
+            for (index, outcome) in &mut pulled {
+                if matches!(outcome.result, PullResult::Failed { .. }) {
+                    debug(1, format!("    pulling {:?} again on its own", self.files[*index].src));
+                    *outcome = self.pull(*index);
+                }
+            }
+        }
+        pulled
+    }
+
+    /// Streams the files as a tar archive made on the device, unpacking each entry to the destination of
+    /// its file. The files missing from the archive are then pulled one by one, which also tells why
+    fn pull_tar(&self, dir: &UnixPath, indices: &[usize]) -> Vec<(usize, PullOutcome)> {
+        self.start(indices);
+        self.set_batch_message(dir, indices.len());
+
+        // The paths start with ./ so that none can be taken for an option
+        let names: Vec<String> = indices
+            .iter()
+            .map(|index| format!("./{}", self.files[*index].src.strip_prefix(dir).unwrap().to_str().unwrap()))
+            .collect();
+        let mut pending: HashMap<&str, usize> = names.iter().map(|name| &name[2..]).zip(indices.iter().copied()).collect();
+        let mut outcomes = Vec::new();
+
+        match self.adb.tar(dir.to_str().unwrap(), &names.iter().map(String::as_str).collect::<Vec<_>>(), self.stream_compressor) {
+            Ok((mut child, stderr_reader)) => {
+                let received = CountingReader {
+                    inner: child.stdout.take().unwrap(),
+                    count: &self.received_bytes,
+                };
+                // A stream which can't even be started is left empty, so that its files are pulled one by one
+                let stream: Box<dyn Read + '_> = match self.stream_compressor {
+                    None => Box::new(received),
+                    Some(StreamCompressor::Gzip) => Box::new(flate2::read::GzDecoder::new(received)),
+                    Some(StreamCompressor::Zstd) => match ruzstd::streaming_decoder::StreamingDecoder::new(received) {
+                        Ok(decoder) => Box::new(decoder),
+                        Err(_) => Box::new(io::empty()),
+                    },
+                };
+                let mut archive = tar::Archive::new(stream);
+                archive.set_preserve_mtime(self.adb.keep_metadata);
+                let mut last_entry = Instant::now();
+                let bytes = indices.iter().filter_map(|index| self.file_sizes[*index]).sum();
+                let (_, killed) = kill_after(&mut child, self.timeout(indices.len() as u32, bytes), || {
+                    for entry in archive.entries().into_iter().flatten() {
+                        let Ok(mut entry) = entry else {
+                            break;
+                        };
+                        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+                        let Some(index) = pending.remove(name.trim_start_matches("./")) else {
+                            continue;
+                        };
+                        let dest_file = self.files[index].dest.as_path();
+                        let mut reported = 0;
+                        let partial_file = partial_path(dest_file);
+                        let result = match fs::create_dir_all(dest_file.parent().unwrap()) {
+                            Err(err) => PullResult::NoDirectory(err),
+                            Ok(()) => match entry
+                                .unpack(&partial_file)
+                                .map_err(|err| PullFailure::LocalIo(err.to_string()))
+                                .and_then(|_| self.size_mismatch(index, entry.size()).map_or(Ok(()), Err))
+                                .and_then(|_| {
+                                    self.put_in_place(&partial_file, dest_file)
+                                        .map_err(|err| PullFailure::LocalIo(err.to_string()))
+                                }) {
+                                Ok(()) => {
+                                    let bytes = entry.size();
+                                    self.unpacked_bytes.fetch_add(bytes, Ordering::Relaxed);
+                                    if self.count_bytes {
+                                        reported = self.file_sizes[index].unwrap_or(bytes);
+                                        self.pb.inc(reported);
+                                    }
+                                    PullResult::Copied { bytes, checksum: None }
+                                }
+                                Err(failure) => {
+                                    let _ = fs::remove_file(&partial_file);
+                                    PullResult::Failed {
+                                        failure,
+                                        stderr: String::new(),
+                                        attempts: 1,
+                                    }
+                                }
+                            },
+                        };
+                        outcomes.push((
+                            index,
+                            PullOutcome {
+                                result,
+                                elapsed: last_entry.elapsed(),
+                                reported,
+                            },
+                        ));
+                        last_entry = Instant::now();
+                    }
+                });
+                drop(archive);
+                if let Some(killed) = killed {
+                    // The entry cut short by the kill is pulled again on its own, unless after Ctrl-C
+                    if let Some((index, PullOutcome { result: PullResult::Failed { .. }, .. })) = outcomes.last() {
+                        let index = *index;
+                        let position = indices.iter().position(|other| *other == index).unwrap();
+                        pending.insert(&names[position][2..], index);
+                        outcomes.pop();
+                    }
+                    if killed == Killed::Timeout {
+                        print_above(self.progress, format!("The tar stream of {:?} timed out", dir).yellow());
+                    }
+                }
+
+                let _ = child.kill();
+                let _ = child.wait();
+                let stderr = String::from_utf8_lossy(&stderr_reader.join().unwrap_or_default()).into_owned();
+                if !stderr.trim().is_empty() {
+                    debug(1, stderr.trim());
+                }
+            }
+            Err(err) => debug(1, format!("Unable to run tar: {err}")),
+        }
+
+        let mut missing: Vec<usize> = pending.into_values().collect();
+        missing.sort_unstable();
+        if interrupt::interrupted() {
+            let interrupted = missing.into_iter().map(|index| {
+                let outcome = PullOutcome {
+                    result: PullResult::Interrupted,
+                    elapsed: Duration::ZERO,
+                    reported: 0,
+                };
+                (index, outcome)
+            });
+            outcomes.extend(interrupted);
+            return outcomes;
+        }
+        if !missing.is_empty() {
+            print_above(
+                self.progress,
+                format!("{} of {} files missing from the tar stream of {:?}, pulling them one by one", missing.len(), indices.len(), dir).yellow(),
+            );
+        }
+        for index in missing {
+            outcomes.push((index, self.pull(index)));
+        }
+        outcomes
+    }
+
+    /// The bytes received compressed and those written from them, if the tar stream was compressed
+    pub fn compression_totals(&self) -> Option<(u64, u64)> {
+        self.stream_compressor?;
+        Some((self.received_bytes.load(Ordering::Relaxed), self.unpacked_bytes.load(Ordering::Relaxed)))
+    }
+
+    /// Shows the folder and the number of files being pulled together
+    fn set_batch_message(&self, dir: &UnixPath, files: usize) {
+        let path_width = self.message_width.saturating_sub(15).max(10);
+        self.pb.set_message(format!("{} ({files} files)", truncate_left(&escape_path(dir.to_str().unwrap()), path_width)));
+    }
+
+    /// Hashes the copied files on the device, all at once, and here, failing those whose hashes differ. They
+    /// are removed, so that the next run doesn't skip them
+    fn verify_checksums(&self, algorithm: HashAlgorithm, outcomes: &mut [(usize, PullOutcome)]) {
+        let copied: Vec<UnixPathBuf> = outcomes
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome.result, PullResult::Copied { .. }))
+            .map(|(index, _)| self.files[*index].src.clone())
+            .collect();
+        if copied.is_empty() {
+            return;
+        }
+        let device = device_hashes(self.adb, &copied, algorithm).unwrap_or_else(|err| {
+            debug(1, format!("{:#}", err));
+            HashMap::new()
+        });
+
+        for (index, outcome) in outcomes.iter_mut() {
+            let PullResult::Copied { checksum, .. } = &mut outcome.result else {
+                continue;
+            };
+            let (src_file, dest_file) = (&self.files[*index].src, &self.files[*index].dest);
+            let failure = match (device.get(src_file), local_hash(dest_file.as_path(), algorithm)) {
+                (Some(expected), Ok(actual)) if *expected == actual => {
+                    *checksum = Some(format!("{}:{actual}", algorithm.name()));
+                    continue;
+                }
+                (Some(_), Ok(_)) => PullFailure::ChecksumMismatch(algorithm.name()),
+                (_, Err(err)) => PullFailure::LocalIo(err.to_string()),
+                (None, _) => PullFailure::NoDeviceChecksum(algorithm.name()),
+            };
+            let _ = fs::remove_file(dest_file.as_path());
+            outcome.result = PullResult::Failed {
+                failure,
+                stderr: String::new(),
+                attempts: 1,
+            };
+        }
+    }
+
+    /// Moves the complete `partial_file` to `dest`, first moving the file already there aside with
+    /// --on-conflict backup
+    fn put_in_place(&self, partial_file: &Path, dest: &Path) -> io::Result<()> {
+        if self.args.on_conflict() == OnConflict::Backup && dest.is_file() {
+            let backup = backup_path(dest);
+            log(format!("Moving {:?} to {:?} before replacing it", dest, backup));
+            fs::rename(dest, backup)?;
+        }
+        move_into_place(partial_file, dest)
+    }
+
+    /// The failure of the file at `index` pulled with `actual` bytes, if it has another size on the device.
+    /// Without its size from the listing the device is asked for it
+    fn size_mismatch(&self, index: usize, actual: u64) -> Option<PullFailure> {
+        if self.args.no_verify_size {
+            return None;
+        }
+        let expected = match self.file_sizes[index] {
+            Some(size) => size,
+            None => {
+                let src_file = &self.files[index].src;
+                get_file_stats(self.adb, std::slice::from_ref(src_file)).ok()?.get(src_file)?.size
+            }
+        };
+        (expected != actual).then_some(PullFailure::SizeMismatch { expected, actual })
+    }
+
+    /// The time allowed to pull `files` files of `bytes` in total, from --file-timeout and --file-timeout-per-gb
+    fn timeout(&self, files: u32, bytes: u64) -> Option<Duration> {
+        let per_file = Duration::from_secs(self.args.file_timeout?);
+        let per_gb = Duration::from_secs(self.args.file_timeout_per_gb.unwrap_or(0));
+        Some(per_file * files + per_gb.mul_f64(bytes as f64 / (1u64 << 30) as f64))
+    }
+
+    /// Runs adb pull, running it again once the device comes back if it dropped during the transfer
+    /// (e.g. a cable glitch). Errs with the stderr of the last attempt if it didn't come back
+    fn run_pull(&self, src: &str, dest: &Path, timeout: Option<Duration>, mut on_percent: impl FnMut(u8)) -> Result<std::process::Output, PullAbort> {
+        loop {
+            let output = match self.adb.pull(src, dest, timeout, &mut on_percent) {
+                Ok(output) => output,
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                    debug(2, "    killed after the timeout");
+                    return Err(PullAbort::Timeout);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                    debug(2, "    killed after Ctrl-C");
+                    return Err(PullAbort::Interrupted);
+                }
+                Err(err) => {
+                    debug(2, format!("    failed to start adb: {err}"));
+                    return Err(PullAbort::NotStarted(err.to_string()));
+                }
+            };
+            debug(2, format!("    exit code {}", output.status.code().map_or("unknown".to_string(), |code| code.to_string())));
+
+            if output.status.success()
+                || PullFailure::from_pull(output.status.code(), &String::from_utf8_lossy(&output.stderr)) != PullFailure::DeviceOffline
+            {
+                return Ok(output);
+            }
+            if interrupt::interrupted() {
+                return Err(PullAbort::Interrupted);
+            }
+            if !self.reconnect() {
+                return Err(PullAbort::DeviceLost(String::from_utf8_lossy(&output.stderr).into_owned()));
+            }
+        }
+    }
+
+    /// Waits for the device to come back, returning whether it did
+    fn reconnect(&self) -> bool {
+        let _reconnecting = self.reconnecting.lock().unwrap();
+        if self.device_lost.load(Ordering::Relaxed) {
+            return false;
+        }
+        print_above(
+            self.progress,
+            format!("Lost connection to the device, waiting up to {} seconds for it..", self.args.reconnect_timeout).yellow(),
+        );
+        let timeout = Duration::from_secs(self.args.reconnect_timeout);
+        let reconnected = self.pb.suspend(|| self.adb.wait_for_device(timeout).unwrap_or(false));
+        if reconnected {
+            print_above(self.progress, "Device reconnected, resuming..");
+        } else {
+            self.device_lost.store(true, Ordering::Relaxed);
+        }
+        reconnected
+    }
+}
+
+/// Added to the name of the files and folders being pulled, which get their own name once complete
+pub const PARTIAL_EXTENSION: &str = ".adbpart";
+
+/// The folder in which the files too long for adb are pulled, removed as the other partial files
+const LONG_PATHS_DIR: &str = "long-paths.adbpart";
+
+/// The longest path Windows accepts without the extended-length `\\?\` prefix, counting the final NUL
+const MAX_PATH: usize = 260;
+
+/// Where `dest` is pulled before being renamed to it. On Windows the paths too long for adb are pulled
+/// into a folder with a short path first, the deepest one of the destination which has room for it
+fn partial_path(dest: &Path) -> PathBuf {
+    let partial_file = with_partial_extension(dest);
+    if !too_long_for_adb(&partial_file) {
+        return partial_file;
+    }
+    let mut hasher = DefaultHasher::new();
+    dest.hash(&mut hasher);
+    let name = format!("{:016x}{PARTIAL_EXTENSION}", hasher.finish());
+    dest.ancestors()
+        .map(|dir| dir.join(LONG_PATHS_DIR).join(&name))
+        .find(|path| !too_long_for_adb(path))
+        .unwrap_or(partial_file)
+}
+
+/// `dest` with the partial extension added to its name
+fn with_partial_extension(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap().to_os_string();
+    name.push(PARTIAL_EXTENSION);
+    dest.with_file_name(name)
+}
+
+/// Whether `path` is too long to be given to adb. The standard library adds the extended-length prefix
+/// to the long paths by itself, but adb may not accept them. The length is in bytes, which are never
+/// fewer than the UTF-16 units counted by Windows
+fn too_long_for_adb(path: &Path) -> bool {
+    cfg!(windows) && path.as_os_str().len() >= MAX_PATH
+}
+
+/// Moves the complete `partial_file` to `dest`, creating its folder
+pub fn move_into_place(partial_file: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest.parent().unwrap())?;
+    fs::rename(partial_file, dest)?;
+    // The folder of the files too long for adb is removed once empty
+    if let Some(dir) = partial_file.parent().filter(|dir| dir.ends_with(LONG_PATHS_DIR)) {
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+/// Removes the files and folders being pulled when a previous run was killed, found anywhere in
+/// `dir`. Returns how many were removed
+pub fn remove_partial_files(dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().ends_with(PARTIAL_EXTENSION) {
+            let result = if file_type.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+            removed += result.is_ok() as usize;
+        } else if file_type.is_dir() {
+            removed += remove_partial_files(&path);
+        }
+    }
+    removed
+}
+
+/// Gives the pulled file the modification time it has on the device, which `adb pull -a` doesn't keep with
+/// some devices and adb versions, with the fraction of the second where the file system can keep it
+pub fn restore_mtime(src_file: &UnixPath, dest: &Path, stat: Option<&FileStat>) {
+    let Some(stat) = stat else {
+        log(format!(
+            "The modification time of {src_file:?} on the device is unknown, leaving the one adb gave it"
+        ));
+        return;
+    };
+    let device = FileTime::from_unix_time(stat.mtime, stat.mtime_nanos);
+    match fs::metadata(dest).map(|metadata| FileTime::from_last_modification_time(&metadata)) {
+        Ok(local) if local == device => return,
+        Ok(local) if local.unix_seconds() != device.unix_seconds() => {
+            log(format!(
+                "{src_file:?} was copied with another modification time than on the device, setting it"
+            ));
+        }
+        _ => (),
+    }
+    if let Err(err) = filetime::set_file_mtime(dest, device) {
+        log(format!("Unable to set the modification time of {:?}: {err}", dest));
+    }
+}
+
+/// Why [`Pulls::run_pull`] gave up on a pull
+enum PullAbort {
+    /// The device dropped and didn't come back, with the stderr of the last attempt
+    DeviceLost(String),
+    /// The pull was killed after --file-timeout
+    Timeout,
+    /// The pull was killed after Ctrl-C
+    Interrupted,
+    /// adb couldn't be started, with the error
+    NotStarted(String),
+}
+
+impl PullAbort {
+    fn into_result(self, attempts: u32) -> PullResult {
+        match self {
+            PullAbort::DeviceLost(stderr) => PullResult::DeviceLost { stderr },
+            PullAbort::Interrupted => PullResult::Interrupted,
+            PullAbort::Timeout => PullResult::Failed {
+                failure: PullFailure::Timeout,
+                stderr: String::new(),
+                attempts,
+            },
+            PullAbort::NotStarted(err) => PullResult::Failed {
+                failure: PullFailure::LocalIo(format!("failed to start adb: {err}")),
+                stderr: String::new(),
+                attempts,
+            },
+        }
+    }
+}
+
+/// Counts the bytes read through it
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a AtomicU64,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Marks the pull of a file as ended when dropped, whichever way it ended
+struct FinishedGuard<'a> {
+    pulls: &'a Pulls<'a>,
+    index: usize,
+}
+
+impl Drop for FinishedGuard<'_> {
+    fn drop(&mut self) {
+        self.pulls.finished.lock().unwrap().insert(self.index);
+        self.pulls.finished_changed.notify_all();
+    }
+}
+
+/// The reason a file failed, telling how many times it was tried when more than once with --retries
+pub fn with_attempts(failure: &PullFailure, attempts: u32) -> String {
+    match attempts {
+        1 => failure.to_string(),
+        attempts => format!("{failure} (after {attempts} attempts)"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destinations_too_long_for_adb_are_pulled_into_a_shorter_folder() {
+        let dest_dir = PathBuf::from(if cfg!(windows) { "C:\\Backup" } else { "/backup" }).join("a".repeat(100));
+        let short = dest_dir.join("IMG_0001.jpg");
+        assert!(!too_long_for_adb(&short));
+        assert_eq!(partial_path(&short), dest_dir.join("IMG_0001.jpg.adbpart"));
+
+        let long = dest_dir.join("b".repeat(120)).join(format!("{}.jpg", "c".repeat(100)));
+        assert!(long.as_os_str().len() > MAX_PATH);
+        assert_eq!(too_long_for_adb(&long), cfg!(windows));
+        let partial = partial_path(&long);
+        if cfg!(windows) {
+            // The deepest folder of the destination which has room for it
+            assert_eq!(partial.parent(), Some(dest_dir.join(LONG_PATHS_DIR).as_path()));
+            assert!(!too_long_for_adb(&partial));
+            assert_eq!(partial, partial_path(&long));
+        } else {
+            assert_eq!(partial, with_partial_extension(&long));
+        }
+    }
+
+    #[test]
+    fn retry_backoff_doubles_until_the_retries_are_used_up() {
+        let failure = PullFailure::Timeout;
+        assert_eq!(retry_backoff(&failure, 1, 0), None);
+        assert_eq!(retry_backoff(&failure, 1, 3), Some(Duration::from_millis(500)));
+        assert_eq!(retry_backoff(&failure, 2, 3), Some(Duration::from_secs(1)));
+        assert_eq!(retry_backoff(&failure, 3, 3), Some(Duration::from_secs(2)));
+        assert_eq!(retry_backoff(&failure, 4, 3), None);
+        assert_eq!(retry_backoff(&failure, 10, 20), Some(Duration::from_secs(16)));
+        // Whatever the retries left, as told by PullFailure::is_retryable
+        assert_eq!(retry_backoff(&PullFailure::NotFound, 1, 3), None);
+    }
+
+    #[test]
+    fn failure_reason_tells_the_attempts() {
+        assert_eq!(with_attempts(&PullFailure::Timeout, 1), "timeout");
+        assert_eq!(with_attempts(&PullFailure::Timeout, 3), "timeout (after 3 attempts)");
+    }
+}
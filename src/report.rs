@@ -0,0 +1,300 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fs::{self, read_to_string, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use unix_path::{Path as UnixPath, PathBuf as UnixPathBuf};
+
+use crate::adb::{Adb, PullFailure};
+use crate::checksum::ChecksumFile;
+use crate::logging::log;
+use crate::manifest::{Manifest, Status};
+use crate::pull::with_attempts;
+use crate::{alert, escape_path, finish_manifest, info, strip_reason, Cli, TransferSummary};
+
+/// A list of device paths appended to while pulling, one per line, so that it is complete up to
+/// the last file even if the program is killed
+pub struct PathListWriter {
+    pub path: PathBuf,
+    file: LineWriter<File>,
+    /// The escaped paths already in the file, which aren't written again even with another reason
+    written: HashSet<String>,
+}
+
+impl PathListWriter {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|err| anyhow!("Couldn't create {:?}: {err}", parent))?;
+        }
+
+        let written: HashSet<String> = read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| strip_reason(line).to_string())
+            .collect();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|err| anyhow!("Couldn't open {:?}: {err}", path))?;
+
+        Ok(Self {
+            path,
+            file: LineWriter::new(file),
+            written,
+        })
+    }
+
+    /// Appends `path`, followed by `reason` separated by a tab if given, unless the path is already in the file
+    pub fn append(&mut self, path: &UnixPath, reason: Option<&str>) {
+        let escaped = escape_path(path.to_str().unwrap());
+        if !self.written.insert(escaped.clone()) {
+            return;
+        }
+        let line = match reason {
+            Some(reason) => format!("{escaped}\t{}", reason.replace(['\t', '\n', '\r'], " ")),
+            None => escaped,
+        };
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            eprintln!("Couldn't write to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// The done, failed and deleted files, None with --no-report-files or if they can't be opened, the
+/// manifests and the checksum file
+pub struct ReportFiles {
+    done: Option<PathListWriter>,
+    failed: Option<PathListWriter>,
+    deleted: Option<PathListWriter>,
+    manifest: Option<Manifest>,
+    pub checksums: Option<ChecksumFile>,
+    pub done_count: usize,
+    pub failed_count: usize,
+    /// Whether the files are copied with --flatten
+    flatten: bool,
+}
+
+impl ReportFiles {
+    pub fn open(args: &Cli, manifest: Option<Manifest>) -> Self {
+        let open = |path: Option<PathBuf>| match path.map(PathListWriter::open) {
+            Some(Ok(writer)) => Some(writer),
+            Some(Err(err)) => {
+                alert!("{}", format!("{:#}", err).red());
+                None
+            }
+            None => None,
+        };
+
+        Self {
+            done: open(args.done_file()),
+            failed: open(args.failed_file()),
+            deleted: open(args.deleted_file()),
+            manifest,
+            checksums: None,
+            done_count: 0,
+            failed_count: 0,
+            flatten: args.flatten,
+        }
+    }
+
+    pub fn done(&mut self, path: &UnixPath, dest: &Path, checksum: Option<String>) {
+        self.done_count += 1;
+        if let Some(done) = &mut self.done {
+            // A file renamed with --on-conflict rename is listed with its new name, and one flattened with --flatten
+            // with where it was copied, which --resume ignores
+            let renamed = dest.file_name().and_then(|name| name.to_str()) != path.file_name().and_then(|name| name.to_str());
+            let reason = match renamed {
+                true => Some(format!("renamed to {}", dest.display())),
+                false => self.flatten.then(|| format!("copied to {}", dest.display())),
+            };
+            done.append(path, reason.as_deref());
+        }
+        if let Some(checksums) = &self.checksums {
+            checksums.add(dest);
+        }
+        self.record(path, Some(dest), Status::Copied, None, checksum);
+    }
+
+    /// Records a file which couldn't be copied, `stderr` is what adb printed, if anything
+    pub fn failed(&mut self, path: &UnixPath, dest: &Path, failure: &PullFailure, attempts: u32, stderr: &str) {
+        self.failed_count += 1;
+        let reason = with_attempts(failure, attempts);
+        if let Some(failed) = &mut self.failed {
+            failed.append(path, Some(&reason));
+        }
+        self.record(path, Some(dest), Status::Failed, Some(reason.clone()), None);
+        log(format!("Failed to copy {:?}: {reason}", path));
+        for line in stderr.trim().lines() {
+            log(format!("    {line}"));
+        }
+    }
+
+    /// Records a file deleted from the device with --move
+    fn deleted(&mut self, path: &UnixPath) {
+        if let Some(deleted) = &mut self.deleted {
+            deleted.append(path, None);
+        }
+    }
+
+    /// Adds the file to the manifest, giving up on it at the first error
+    pub fn record(&mut self, path: &UnixPath, dest: Option<&Path>, status: Status, error: Option<String>, checksum: Option<String>) {
+        if let Some(Err(err)) = self.manifest.as_mut().map(|manifest| manifest.write(path, dest, status, error, checksum)) {
+            alert!("{}", format!("{:#}", err).red());
+            self.manifest = None;
+        }
+    }
+
+    /// Waits for the pulled files still being hashed for --checksum-file, which has to be done before
+    /// printing the summary as the hashing has its own progress bar
+    pub fn finish_checksums(&mut self) {
+        let Some(checksums) = self.checksums.take() else {
+            return;
+        };
+        let path = checksums.path.clone();
+        match checksums.finish() {
+            Ok(errors) => {
+                for error in errors {
+                    alert!("{}", format!("Couldn't hash {error}").yellow());
+                }
+                info!("Checksums written to {:?}", path);
+            }
+            Err(err) => alert!("{}", format!("{:#}", err).red()),
+        }
+    }
+
+    /// Prints where the copied and failed files were written, finishing the manifest
+    pub fn print_summary(&mut self) {
+        if self.failed_count > 0 {
+            match &self.failed {
+                Some(failed) => info!("Failed to copy {} files. Failed files written to {:?}", self.failed_count, failed.path),
+                None => info!("Failed to copy {} files", self.failed_count),
+            }
+        }
+
+        if let Some(done) = &self.done {
+            info!("Copied files written to {:?}", done.path);
+        }
+
+        if let Some(deleted) = &self.deleted {
+            info!("Deleted files written to {:?}", deleted.path);
+        }
+
+        if let Some(manifest) = self.manifest.take() {
+            finish_manifest(manifest);
+        }
+    }
+}
+
+/// How many copied files are deleted from the device at once with --move
+const MOVE_BATCH: usize = 100;
+
+/// The files copied with --move, deleted from the device a batch at a time, so that the space is freed
+/// while copying
+pub struct DeviceCleanup<'a> {
+    adb: &'a Adb,
+    /// The copied files not deleted yet, with their source and size
+    pending: Vec<(UnixPathBuf, UnixPathBuf, u64)>,
+    /// The folders of the deleted files within their source, for --prune-empty-dirs
+    dirs: HashSet<UnixPathBuf>,
+}
+
+impl<'a> DeviceCleanup<'a> {
+    pub fn new(adb: &'a Adb) -> Self {
+        Self {
+            adb,
+            pending: Vec::new(),
+            dirs: HashSet::new(),
+        }
+    }
+
+    /// Adds a file of `root_src` copied with `bytes`, deleting the batch once full
+    pub fn add(&mut self, file: &UnixPath, root_src: &UnixPath, bytes: u64, reports: &mut ReportFiles, summary: &mut TransferSummary) {
+        self.pending.push((file.to_path_buf(), root_src.to_path_buf(), bytes));
+        if self.pending.len() >= MOVE_BATCH {
+            self.flush(reports, summary);
+        }
+    }
+
+    /// Deletes the files added since the last batch
+    fn flush(&mut self, reports: &mut ReportFiles, summary: &mut TransferSummary) {
+        let pending = std::mem::take(&mut self.pending);
+        let paths: Vec<&str> = pending.iter().map(|(file, _, _)| file.to_str().unwrap()).collect();
+        let deleted: HashSet<String> = match self.adb.remove_files(&paths) {
+            Ok(deleted) => deleted.into_iter().collect(),
+            Err(err) => {
+                log(format!("{:#}", err));
+                HashSet::new()
+            }
+        };
+
+        for (file, root_src, bytes) in pending {
+            if !deleted.contains(file.to_str().unwrap()) {
+                log(format!("Couldn't delete {:?} from the device", file));
+                summary.not_deleted += 1;
+                continue;
+            }
+            log(format!("Deleted {:?} from the device", file));
+            reports.deleted(&file);
+            summary.deleted += 1;
+            summary.freed += bytes;
+            let dirs = file.ancestors().skip(1).take_while(|dir| *dir != root_src && dir.starts_with(&root_src));
+            self.dirs.extend(dirs.map(UnixPath::to_path_buf));
+        }
+    }
+
+    /// Deletes the files left, then with `prune` the folders they left empty, deepest first
+    pub fn finish(mut self, prune: bool, reports: &mut ReportFiles, summary: &mut TransferSummary) {
+        self.flush(reports, summary);
+        if !prune {
+            return;
+        }
+        let mut dirs: Vec<&str> = self.dirs.iter().map(|dir| dir.to_str().unwrap()).collect();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.matches('/').count()));
+        match self.adb.remove_empty_dirs(&dirs) {
+            Ok(removed) => summary.pruned_dirs = removed.len(),
+            Err(err) => log(format!("{:#}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_list_writer_leaves_every_line_appended_on_disk() {
+        let dir = crate::adb::tests::test_dir("path-list-writer");
+        let path = dir.join("reports/files_failed.txt");
+        let mut writer = PathListWriter::open(path.clone()).unwrap();
+
+        // Each line is on disk as soon as it's appended, as if the run was killed right after
+        writer.append(UnixPath::new("/sdcard/DCIM/a.jpg"), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "/sdcard/DCIM/a.jpg\n");
+        writer.append(UnixPath::new("/sdcard/DCIM/line\nbreak.jpg"), Some("timeout\t(after\n2 attempts)"));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "/sdcard/DCIM/a.jpg\n/sdcard/DCIM/line\\nbreak.jpg\ttimeout (after 2 attempts)\n"
+        );
+
+        // The paths already there aren't written again, even by the next run or with another reason
+        writer.append(UnixPath::new("/sdcard/DCIM/a.jpg"), None);
+        drop(writer);
+        let mut writer = PathListWriter::open(path.clone()).unwrap();
+        writer.append(UnixPath::new("/sdcard/DCIM/a.jpg"), None);
+        writer.append(UnixPath::new("/sdcard/DCIM/line\nbreak.jpg"), Some("device offline"));
+        writer.append(UnixPath::new("/sdcard/DCIM/b.jpg"), None);
+        let lines: Vec<String> = fs::read_to_string(&path).unwrap().lines().map(String::from).collect();
+        assert_eq!(
+            lines,
+            [
+                "/sdcard/DCIM/a.jpg",
+                "/sdcard/DCIM/line\\nbreak.jpg\ttimeout (after 2 attempts)",
+                "/sdcard/DCIM/b.jpg"
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
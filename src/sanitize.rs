@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use unix_path::{Path as UnixPath, PathBuf as UnixPathBuf};
+
+use crate::collision_key;
+
+/// The names Windows reserves for devices, with any extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5",
+    "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether `c` can't be in a file name on Windows
+fn is_invalid_char(c: char) -> bool {
+    c.is_ascii_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+}
+
+/// Parses the character which replaces the invalid ones with --sanitize-names
+pub fn parse_substitute(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if !is_invalid_char(c) && c != '.' && c != ' ' => Ok(c),
+        _ => Err(format!(
+            "invalid substitute {value:?}, expected a single character valid in file names, other than a dot or a space"
+        )),
+    }
+}
+
+/// `name` made valid on Windows: the invalid characters are replaced by `substitute`, the trailing dots and
+/// spaces removed, and `substitute` added after the reserved names
+pub fn sanitize_name(name: &str, substitute: char) -> String {
+    let mut sanitized: String = name.chars().map(|c| if is_invalid_char(c) { substitute } else { c }).collect();
+    sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+    if sanitized.is_empty() {
+        sanitized.push(substitute);
+    }
+
+    let stem = sanitized.split('.').next().unwrap();
+    if RESERVED_NAMES.contains(&stem.trim_end().to_uppercase().as_str()) {
+        sanitized.insert(stem.len(), substitute);
+    }
+    sanitized
+}
+
+/// The folder of the device with --dest-per-device, such as `Pixel_7_ABC123`: its model and serial joined by
+/// underscores, with the spaces and the characters invalid in file names replaced by `substitute`
+pub fn device_folder(model: &str, serial: &str, substitute: char) -> String {
+    let name = format!("{model}_{serial}");
+    let name: String = name.chars().map(|c| if c.is_whitespace() { substitute } else { c }).collect();
+    sanitize_name(&name, substitute)
+}
+
+/// Gives the files of a source names valid on Windows, keeping them unique: a sanitized name which is
+/// already taken in its folder gets a number, like `name (1).ext`
+pub struct NameSanitizer {
+    substitute: char,
+    /// The sanitized path of each folder and file already seen, relative to the destination
+    paths: HashMap<UnixPathBuf, PathBuf>,
+    /// The names taken in each folder of the device, as compared by the file system
+    taken: HashMap<UnixPathBuf, HashSet<String>>,
+}
+
+impl NameSanitizer {
+    /// `paths` are all the paths to sanitize, relative to the destination. The names already valid are
+    /// kept as they are, so the sanitized ones can't take them whatever the order of the files
+    pub fn new<'a>(substitute: char, paths: impl IntoIterator<Item = &'a UnixPath>) -> Self {
+        let mut taken: HashMap<UnixPathBuf, HashSet<String>> = HashMap::new();
+        for path in paths {
+            let mut parent = UnixPathBuf::new();
+            for name in path.iter().filter_map(|name| name.to_str()) {
+                if sanitize_name(name, substitute) == name {
+                    taken.entry(parent.clone()).or_default().insert(collision_key(Path::new(name)));
+                }
+                parent.push(name);
+            }
+        }
+
+        Self {
+            substitute,
+            paths: HashMap::new(),
+            taken,
+        }
+    }
+
+    /// The sanitized version of `path`, relative to the destination
+    pub fn sanitize(&mut self, path: &UnixPath) -> PathBuf {
+        let mut sanitized = PathBuf::new();
+        let mut device = UnixPathBuf::new();
+        for name in path.iter().filter_map(|name| name.to_str()) {
+            let parent = device.clone();
+            device.push(name);
+            if let Some(known) = self.paths.get(&device) {
+                sanitized = known.clone();
+                continue;
+            }
+
+            let mut valid = sanitize_name(name, self.substitute);
+            if valid != name {
+                let taken = self.taken.entry(parent).or_default();
+                if taken.contains(&collision_key(Path::new(&valid))) {
+                    let numbered = numbered_names(&valid).find(|candidate| !taken.contains(&collision_key(Path::new(candidate))));
+                    valid = numbered.unwrap();
+                }
+                taken.insert(collision_key(Path::new(&valid)));
+            }
+            sanitized.push(valid);
+            self.paths.insert(device.clone(), sanitized.clone());
+        }
+        sanitized
+    }
+}
+
+/// `name (1).ext`, `name (2).ext` and so on
+fn numbered_names(name: &str) -> impl Iterator<Item = String> + '_ {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    (1..).map(move |number| format!("{stem} ({number}){extension}"))
+}
@@ -0,0 +1,165 @@
+use unix_path::{Path as UnixPath, PathBuf as UnixPathBuf};
+
+use crate::sanitize::sanitize_name;
+
+/// What a placeholder of --dest-template is replaced with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    DeviceModel,
+    DeviceSerial,
+    Date,
+    SourceName,
+    RelPath,
+    FileName,
+    Year,
+    Month,
+}
+
+/// The placeholders by name, in the order they are documented
+const PLACEHOLDERS: [(&str, Placeholder); 8] = [
+    ("device_model", Placeholder::DeviceModel),
+    ("device_serial", Placeholder::DeviceSerial),
+    ("date", Placeholder::Date),
+    ("source_name", Placeholder::SourceName),
+    ("rel_path", Placeholder::RelPath),
+    ("file_name", Placeholder::FileName),
+    ("year", Placeholder::Year),
+    ("month", Placeholder::Month),
+];
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// The path of each file in the destination, set with --dest-template
+#[derive(Debug, Clone)]
+pub struct DestTemplate {
+    parts: Vec<Part>,
+}
+
+impl DestTemplate {
+    /// Parses a template such as `{device_model}/{date}/{source_name}/{rel_path}`. It has to name the files
+    /// with {rel_path} or {file_name}, and can't leave the destination
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("unclosed placeholder in {value:?}"));
+            };
+            let name = &rest[start + 1..start + end];
+            let Some((_, placeholder)) = PLACEHOLDERS.iter().find(|(known, _)| *known == name) else {
+                let known: Vec<String> = PLACEHOLDERS.iter().map(|(known, _)| format!("{{{known}}}")).collect();
+                return Err(format!("unknown placeholder {{{name}}}, expected one of {}", known.join(", ")));
+            };
+            parts.push(Part::Placeholder(*placeholder));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        let template = Self { parts };
+        if !template.uses(|placeholder| matches!(placeholder, Placeholder::RelPath | Placeholder::FileName)) {
+            return Err(format!(
+                "{value:?} doesn't contain {{rel_path}} or {{file_name}}, so all the files would have the same path"
+            ));
+        }
+        let absolute = matches!(template.parts.first(), Some(Part::Text(text)) if text.starts_with('/'));
+        let parent = template
+            .parts
+            .iter()
+            .any(|part| matches!(part, Part::Text(text) if text.split('/').any(|name| name == "..")));
+        if absolute || parent {
+            return Err(format!("{value:?} has to be relative to the destination, without `..`"));
+        }
+        Ok(template)
+    }
+
+    fn uses(&self, matches: impl Fn(Placeholder) -> bool) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Placeholder(placeholder) if matches(*placeholder)))
+    }
+
+    /// Whether the template needs the modification time of the files
+    pub fn needs_mtime(&self) -> bool {
+        self.uses(|placeholder| matches!(placeholder, Placeholder::Year | Placeholder::Month))
+    }
+
+    /// Whether the template needs the model of the device
+    pub fn needs_model(&self) -> bool {
+        self.uses(|placeholder| placeholder == Placeholder::DeviceModel)
+    }
+
+    /// Whether the template needs the serial of the device
+    pub fn needs_serial(&self) -> bool {
+        self.uses(|placeholder| placeholder == Placeholder::DeviceSerial)
+    }
+
+    /// Replaces the placeholders which are the same for all the files: the model and serial of the device,
+    /// with the characters invalid in file names replaced by `substitute` as they can't add folders either,
+    /// and the date of the run, as seconds since the Unix epoch
+    pub fn fill_run(&mut self, model: &str, serial: &str, started: i64, substitute: char) {
+        for part in self.parts.iter_mut() {
+            let value = match part {
+                Part::Placeholder(Placeholder::DeviceModel) => sanitize_name(model, substitute),
+                Part::Placeholder(Placeholder::DeviceSerial) => sanitize_name(serial, substitute),
+                Part::Placeholder(Placeholder::Date) => {
+                    let (year, month, day) = civil_date(started);
+                    format!("{year:04}-{month:02}-{day:02}")
+                }
+                _ => continue,
+            };
+            *part = Part::Text(value);
+        }
+    }
+
+    /// The path of a file relative to the destination. `rel_path` is its path relative to the source folder
+    /// named `source_name`, `mtime` its modification time on the device, if known
+    pub fn expand(&self, source_name: &str, rel_path: &UnixPath, mtime: Option<i64>) -> UnixPathBuf {
+        let date = mtime.map(civil_date);
+        let mut expanded = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => expanded.push_str(text),
+                Part::Placeholder(Placeholder::SourceName) => expanded.push_str(source_name),
+                Part::Placeholder(Placeholder::RelPath) => expanded.push_str(rel_path.to_str().unwrap()),
+                Part::Placeholder(Placeholder::FileName) => {
+                    expanded.push_str(rel_path.file_name().and_then(|name| name.to_str()).unwrap_or_default())
+                }
+                Part::Placeholder(Placeholder::Year) => match date {
+                    Some((year, _, _)) => expanded.push_str(&format!("{year:04}")),
+                    None => expanded.push_str("unknown"),
+                },
+                Part::Placeholder(Placeholder::Month) => match date {
+                    Some((_, month, _)) => expanded.push_str(&format!("{month:02}")),
+                    None => expanded.push_str("unknown"),
+                },
+                // Filled in by fill_run before any file is expanded
+                Part::Placeholder(_) => unreachable!(),
+            }
+        }
+        // Empty values, such as the name of the source `/`, leave no folder
+        expanded.split('/').filter(|name| !name.is_empty() && *name != ".").collect()
+    }
+}
+
+/// The UTC year, month and day of `seconds` since the Unix epoch, see http://howardhinnant.github.io/date_algorithms.html
+pub fn civil_date(seconds: i64) -> (i64, i64, i64) {
+    let days = seconds.div_euclid(24 * 60 * 60) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
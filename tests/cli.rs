@@ -0,0 +1,292 @@
+//! Runs the adbpuller executable against a fake adb, a shell script answering like adb would, and checks
+//! the exit codes documented in the help
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ONE_DEVICE: &str = "ABC123\tdevice usb:1-1 product:panther model:Pixel_7 device:panther transport_id:1";
+
+/// An empty folder for the test `name`
+fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("adbpuller-cli-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes in `dir` an adb which lists `devices` and runs the shell commands on this machine. Its pull copies
+/// the local path, failing for the paths containing `fail`, and waits a few seconds for those containing
+/// `slow` after creating `pulling` in `dir`
+fn fake_adb(dir: &Path, devices: &str) -> PathBuf {
+    let script = format!(
+        "#!/bin/sh
+[ \"$1\" = -s ] && shift 2
+case \"$1\" in
+    version|start-server) ;;
+    devices) printf 'List of devices attached\\n'; cat <<'EOF'
+{devices}
+EOF
+    ;;
+    shell) shift; exec sh -c \"$*\" ;;
+    pull) shift
+        while [ \"$1\" = -a ] || [ \"$1\" = -z ]; do
+            [ \"$1\" = -z ] && shift
+            shift
+        done
+        if find \"$1\" -name '*fail*' | grep -q .; then
+            echo \"adb: error: failed to copy '$1': remote Permission denied\" >&2
+            exit 1
+        fi
+        if find \"$1\" -name '*slow*' | grep -q .; then
+            touch '{pulling}'
+            sleep 3
+        fi
+        exec cp -R \"$1\" \"$2\" ;;
+    *) exit 1 ;;
+esac
+",
+        pulling = dir.join("pulling").display()
+    );
+
+    let path = dir.join("adb");
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+/// The command running adbpuller with `args`, without the environment variables which would pick another adb or device
+fn adbpuller_command(args: &[&str]) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_AdbPuller"));
+    cmd.args(args)
+        .env_remove("ADB")
+        .env_remove("ANDROID_SERIAL")
+        .env_remove("ANDROID_ADB_SERVER_ADDRESS")
+        .env_remove("ANDROID_ADB_SERVER_PORT");
+    cmd
+}
+
+fn adbpuller(args: &[&str]) -> Output {
+    adbpuller_command(args).output().unwrap()
+}
+
+/// Both stdout and stderr of the run
+fn printed(output: &Output) -> String {
+    format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr))
+}
+
+/// A folder of `dir` with the files `names` in it, to be copied as if on the device
+fn source_folder(dir: &Path, names: &[&str]) -> PathBuf {
+    let source = dir.join("source");
+    fs::create_dir_all(&source).unwrap();
+    for name in names {
+        fs::write(source.join(name), name).unwrap();
+    }
+    source
+}
+
+#[test]
+fn show_default_excludes_exits_with_success() {
+    let output = adbpuller(&["--show-default-excludes"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!output.stdout.is_empty());
+}
+
+#[test]
+fn invalid_arguments_are_fatal() {
+    assert_eq!(adbpuller(&["-s", "/sdcard", "--no-such-option"]).status.code(), Some(2));
+    assert_eq!(adbpuller(&["-s", "/sdcard", "--include", "("]).status.code(), Some(2));
+}
+
+#[test]
+fn missing_adb_is_fatal() {
+    let dir = test_dir("missing_adb");
+    let adb = dir.join("adb");
+
+    let output = adbpuller(&["-s", "/sdcard", "--dry-run", "--adb-path", adb.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn devices_lists_the_attached_devices() {
+    let dir = test_dir("devices");
+    let adb = fake_adb(&dir, ONE_DEVICE);
+
+    let output = adbpuller(&["devices", "--adb-path", adb.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("ABC123"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn devices_runs_in_a_folder_which_cant_be_written() {
+    let dir = test_dir("read_only_folder");
+    let adb = fake_adb(&dir, ONE_DEVICE);
+
+    // Not even root can create files in /proc, unlike a folder without the write permission
+    let output = adbpuller_command(&["devices", "--adb-path", adb.to_str().unwrap()])
+        .current_dir("/proc")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(0), "{}", printed(&output));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("ABC123"));
+}
+
+#[test]
+fn no_device_is_fatal() {
+    let dir = test_dir("no_device");
+    let adb = fake_adb(&dir, "");
+
+    let output = adbpuller(&["-s", "/sdcard", "--dry-run", "--device-retries", "0", "--adb-path", adb.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(printed(&output).contains("No device found"));
+}
+
+#[test]
+fn unauthorized_device_is_fatal() {
+    let dir = test_dir("unauthorized");
+    let adb = fake_adb(&dir, "ABC123\tunauthorized usb:1-1 transport_id:1");
+
+    let output = adbpuller(&["-s", "/sdcard", "--dry-run", "--device-retries", "0", "--adb-path", adb.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(printed(&output).contains("unauthorized"));
+}
+
+#[test]
+fn more_than_one_device_without_serial_is_fatal() {
+    let dir = test_dir("two_devices");
+    let adb = fake_adb(&dir, &format!("{ONE_DEVICE}\nDEF456\tdevice usb:1-2 model:Pixel_6 transport_id:2"));
+
+    let output = adbpuller(&["-s", "/sdcard", "--dry-run", "--adb-path", adb.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(printed(&output).contains("DEF456"));
+}
+
+#[test]
+fn missing_source_is_fatal() {
+    let dir = test_dir("missing_source");
+    let adb = fake_adb(&dir, ONE_DEVICE);
+    let source = dir.join("no-such-folder");
+
+    let output = adbpuller(&["-s", source.to_str().unwrap(), "--dry-run", "--adb-path", adb.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(printed(&output).contains("does not exist on the device"));
+}
+
+#[test]
+fn completed_copy_exits_with_success() {
+    let dir = test_dir("completed_copy");
+    let adb = fake_adb(&dir, ONE_DEVICE);
+    let source = source_folder(&dir, &["a.jpg", "b.jpg"]);
+    let dest = dir.join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let output = adbpuller(&[
+        "-s",
+        source.to_str().unwrap(),
+        "--dest",
+        dest.to_str().unwrap(),
+        "--yes",
+        "--adb-path",
+        adb.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", printed(&output));
+    assert_eq!(fs::read_to_string(dest.join("source/a.jpg")).unwrap(), "a.jpg");
+    assert_eq!(fs::read_to_string(dest.join("source/b.jpg")).unwrap(), "b.jpg");
+}
+
+#[test]
+fn dry_run_exits_with_success_without_copying() {
+    let dir = test_dir("dry_run");
+    let adb = fake_adb(&dir, ONE_DEVICE);
+    let source = source_folder(&dir, &["a.jpg"]);
+    let dest = dir.join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let output = adbpuller(&[
+        "-s",
+        source.to_str().unwrap(),
+        "--dest",
+        dest.to_str().unwrap(),
+        "--dry-run",
+        "--adb-path",
+        adb.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", printed(&output));
+    assert!(!dest.join("source/a.jpg").exists());
+}
+
+#[test]
+fn failed_files_are_a_partial_failure() {
+    let dir = test_dir("partial_failure");
+    let adb = fake_adb(&dir, ONE_DEVICE);
+    let source = source_folder(&dir, &["a.jpg", "fail.jpg"]);
+    let dest = dir.join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let output = adbpuller(&[
+        "-s",
+        source.join("a.jpg").to_str().unwrap(),
+        "-s",
+        source.join("fail.jpg").to_str().unwrap(),
+        "--dest",
+        dest.to_str().unwrap(),
+        "--yes",
+        "--adb-path",
+        adb.to_str().unwrap(),
+    ]);
+
+    assert_eq!(output.status.code(), Some(1), "{}", printed(&output));
+    assert!(dest.join("a.jpg").exists());
+    assert!(!dest.join("fail.jpg").exists());
+}
+
+#[test]
+fn ctrl_c_exits_as_interrupted() {
+    let dir = test_dir("interrupted");
+    let adb = fake_adb(&dir, ONE_DEVICE);
+    let source = source_folder(&dir, &["slow.jpg"]);
+    let dest = dir.join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let child = adbpuller_command(&[
+        "-s",
+        source.to_str().unwrap(),
+        "--dest",
+        dest.to_str().unwrap(),
+        "--yes",
+        "--adb-path",
+        adb.to_str().unwrap(),
+    ])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+
+    // Ctrl-C once the file is being pulled, which the run lets finish
+    let started = Instant::now();
+    while !dir.join("pulling").exists() {
+        assert!(started.elapsed() < Duration::from_secs(30), "the pull never started");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let killed = Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+    assert!(killed.success());
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(3), "{}", printed(&output));
+}